@@ -1,118 +1,858 @@
-use crate::lsm::tree::LsmTree;
+use crate::lsm::tree::{ CompactionStrategy, DEFAULT_LEVEL_SCALING_FACTOR, LsmTree };
 use crate::lsm::kv::KV;
 use crate::io::table::TableErr;
+use crate::io::wal::Wal;
 use crate::lsm::tree::Scan;
+use crate::lsm::merge_iter::{ EntryIter, merge_newest_first_live, merge_newest_first_live_with };
+use std::collections::{ BTreeMap, HashMap, HashSet };
+use std::path::Path;
+use std::sync::{ Arc, Mutex, RwLock, RwLockReadGuard, RwLockWriteGuard };
+use std::sync::mpsc::{ self, Receiver, Sender };
 
 pub struct Client {
-    mem_table: Vec<KV>,
-    lsm_tree: LsmTree,
+    /// Behind a lock so that [Client::get] can write back values fetched from the fallback
+    /// while only holding `&self`. Everything that takes `&mut self` goes straight to it.
+    mem_table: RwLock<MemTable>,
+    /// Every write in the mem_table, so that it can be recovered if we crash before flushing
+    wal: Wal,
+    /// Shared with the background compaction thread, if there is one
+    lsm_tree: Arc<LsmTree>,
     max_size: usize,
+    max_bytes: Option<usize>,
+    /// Latest values of pinned keys. These are still written through to the mem_table and
+    /// tree as usual, but reads for them never have to go to disk.
+    pinned: HashMap<String, String>,
+    throttle_threshold: Option<usize>,
+    stall_threshold: Option<usize>,
+    /// Consulted by [Client::get] for keys that aren't stored locally
+    fallback: Option<Box<dyn Scan + Send + Sync>>,
+    /// Rejects writes to keys that already have a value
+    write_once: bool,
+    /// Key prefixes that have been watched, along with where to send their changes
+    watchers: Mutex<Vec<(String, Sender<ChangeEvent>)>>,
+    /// See [Client::wal_discarded_bytes]
+    wal_discarded_bytes: usize,
 }
 
-#[derive(Debug)]
-enum BinSearchErr {
-    SMALLER, // value is smaller than the entire list
-    LARGER, // value is larger than the entire list
-    MISSING(usize), // value is missing. The contained index is the largest one smaller than
-                    // the element
-    EMPTY, // the list is empty
+/// Unflushed writes, kept in key order so that they can be flushed as they are
+#[derive(Default)]
+struct MemTable {
+    /// Deleted keys map to `None`
+    entries: BTreeMap<String, Option<String>>,
+    /// Total size of the keys and values in `entries`
+    bytes: usize,
+}
+
+/// A [Client] that can be shared between threads. Reads take a shared lock, so they don't
+/// block each other, while writes take it exclusively. Cloning it gives another handle to the
+/// same client.
+#[derive(Clone)]
+pub struct SharedClient {
+    client: Arc<RwLock<Client>>,
+}
+
+/// Builds a [Client] with non-default settings
+pub struct ClientBuilder {
+    db_name: String,
+    mem_table_max: usize,
+    mem_table_max_bytes: Option<usize>,
+    throttle_threshold: Option<usize>,
+    stall_threshold: Option<usize>,
+    fallback: Option<Box<dyn Scan + Send + Sync>>,
+    write_once: bool,
+    create_if_missing: bool,
+    auto_compact: bool,
+    background_compaction: bool,
+    durable: bool,
+    compaction_strategy: Option<CompactionStrategy>,
+}
+
+/// Tells a writer whether the database is keeping up with its writes
+#[derive(Debug, PartialEq)]
+pub enum WriteStatus {
+    Ok,
+    /// Compaction is falling behind. Nothing is blocked yet, but callers should slow down.
+    ThrottleRecommended,
+}
+
+/// A write to a watched key. See [Client::watch].
+#[derive(Debug, PartialEq)]
+pub struct ChangeEvent {
+    pub key: String,
+    pub kind: ChangeKind,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ChangeKind {
+    Put,
+    Delete,
+}
+
+impl ChangeKind {
+    fn of(kv: &KV) -> ChangeKind {
+        if kv.tombstone {
+            ChangeKind::Delete
+        } else {
+            ChangeKind::Put
+        }
+    }
+}
+
+impl ClientBuilder {
+    pub fn new(db_name: &str) -> ClientBuilder {
+        ClientBuilder {
+            db_name: db_name.to_string(),
+            mem_table_max: 10,
+            mem_table_max_bytes: None,
+            throttle_threshold: None,
+            stall_threshold: None,
+            fallback: None,
+            write_once: false,
+            create_if_missing: false,
+            auto_compact: true,
+            background_compaction: false,
+            durable: false,
+            compaction_strategy: None,
+        }
+    }
+
+    /// The number of entries the mem_table holds before it's flushed to disk
+    pub fn mem_table_max(mut self, mem_table_max: usize) -> ClientBuilder {
+        self.mem_table_max = mem_table_max;
+        self
+    }
+
+    /// Used to preallocate the mem_table. It's a BTreeMap now, which grows a node at a time
+    /// and has nothing to preallocate, so this does nothing. Kept so that existing callers
+    /// still compile.
+    #[deprecated(note = "the mem_table can't be preallocated, so this has no effect")]
+    pub fn mem_table_capacity(self, _mem_table_capacity: usize) -> ClientBuilder {
+        self
+    }
+
+    /// The total size in bytes of the keys and values the mem_table holds before it's flushed
+    /// to disk. This applies alongside `mem_table_max`; whichever is reached first triggers
+    /// the flush.
+    pub fn mem_table_max_bytes(mut self, mem_table_max_bytes: usize) -> ClientBuilder {
+        self.mem_table_max_bytes = Some(mem_table_max_bytes);
+        self
+    }
+
+    /// Once this many tables are waiting for compaction, writes report
+    /// [WriteStatus::ThrottleRecommended]
+    pub fn throttle_threshold(mut self, throttle_threshold: usize) -> ClientBuilder {
+        self.throttle_threshold = Some(throttle_threshold);
+        self
+    }
+
+    /// Once this many tables are waiting for compaction, writes are rejected with
+    /// [TableErr::WriteStalled] until compaction catches up
+    pub fn stall_threshold(mut self, stall_threshold: usize) -> ClientBuilder {
+        self.stall_threshold = Some(stall_threshold);
+        self
+    }
+
+    /// A slower store to fall back on for keys that aren't stored locally, turning the client
+    /// into a cache over it. [Client::get] writes whatever it fetches back into the mem_table,
+    /// so the next read for the key is served locally.
+    pub fn fallback(mut self, fallback: Box<dyn Scan + Send + Sync>) -> ClientBuilder {
+        self.fallback = Some(fallback);
+        self
+    }
+
+    /// Makes every key immutable once written: writes to a key that already has a value are
+    /// rejected with [TableErr::KeyExists], and deletes with [TableErr::WriteOnce]. Meant for
+    /// append-only data like audit logs.
+    pub fn write_once(mut self, write_once: bool) -> ClientBuilder {
+        self.write_once = write_once;
+        self
+    }
+
+    /// Creates the directory the database lives in (and any missing parents) if it doesn't
+    /// exist yet. Otherwise, building fails when the directory is missing.
+    pub fn create_if_missing(mut self, create_if_missing: bool) -> ClientBuilder {
+        self.create_if_missing = create_if_missing;
+        self
+    }
+
+    /// Whether flushing the mem_table also compacts the tree. On by default; without it, tables
+    /// pile up in level 0 and writes start being throttled and stalled.
+    pub fn auto_compact(mut self, auto_compact: bool) -> ClientBuilder {
+        self.auto_compact = auto_compact;
+        self
+    }
+
+    /// Compacts on a background thread rather than as part of the write that flushed the
+    /// mem_table. Off by default. See [LsmTree::start_background_compaction].
+    pub fn background_compaction(mut self, background_compaction: bool) -> ClientBuilder {
+        self.background_compaction = background_compaction;
+        self
+    }
+
+    /// Syncs every write-ahead log record, table and manifest to disk before relying on it, so
+    /// that acknowledged writes survive a power failure and not just a crash. Off by default,
+    /// since it makes every write wait on the disk.
+    pub fn durable(mut self, durable: bool) -> ClientBuilder {
+        self.durable = durable;
+        self
+    }
+
+    /// How the tree picks tables to compact. New databases are leveled unless told otherwise,
+    /// and existing ones keep whatever they were created with; opening one with a different
+    /// strategy fails.
+    pub fn compaction_strategy(mut self, compaction_strategy: CompactionStrategy) -> ClientBuilder {
+        self.compaction_strategy = Some(compaction_strategy);
+        self
+    }
+
+    /// Builds a client for a new, empty database
+    pub fn build(self) -> Result<Client, TableErr> {
+        self.check_dir()?;
+        let lsm_tree = LsmTree::with_strategy(self.db_name.to_string(), DEFAULT_LEVEL_SCALING_FACTOR, self.compaction_strategy.unwrap_or_default())?;
+        let wal = Wal::create(&wal_name(&self.db_name))?;
+
+        Ok(self.with_tree(lsm_tree, wal))
+    }
+
+    /// Builds a client for a database that's already on disk, picking up all of its tables.
+    /// Writes that hadn't been flushed yet are recovered from the write-ahead log.
+    pub fn open(self) -> Result<Client, TableErr> {
+        self.check_dir()?;
+        let lsm_tree = match self.compaction_strategy {
+            Some(compaction_strategy) => LsmTree::load_with_strategy(&self.db_name, compaction_strategy)?,
+            None => LsmTree::load(&self.db_name)?,
+        };
+        let (wal, replay) = Wal::open(&wal_name(&self.db_name))?;
+
+        let mut client = self.with_tree(lsm_tree, wal);
+        for kv in replay.records {
+            client.insert(kv);
+        }
+        client.wal_discarded_bytes = replay.discarded_bytes;
+
+        Ok(client)
+    }
+
+    fn check_dir(&self) -> Result<(), TableErr> {
+        // Table files are written next to the db name, so it's the parent that has to exist
+        if let Some(dir) = Path::new(&self.db_name).parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            if !dir.is_dir() {
+                if !self.create_if_missing {
+                    return Err(TableErr::IO(format!("The directory {} for database {} doesn't exist", dir.display(), self.db_name)));
+                }
+                std::fs::create_dir_all(dir)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn with_tree(self, mut lsm_tree: LsmTree, mut wal: Wal) -> Client {
+        lsm_tree.set_auto_compact(self.auto_compact);
+        lsm_tree.set_durable(self.durable);
+        wal.set_durable(self.durable);
+        let lsm_tree = Arc::new(lsm_tree);
+        if self.background_compaction {
+            LsmTree::start_background_compaction(&lsm_tree);
+        }
+
+        Client {
+            mem_table: RwLock::new(MemTable::default()),
+            wal,
+            lsm_tree,
+            max_size: self.mem_table_max,
+            max_bytes: self.mem_table_max_bytes,
+            pinned: HashMap::new(),
+            throttle_threshold: self.throttle_threshold,
+            stall_threshold: self.stall_threshold,
+            fallback: self.fallback,
+            write_once: self.write_once,
+            watchers: Mutex::new(Vec::new()),
+            wal_discarded_bytes: 0,
+        }
+    }
 }
 
 impl Client {
     pub fn new(db_name: &str) -> Result<Client, TableErr> {
-        return Ok(
-            Client {
-                mem_table: Vec::new(),
-                lsm_tree: LsmTree::new(db_name.to_string())?,
-                max_size: 10,
-            }
-        )
+        ClientBuilder::new(db_name).build()
     }
 
-    fn put(&mut self, key: String, value: String) -> Result<(), TableErr> {
-        let new_elem = KV {
-            key: key.to_string(),
-            value
-        };
+    /// Reopens a database that was written previously
+    pub fn open(db_name: &str) -> Result<Client, TableErr> {
+        ClientBuilder::new(db_name).open()
+    }
+
+    pub fn put(&mut self, key: String, value: String) -> Result<(), TableErr> {
+        self.put_with_status(key, value).map(|_| ())
+    }
+
+    /// Like [Client::put], but also reports whether the caller should back off because
+    /// compaction isn't keeping up
+    pub fn put_with_status(&mut self, key: String, value: String) -> Result<WriteStatus, TableErr> {
+        self.write(KV::new(key, value))
+    }
 
-        match self.find_index(&key) {
-            Ok(index) => {
-                self.mem_table.remove(index);
-                self.mem_table.insert(index, new_elem);
-            },
-            Err(BinSearchErr::SMALLER) => self.mem_table.insert(0, new_elem),
-            Err(BinSearchErr::LARGER) | Err(BinSearchErr::EMPTY) => self.mem_table.push(new_elem),
-            Err(BinSearchErr::MISSING(index)) => self.mem_table.insert(index + 1, new_elem),
+    /// Deletes the key by writing a tombstone for it, which hides any older values on disk.
+    /// Deleting a key that doesn't exist isn't an error. Deleted keys are also unpinned.
+    pub fn delete(&mut self, key: &str) -> Result<(), TableErr> {
+        if self.write_once {
+            return Err(TableErr::WriteOnce(format!("Can't delete {} from a write-once database", key)));
         }
 
+        self.write(KV::tombstone(key)).map(|_| ())
+    }
 
-        if self.mem_table.len() >= self.max_size {
-            let _ = self.lsm_tree.add(self.mem_table.clone());
-            self.mem_table = Vec::new();
+    fn write(&mut self, new_elem: KV) -> Result<WriteStatus, TableErr> {
+        let key = new_elem.key.to_string();
+        if key.is_empty() {
+            return Err(TableErr::InvalidKey(String::from("Keys can't be empty")));
         }
 
+        self.check_stalled()?;
+        self.check_unwritten(&key)?;
+        self.wal.append(&new_elem)?;
+        self.update_pinned(&new_elem);
+        let kind = ChangeKind::of(&new_elem);
+        self.insert(new_elem);
+
+        self.publish(&key, kind);
+        self.flush_if_full()?;
+
+        Ok(self.write_status())
+    }
+
+    /// Fails once compaction is so far behind that writes have to wait for it
+    fn check_stalled(&self) -> Result<(), TableErr> {
+        let backlog = self.lsm_tree.compaction_backlog();
+        if self.stall_threshold.is_some_and(|threshold| backlog >= threshold) {
+            return Err(TableErr::WriteStalled(format!("{} tables are waiting for compaction", backlog)));
+        }
 
         Ok(())
     }
 
-    fn get(&self, key: &str) -> Result<String, TableErr> {
-        if let Ok(index) = self.find_index(key) {
-            return Ok(self.mem_table[index].value.to_string())
-        } else {
-            if let Ok(val) = self.lsm_tree.read(key) {
-                return Ok(val);
-            } else {
-                return Err(TableErr::KeyNotFound(format!("DB does not contain {}", key)))
+    fn write_status(&self) -> WriteStatus {
+        let backlog = self.lsm_tree.compaction_backlog();
+        if self.throttle_threshold.is_some_and(|threshold| backlog >= threshold) {
+            return WriteStatus::ThrottleRecommended;
+        }
+
+        WriteStatus::Ok
+    }
+
+    /// Writes a batch of entries that's already sorted by key and free of duplicates, with the
+    /// batch's values replacing any existing ones. The batch is checked as a whole before
+    /// anything is written. Rather than inserting each entry separately, the batch is built
+    /// into a map of its own in one pass and then merged into the mem_table in another, so the
+    /// whole write is O(n + m).
+    ///
+    /// Like [Client::put_with_status], the batch is refused while writes are stalled, and the
+    /// status says whether the caller should back off.
+    pub fn put_batch_sorted(&mut self, sorted: Vec<KV>) -> Result<WriteStatus, TableErr> {
+        debug_assert!(sorted.windows(2).all(|pair| pair[0].key < pair[1].key), "put_batch_sorted requires sorted, deduplicated keys");
+
+        self.write_batch(sorted)
+    }
+
+    /// Writes a batch of entries in any order. Where a key appears more than once, the last of
+    /// its values wins. The whole batch goes into the mem_table before it's checked for
+    /// flushing, so a batch is never split across tables. The batch is sorted first, and then
+    /// written like [Client::put_batch_sorted].
+    pub fn put_batch(&mut self, entries: impl IntoIterator<Item = (String, String)>) -> Result<WriteStatus, TableErr> {
+        let batch: Vec<KV> = entries.into_iter().map(|(key, value)| KV::new(key, value)).collect();
+
+        if self.write_once {
+            // Checking against the database alone would let a batch overwrite itself
+            let mut seen = HashSet::new();
+            if let Some(kv) = batch.iter().find(|kv| !seen.insert(kv.key.as_str())) {
+                return Err(TableErr::KeyExists(format!("{} appears more than once in the batch", kv.key)));
             }
-        };
+        }
+
+        // Later entries replace earlier ones for the same key
+        let sorted: BTreeMap<String, KV> = batch.into_iter().map(|kv| (kv.key.to_string(), kv)).collect();
+        self.write_batch(sorted.into_values().collect())
     }
 
+    /// Writes a batch that's sorted by key and free of duplicates
+    fn write_batch(&mut self, batch: Vec<KV>) -> Result<WriteStatus, TableErr> {
+        if batch.iter().any(|kv| kv.key.is_empty()) {
+            return Err(TableErr::InvalidKey(String::from("Keys can't be empty")));
+        }
+        self.check_stalled()?;
+
+        // Check the whole batch up front so that it's rejected without any of it being written
+        for kv in &batch {
+            self.check_unwritten(&kv.key)?;
+        }
+
+        for kv in &batch {
+            self.wal.append(kv)?;
+        }
 
-    // Returns the index of the given key or the index of the largest element smaller
-    // than they key
-    fn find_index(&self, key: &str) -> Result<usize, BinSearchErr> {
-        if self.mem_table.len() < 1 {
-            return Err(BinSearchErr::EMPTY);
+        for kv in &batch {
+            self.update_pinned(kv);
+            self.publish(&kv.key, ChangeKind::of(kv));
         }
+        self.mem_table.get_mut().expect("mem_table lock was poisoned").append_sorted(batch);
+        self.flush_if_full()?;
 
-        let mut low = 0;
-        let mut high = self.mem_table.len() - 1;
-        let mut mid = (high + low) / 2;
+        Ok(self.write_status())
+    }
 
-        let key_str = key.to_string();
+    /// Returns the key's latest value, or `None` if it's never been written or has been
+    /// deleted. Every write lands in the mem_table before it reaches disk, so a value (or
+    /// tombstone) there is always newer than any flushed one and is returned without checking
+    /// the tree.
+    ///
+    /// Keys that have never been written locally are looked up in the fallback, if there is
+    /// one, and written back into the mem_table so that the next read is served locally.
+    /// Deleted keys stay deleted; the fallback is never asked about them.
+    pub fn get(&self, key: &str) -> Result<Option<String>, TableErr> {
+        if let Some(value) = self.pinned.get(key) {
+            return Ok(Some(value.to_string()));
+        }
 
-        if self.mem_table[low].key > key_str {
-            return Err(BinSearchErr::SMALLER);
+        if let Some(entry) = self.mem_table().entries.get(key) {
+            return Ok(entry.clone());
         }
 
-        if self.mem_table[high].key < key_str { 
-            return Err(BinSearchErr::LARGER);
+        match self.lsm_tree.read(key) {
+            Ok(value) => Ok(Some(value)),
+            Err(TableErr::KeyDeleted(_)) => Ok(None),
+            Err(TableErr::KeyNotFound(_)) => self.read_fallback(key),
+            Err(e) => Err(e),
         }
+    }
+
+    /// Fetches a key that isn't stored locally from the fallback and writes it back. The
+    /// write back isn't logged, since the fallback still has the value if it's lost, and it
+    /// doesn't flush; the next write does that if the mem_table's full.
+    fn read_fallback(&self, key: &str) -> Result<Option<String>, TableErr> {
+        let Some(fallback) = &self.fallback else {
+            return Ok(None);
+        };
+
+        let value = match fallback.read(key) {
+            Ok(value) => value,
+            Err(TableErr::KeyNotFound(_)) => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        self.mem_table.write().expect("mem_table lock was poisoned").insert(KV::new(key, value.to_string()));
+
+        Ok(Some(value))
+    }
 
-        while low < high && self.mem_table[mid].key != key_str {
-            if self.mem_table[mid].key < key_str {
-                low = mid + 1;
+    /// Like [Client::get] for several keys at once, with `None` for the ones that are missing.
+    /// The values line up with `keys`. Whatever can't be served from memory is looked up with
+    /// one read of each table, rather than one per key.
+    pub fn get_many(&self, keys: &[&str]) -> Result<Vec<Option<String>>, TableErr> {
+        let mut values = vec![None; keys.len()];
+        let mut on_disk = Vec::new();
+        for (i, key) in keys.iter().enumerate() {
+            if let Some(value) = self.pinned.get(*key) {
+                values[i] = Some(value.to_string());
+            } else if let Some(entry) = self.mem_table().entries.get(*key) {
+                values[i] = entry.clone();
             } else {
-                high = if mid > 0 {
-                    mid - 1
-                } else {
-                    mid
-                }
+                on_disk.push(i);
+            }
+        }
+
+        let disk_keys: Vec<&str> = on_disk.iter().map(|&i| keys[i]).collect();
+        for (i, value) in on_disk.into_iter().zip(self.lsm_tree.read_many(&disk_keys)?) {
+            values[i] = value;
+        }
+
+        Ok(values)
+    }
+
+    /// Whether the key has a value, without reading the value from disk. Deleted keys don't.
+    pub fn contains_key(&self, key: &str) -> Result<bool, TableErr> {
+        if self.pinned.contains_key(key) {
+            return Ok(true);
+        }
+
+        match self.mem_table().entries.get(key) {
+            Some(entry) => Ok(entry.is_some()),
+            None => self.lsm_tree.contains_key(key),
+        }
+    }
+
+    /// Like [Client::get], but also says whether the value was served from memory (the
+    /// mem_table or a pinned key) rather than read from disk. Missing keys are `None`; the
+    /// fallback isn't consulted.
+    pub fn get_cached(&self, key: &str) -> Result<Option<(String, bool)>, TableErr> {
+        if let Some(value) = self.pinned.get(key) {
+            return Ok(Some((value.to_string(), true)));
+        }
+
+        if let Some(entry) = self.mem_table().entries.get(key) {
+            return Ok(entry.clone().map(|value| (value, true)));
+        }
+
+        match self.lsm_tree.read(key) {
+            Ok(value) => Ok(Some((value, false))),
+            Err(TableErr::KeyNotFound(_)) | Err(TableErr::KeyDeleted(_)) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Iterates over every key in the database in order, with its latest value, merging the
+    /// mem_table with every table on disk. Entries are streamed from disk rather than loaded
+    /// up front; only the mem_table is copied.
+    pub fn merged_iter(&self) -> Result<EntryIter, TableErr> {
+        let mem_table: EntryIter = Box::new(self.mem_table().entries.clone().into_iter().map(|entry| Ok(to_kv(entry))));
+
+        Ok(merge_newest_first_live(vec![mem_table, self.lsm_tree.merged_iter()?]))
+    }
+
+    /// Every key in the database in order, without reading any values from disk. Deleted keys
+    /// are left out.
+    pub fn keys(&self) -> Result<impl Iterator<Item = Result<String, TableErr>>, TableErr> {
+        let mem_table: Vec<_> = self.mem_table().entries.iter()
+            .map(|(key, value)| Ok(KV { key: key.to_string(), value: String::new(), tombstone: value.is_none() }))
+            .collect();
+        let on_disk = self.lsm_tree.keys()?.map(|key| key.map(|key| KV::new(key, String::new())));
+
+        let merged = merge_newest_first_live(vec![Box::new(mem_table.into_iter()), Box::new(on_disk)]);
+        Ok(merged.map(|entry| entry.map(|kv| kv.key)))
+    }
+
+    /// Every live key in order with the size in bytes of its latest value, without reading any
+    /// values from disk. Sizes on disk come from the tables' indexes.
+    pub fn size_report(&self) -> Result<impl Iterator<Item = Result<(String, usize), TableErr>>, TableErr> {
+        let mem_table: Vec<_> = self.mem_table().entries.iter()
+            .map(|(key, value)| {
+                let kv = KV { key: key.to_string(), value: String::new(), tombstone: value.is_none() };
+                Ok((kv, value.as_ref().map_or(0, String::len)))
+            })
+            .collect();
+        let on_disk = self.lsm_tree.value_sizes()?.map(|entry| entry.map(|(key, size)| (KV::new(key, String::new()), size)));
+
+        let merged = merge_newest_first_live_with(vec![Box::new(mem_table.into_iter()), Box::new(on_disk)]);
+        Ok(merged.map(|entry| entry.map(|(kv, size)| (kv.key, size))))
+    }
+
+    /// Every key from `start` (inclusive) to `end` (exclusive) in order, with its latest value.
+    /// Deleted keys are left out, including ones whose tombstone is still in the mem_table.
+    pub fn range(&self, start: &str, end: &str) -> Result<Vec<KV>, TableErr> {
+        if start >= end {
+            return Ok(Vec::new());
+        }
+
+        let mem_table: Vec<_> = self.mem_table().entries.range(start.to_string()..end.to_string())
+            .map(|(key, value)| Ok(to_kv((key.to_string(), value.clone()))))
+            .collect();
+
+        merge_newest_first_live(vec![Box::new(mem_table.into_iter()), self.lsm_tree.scan_range(start, end)?]).collect()
+    }
+
+    /// Every live entry whose key matches the glob `pattern`, in key order. `*` matches any run
+    /// of characters (including none) and `?` matches exactly one; everything else matches
+    /// itself. Only keys starting with the pattern's literal prefix (everything before the
+    /// first wildcard) are read, so `user:*` never looks at keys outside `user:`. A pattern
+    /// starting with a wildcard has to scan everything, and one without any is a point lookup.
+    pub fn scan_glob(&self, pattern: &str) -> Result<EntryIter, TableErr> {
+        let Some(wildcard) = pattern.find(['*', '?']) else {
+            let found = self.get(pattern)?.map(|value| Ok(KV::new(pattern, value)));
+            return Ok(Box::new(found.into_iter()));
+        };
+
+        let candidates = self.scan_prefix(&pattern[..wildcard])?;
+        let pattern = pattern.to_string();
+        Ok(Box::new(candidates.filter(move |entry| !matches!(entry, Ok(kv) if !glob_matches(&pattern, &kv.key)))))
+    }
+
+    /// Every live entry whose key starts with `prefix`, in key order
+    fn scan_prefix(&self, prefix: &str) -> Result<EntryIter, TableErr> {
+        let Some(end) = prefix_end(prefix) else {
+            // Every key's past the prefix's range, or it's the whole keyspace
+            let prefix = prefix.to_string();
+            return Ok(Box::new(self.merged_iter()?.filter(move |entry| !matches!(entry, Ok(kv) if !kv.key.starts_with(&prefix)))));
+        };
+
+        let mem_table: Vec<_> = self.mem_table().entries.range(prefix.to_string()..end.to_string())
+            .map(|(key, value)| Ok(to_kv((key.to_string(), value.clone()))))
+            .collect();
+
+        Ok(merge_newest_first_live(vec![Box::new(mem_table.into_iter()), self.lsm_tree.scan_range(prefix, &end)?]))
+    }
+
+    /// Deletes every entry for which `f(key, value)` is false, returning how many were deleted.
+    /// Each one is deleted with a tombstone, as with [Client::delete], so nothing is rewritten
+    /// until compaction gets to it.
+    pub fn retain(&mut self, f: impl Fn(&str, &str) -> bool) -> Result<usize, TableErr> {
+        let mut deleted = 0;
+        // The iterator works from a copy of the mem_table and its own view of the tree, so the
+        // deletes (and any flushes they cause) don't disturb it
+        for entry in self.merged_iter()? {
+            let kv = entry?;
+            if !f(&kv.key, &kv.value) {
+                self.delete(&kv.key)?;
+                deleted += 1;
             }
-            mid = (high + low) / 2;
         }
 
-        if self.mem_table[mid].key == key_str {
-            return Ok(mid);
+        Ok(deleted)
+    }
+
+    /// Returns the key's value, or if it doesn't have one, stores and returns the result of
+    /// `f`. `f` is only called when the key is missing.
+    pub fn get_or_insert_with(&mut self, key: &str, f: impl FnOnce() -> String) -> Result<String, TableErr> {
+        if let Some((value, _)) = self.get_cached(key)? {
+            return Ok(value);
+        }
+
+        let value = f();
+        self.put(key.to_string(), value.to_string())?;
+
+        Ok(value)
+    }
+
+    /// Keeps the key's latest value in memory so that reads for it never touch disk, even
+    /// after the mem_table has been flushed. The key must already exist.
+    pub fn pin(&mut self, key: &str) -> Result<(), TableErr> {
+        let Some(value) = self.get(key)? else {
+            return Err(TableErr::KeyNotFound(key.to_string()));
+        };
+        self.pinned.insert(key.to_string(), value);
+
+        Ok(())
+    }
+
+    pub fn unpin(&mut self, key: &str) {
+        self.pinned.remove(key);
+    }
+
+    fn update_pinned(&mut self, kv: &KV) {
+        if kv.tombstone {
+            self.pinned.remove(&kv.key);
+        } else if let Some(pinned_value) = self.pinned.get_mut(&kv.key) {
+            *pinned_value = kv.value.to_string();
+        }
+    }
+
+    fn flush_if_full(&mut self) -> Result<(), TableErr> {
+        let mem_table = self.mem_table.get_mut().expect("mem_table lock was poisoned");
+        if mem_table.entries.len() >= self.max_size || self.max_bytes.is_some_and(|max_bytes| mem_table.bytes >= max_bytes) {
+            self.flush_mem_table()?;
+        }
+
+        Ok(())
+    }
+
+    /// How many bytes of incomplete records were cut off the end of the write-ahead log when
+    /// the database was opened. Anything above zero means the last run crashed part way
+    /// through appending a write. That write is lost, but it was never acknowledged either.
+    pub fn wal_discarded_bytes(&self) -> usize {
+        self.wal_discarded_bytes
+    }
+
+    /// Syncs the write-ahead log to disk, so that every write made so far survives a power
+    /// failure even without `durable`. Cheaper than [Client::flush], since nothing is written
+    /// out to a table.
+    pub fn sync(&mut self) -> Result<(), TableErr> {
+        self.wal.sync()
+    }
+
+    /// Closes the database cleanly: the mem_table is flushed, background compaction finishes
+    /// whatever it's been asked to do and stops, and the write-ahead log is synced. Unlike
+    /// dropping the client, any error along the way is returned.
+    pub fn shutdown(mut self) -> Result<(), TableErr> {
+        self.flush_mem_table()?;
+        self.lsm_tree.stop_background_compaction()?;
+        self.wal.sync()
+    }
+
+    /// Writes the mem_table out to a new table now, however full it is, e.g. as a checkpoint.
+    /// Does nothing if the mem_table is empty.
+    pub fn flush(&mut self) -> Result<(), TableErr> {
+        self.flush_mem_table()
+    }
+
+    /// Reclaims the space taken up by deleted keys. The mem_table is flushed, and then the
+    /// whole tree is merged into a single table at the bottom, which drops every tombstone
+    /// along with the older values they were hiding. See [LsmTree::purge_tombstones].
+    pub fn gc(&mut self) -> Result<(), TableErr> {
+        self.flush_mem_table()?;
+        self.lsm_tree.purge_tombstones()
+    }
+
+    fn flush_mem_table(&mut self) -> Result<(), TableErr> {
+        let mem_table = self.mem_table.get_mut().expect("mem_table lock was poisoned");
+        if mem_table.entries.is_empty() {
+            return Ok(());
+        }
+
+        self.lsm_tree.add(mem_table.entries.iter().map(|(key, value)| to_kv((key.to_string(), value.clone()))))?;
+        // Only once the table's safely written, so that a failed flush loses nothing and can
+        // be retried
+        *mem_table = MemTable::default();
+        self.wal.clear()
+    }
+
+    fn insert(&mut self, kv: KV) {
+        self.mem_table.get_mut().expect("mem_table lock was poisoned").insert(kv);
+    }
+
+    fn mem_table(&self) -> RwLockReadGuard<'_, MemTable> {
+        self.mem_table.read().expect("mem_table lock was poisoned")
+    }
+
+    /// In write-once mode, fails if the key already has a value
+    fn check_unwritten(&self, key: &str) -> Result<(), TableErr> {
+        if self.write_once && self.get_cached(key)?.is_some() {
+            return Err(TableErr::KeyExists(format!("{} has already been written", key)));
+        }
+
+        Ok(())
+    }
+
+    /// Returns a channel that's sent a [ChangeEvent] for every later write to a key starting
+    /// with `key_or_prefix`. Dropping the receiver unregisters the watch.
+    pub fn watch(&self, key_or_prefix: &str) -> Receiver<ChangeEvent> {
+        let (sender, receiver) = mpsc::channel();
+        self.watchers.lock().expect("Watchers lock was poisoned").push((key_or_prefix.to_string(), sender));
+
+        receiver
+    }
+
+    fn publish(&self, key: &str, kind: ChangeKind) {
+        let mut watchers = self.watchers.lock().expect("Watchers lock was poisoned");
+        // Sending only fails once the receiver is gone, so that's when the watch gets pruned
+        watchers.retain(|(prefix, sender)| {
+            !key.starts_with(prefix.as_str()) || sender.send(ChangeEvent { key: key.to_string(), kind: kind.clone() }).is_ok()
+        });
+    }
+}
+
+impl MemTable {
+    fn insert(&mut self, kv: KV) {
+        let key_len = kv.key.len();
+        self.bytes += key_len + kv.value.len();
+        let value = (!kv.tombstone).then_some(kv.value);
+        if let Some(old_value) = self.entries.insert(kv.key, value) {
+            self.bytes -= key_len + old_value.map_or(0, |old_value| old_value.len());
+        }
+    }
+
+    /// Merges in entries that are sorted by key and free of duplicates, replacing any existing
+    /// values for their keys. A map built from sorted entries is built in one pass, and
+    /// appending it is a single merge, rather than a lookup and insert per entry.
+    fn append_sorted(&mut self, sorted: Vec<KV>) {
+        let mut batch: BTreeMap<String, Option<String>> = sorted.into_iter()
+            .map(|kv| (kv.key, (!kv.tombstone).then_some(kv.value)))
+            .collect();
+        self.entries.append(&mut batch);
+
+        // Working out which values were replaced would take a lookup per entry again
+        self.bytes = self.entries.iter().map(|(key, value)| key.len() + value.as_ref().map_or(0, |value| value.len())).sum();
+    }
+}
+
+/// The smallest key that sorts after every key starting with `prefix`, or `None` if there
+/// isn't one (an empty prefix, or one that's all `char::MAX`). Keys compare as UTF-8 bytes,
+/// which sort the same way as the characters they encode.
+fn prefix_end(prefix: &str) -> Option<String> {
+    let mut end: Vec<char> = prefix.chars().collect();
+    while let Some(last) = end.pop() {
+        // Surrogates aren't chars, so the one after the last of them is the first after the gap
+        let next = (u32::from(last) + 1..=u32::from(char::MAX)).find_map(char::from_u32);
+        if let Some(next) = next {
+            end.push(next);
+            return Some(end.into_iter().collect());
+        }
+    }
+
+    None
+}
+
+/// Whether the whole of `key` matches the glob `pattern`, where `*` matches any run of
+/// characters and `?` any single one
+fn glob_matches(pattern: &str, key: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let key: Vec<char> = key.chars().collect();
+    let (mut p, mut k) = (0, 0);
+    // Where the last `*` was, and where in the key it's currently matched up to
+    let mut star: Option<(usize, usize)> = None;
+
+    while k < key.len() {
+        if p < pattern.len() && (pattern[p] == '?' || (pattern[p] != '*' && pattern[p] == key[k])) {
+            p += 1;
+            k += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            star = Some((p, k));
+            p += 1;
+        } else if let Some((star_p, star_k)) = star {
+            // Let the last `*` swallow one more character and try again from there
+            star = Some((star_p, star_k + 1));
+            p = star_p + 1;
+            k = star_k + 1;
+        } else {
+            return false;
         }
+    }
+
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+fn wal_name(db_name: &str) -> String {
+    format!("{}.wal", db_name)
+}
+
+fn to_kv((key, value): (String, Option<String>)) -> KV {
+    match value {
+        Some(value) => KV::new(key, value),
+        None => KV::tombstone(&key),
+    }
+}
+
+impl SharedClient {
+    pub fn new(client: Client) -> SharedClient {
+        SharedClient { client: Arc::new(RwLock::new(client)) }
+    }
+
+    pub fn get(&self, key: &str) -> Result<Option<String>, TableErr> {
+        self.read().get(key)
+    }
 
-        Err(BinSearchErr::MISSING(mid))
+    pub fn put(&self, key: String, value: String) -> Result<(), TableErr> {
+        self.write().put(key, value)
+    }
+
+    pub fn delete(&self, key: &str) -> Result<(), TableErr> {
+        self.write().delete(key)
+    }
+
+    /// Shared access to the client, for any of its other reads
+    pub fn read(&self) -> RwLockReadGuard<'_, Client> {
+        self.client.read().expect("Client lock was poisoned")
+    }
+
+    /// Exclusive access to the client, for any of its other writes
+    pub fn write(&self) -> RwLockWriteGuard<'_, Client> {
+        self.client.write().expect("Client lock was poisoned")
+    }
+}
+
+impl Drop for Client {
+    /// Writes out whatever's left in the mem_table so that it isn't lost. There's no one to
+    /// return an error to, so it's only logged.
+    fn drop(&mut self) {
+        if let Err(e) = self.flush_mem_table() {
+            eprintln!("Failed to flush the mem_table on drop: {:?}", e);
+        }
     }
 }
 
 #[cfg(test)]
 mod test {
     use crate::db::client::*;
+    use std::sync::Arc;
+    use std::sync::atomic::{ AtomicUsize, Ordering };
+    use crate::lsm::kv::{ encode_u64, decode_u64 };
     #[test]
     fn str_eq() {
         let s = "foo".to_string();
@@ -122,49 +862,49 @@ mod test {
 
     #[test]
     fn put_get() -> Result<(), TableErr> {
-        let mut instance = Client::new("client-test")?;
+        let mut instance = Client::new("test_files/client_put_get_test")?;
 
         let test_elems: [KV; 4] = [
-                KV { key: String::from("foo"), value: String::from("bar") },
-                KV { key: String::from("egg"), value: String::from("baz") },
-                KV { key: String::from("mome"), value: String::from("rath") },
-                KV { key: String::from("wibbly"), value: String::from("wobbly") },
+                KV::new("foo", "bar"),
+                KV::new("egg", "baz"),
+                KV::new("mome", "rath"),
+                KV::new("wibbly", "wobbly"),
             ];
 
         for elem in test_elems {
             let _ = instance.put(elem.key.to_string(), elem.value.to_string());
-            assert_eq!(instance.get(&elem.key[..])?, elem.value);
+            assert_eq!(instance.get(&elem.key[..])?, Some(elem.value));
         }
 
         Ok(())
     }
     
     #[test]
-    fn get_index() -> Result<(), BinSearchErr> {
-        println!("Starting get_index");
-        let mut instance = Client::new("client-test").expect("Failed to build client");
-
-        let test_elems: [KV; 4] = [
-                KV { key: String::from("foo"), value: String::from("bar") },
-                KV { key: String::from("egg"), value: String::from("baz") },
-                KV { key: String::from("mome"), value: String::from("rath") },
-                KV { key: String::from("wibbly"), value: String::from("wobbly") },
-            ];
+    fn keeps_mem_table_sorted() -> Result<(), TableErr> {
+        let mut instance = Client::new("test_files/client_index_test")?;
 
-        for elem in test_elems {
-            let _ = instance.put(elem.key.to_string(), elem.value.to_string());
+        for key in ["foo", "egg", "mome", "wibbly"] {
+            instance.put(key.to_string(), String::from("value"))?;
         }
 
-        println!("Starting assertions");
-        assert_eq!(0, instance.find_index("egg")?);
-        assert_eq!(1, instance.find_index("foo")?);
-        assert_eq!(2, instance.find_index("mome")?);
-        assert_eq!(3, instance.find_index("wibbly")?);
-        if let Err(BinSearchErr::MISSING(_)) = instance.find_index("gumgum") {
-            // Happy case
-        } else {
-            panic!("Expected a MISSING response");
-        }
+        assert_eq!(vec!["egg", "foo", "mome", "wibbly"], instance.mem_table().entries.keys().collect::<Vec<_>>());
+        assert!(!instance.mem_table().entries.contains_key("gumgum"));
+
+        Ok(())
+    }
+
+    #[test]
+    #[allow(deprecated)]
+    fn ignores_mem_table_capacity() -> Result<(), TableErr> {
+        let mut instance = ClientBuilder::new("test_files/client-capacity-test")
+            .mem_table_capacity(100)
+            .mem_table_max(2)
+            .build()?;
+
+        instance.put(String::from("a"), String::from("1"))?;
+        instance.put(String::from("b"), String::from("2"))?;
+        assert!(instance.mem_table().entries.is_empty());
+        assert_eq!(Some("1"), instance.get("a")?.as_deref());
 
         Ok(())
     }
@@ -180,8 +920,941 @@ mod test {
                 i.to_string(),
             )?;
 
-            assert_eq!(instance.get(&i.to_string())?, i.to_string());
+            assert_eq!(instance.get(&i.to_string())?, Some(i.to_string()));
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn orders_encoded_u64_keys_numerically() -> Result<(), TableErr> {
+        let _ = std::fs::remove_dir_all("test_files/client_u64_test");
+        std::fs::create_dir_all("test_files/client_u64_test")?;
+        let mut instance = ClientBuilder::new("test_files/client_u64_test/db").mem_table_max(4).build()?;
+
+        // The first four are flushed to a table, the rest stay in the mem_table
+        for n in [100, 2, 10, 1, 1000, 5] {
+            instance.put(encode_u64(n), n.to_string())?;
         }
+        assert_eq!(2, instance.mem_table().entries.len());
+
+        let ordered = instance.merged_iter()?
+            .map(|entry| decode_u64(&entry?.key))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        assert_eq!(vec![1, 2, 5, 10, 100, 1000], ordered);
+        assert_eq!(instance.get(&encode_u64(10))?.as_deref(), Some("10"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn pinned_keys_skip_disk() -> Result<(), TableErr> {
+        let mut instance = Client::new("test_files/client-pin-test")?;
+
+        instance.put(String::from("hot"), String::from("cold"))?;
+        instance.pin("hot")?;
+        instance.put(String::from("hot"), String::from("warm"))?;
+
+        // Fill the mem_table so that it gets flushed to disk
+        for i in 0..9 {
+            instance.put(i.to_string(), i.to_string())?;
+        }
+        assert!(instance.mem_table().entries.is_empty());
+
+        // With the table gone, only pinned keys can still be read
+        std::fs::remove_file("test_files/client-pin-test-0-1.index")?;
+        std::fs::remove_file("test_files/client-pin-test-0-1.data")?;
+
+        assert_eq!(instance.get("hot")?.as_deref(), Some("warm"));
+        assert!(instance.get("1").is_err());
+
+        instance.unpin("hot");
+        assert!(instance.get("hot").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn signals_backpressure() -> Result<(), TableErr> {
+        // Every put flushes a table that nothing ever compacts
+        let mut instance = ClientBuilder::new("test_files/client-backpressure-test")
+            .mem_table_max(1)
+            .auto_compact(false)
+            .throttle_threshold(2)
+            .stall_threshold(4)
+            .build()?;
+
+        assert_eq!(WriteStatus::Ok, instance.put_with_status(String::from("a"), String::from("1"))?);
+        assert_eq!(WriteStatus::ThrottleRecommended, instance.put_with_status(String::from("b"), String::from("2"))?);
+        assert_eq!(WriteStatus::ThrottleRecommended, instance.put_with_status(String::from("c"), String::from("3"))?);
+        assert_eq!(WriteStatus::ThrottleRecommended, instance.put_with_status(String::from("d"), String::from("4"))?);
+
+        assert!(matches!(
+            instance.put_with_status(String::from("e"), String::from("5")),
+            Err(TableErr::WriteStalled(_))
+        ));
+        assert!(instance.put(String::from("e"), String::from("5")).is_err());
+        assert_eq!(None, instance.get("e")?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn signals_backpressure_to_batches() -> Result<(), TableErr> {
+        let _ = std::fs::remove_dir_all("test_files/client_batch_backpressure_test");
+        std::fs::create_dir_all("test_files/client_batch_backpressure_test")?;
+
+        // Every batch fills the mem_table, so each one flushes a table that's never compacted
+        let mut instance = ClientBuilder::new("test_files/client_batch_backpressure_test/db")
+            .mem_table_max(2)
+            .auto_compact(false)
+            .throttle_threshold(2)
+            .stall_threshold(3)
+            .build()?;
+        let batch = |prefix: &str| vec![(format!("{}1", prefix), String::from("1")), (format!("{}2", prefix), String::from("2"))];
+
+        assert_eq!(WriteStatus::Ok, instance.put_batch(batch("a"))?);
+        assert_eq!(WriteStatus::ThrottleRecommended, instance.put_batch(batch("b"))?);
+        assert_eq!(WriteStatus::ThrottleRecommended, instance.put_batch_sorted(vec![KV::new("c1", "1"), KV::new("c2", "2")])?);
+
+        assert!(matches!(instance.put_batch(batch("d")), Err(TableErr::WriteStalled(_))));
+        assert!(matches!(instance.put_batch_sorted(vec![KV::new("e1", "1")]), Err(TableErr::WriteStalled(_))));
+        assert_eq!(None, instance.get("d1")?);
+        assert_eq!(None, instance.get("e1")?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn reports_mem_table_hits() -> Result<(), TableErr> {
+        let mut instance = Client::new("test_files/client-cached-test")?;
+
+        instance.put(String::from("warm"), String::from("soup"))?;
+        assert_eq!(Some((String::from("soup"), true)), instance.get_cached("warm")?);
+        assert_eq!(None, instance.get_cached("cold")?);
+
+        // Fill the mem_table so that it gets flushed to disk
+        for i in 0..9 {
+            instance.put(i.to_string(), i.to_string())?;
+        }
+
+        assert_eq!(Some((String::from("soup"), false)), instance.get_cached("warm")?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn rejects_empty_keys() -> Result<(), TableErr> {
+        let mut instance = Client::new("test_files/client-empty-key-test")?;
+
+        assert!(matches!(instance.put(String::new(), String::from("nothing")), Err(TableErr::InvalidKey(_))));
+        assert!(instance.mem_table().entries.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn puts_sorted_batches() -> Result<(), TableErr> {
+        let mut instance = ClientBuilder::new("test_files/client-sorted-batch-test")
+            .mem_table_max(1000)
+            .build()?;
+
+        for i in (0..100).step_by(10) {
+            instance.put(format!("{:04}", i), String::from("old"))?;
+        }
+        instance.put(String::from("zzzz"), String::from("last"))?;
+
+        let batch: Vec<KV> = (0..500).map(|i| KV::new(format!("{:04}", i), i.to_string())).collect();
+        instance.put_batch_sorted(batch)?;
+
+        assert_eq!(501, instance.mem_table().entries.len());
+        // The replaced values' sizes are dropped along with them
+        let expected_bytes = 500 * 4 + (0..500).map(|i: usize| i.to_string().len()).sum::<usize>() + "zzzz".len() + "last".len();
+        assert_eq!(expected_bytes, instance.mem_table().bytes);
+        assert_eq!(instance.get("0010")?.as_deref(), Some("10"));
+        assert_eq!(instance.get("0499")?.as_deref(), Some("499"));
+        assert_eq!(instance.get("zzzz")?.as_deref(), Some("last"));
+
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "sorted, deduplicated keys")]
+    fn rejects_unsorted_batches() {
+        let mut instance = Client::new("test_files/client-unsorted-batch-test").expect("Failed to build client");
+
+        let _ = instance.put_batch_sorted(vec![
+            KV::new("b", "2"),
+            KV::new("a", "1"),
+        ]);
+    }
+
+    struct CountingStore {
+        reads: Arc<AtomicUsize>,
+    }
+
+    impl Scan for CountingStore {
+        fn read(&self, key: &str) -> Result<String, TableErr> {
+            self.reads.fetch_add(1, Ordering::SeqCst);
+            match key {
+                "remote" => Ok(String::from("from afar")),
+                _ => Err(TableErr::KeyNotFound(key.to_string())),
+            }
+        }
+    }
+
+    #[test]
+    fn reads_through_to_fallback() -> Result<(), TableErr> {
+        let reads = Arc::new(AtomicUsize::new(0));
+        let mut instance = ClientBuilder::new("test_files/client-fallback-test")
+            .fallback(Box::new(CountingStore { reads: Arc::clone(&reads) }))
+            .build()?;
+
+        instance.put(String::from("local"), String::from("close by"))?;
+        assert_eq!(instance.get("local")?.as_deref(), Some("close by"));
+        assert_eq!(0, reads.load(Ordering::SeqCst));
+
+        assert_eq!(instance.get("remote")?.as_deref(), Some("from afar"));
+        assert_eq!(1, reads.load(Ordering::SeqCst));
+
+        // Written back, so it's now served locally
+        assert_eq!(Some((String::from("from afar"), true)), instance.get_cached("remote")?);
+        assert_eq!(instance.get("remote")?.as_deref(), Some("from afar"));
+        assert_eq!(1, reads.load(Ordering::SeqCst));
+
+        assert_eq!(None, instance.get("nowhere")?);
+        assert_eq!(2, reads.load(Ordering::SeqCst));
+
+        Ok(())
+    }
+
+    #[test]
+    fn deleted_keys_skip_the_fallback() -> Result<(), TableErr> {
+        let reads = Arc::new(AtomicUsize::new(0));
+        let mut instance = ClientBuilder::new("test_files/client-fallback-delete-test")
+            .mem_table_max(2)
+            .fallback(Box::new(CountingStore { reads: Arc::clone(&reads) }))
+            .build()?;
+
+        // The tombstone's still in the mem_table
+        assert_eq!(instance.get("remote")?.as_deref(), Some("from afar"));
+        instance.delete("remote")?;
+        assert_eq!(None, instance.get("remote")?);
+
+        // And once it's been flushed to the tree
+        instance.put(String::from("other"), String::from("1"))?;
+        assert!(instance.mem_table().entries.is_empty());
+        assert_eq!(None, instance.get("remote")?);
+        assert_eq!(1, reads.load(Ordering::SeqCst));
+
+        Ok(())
+    }
+
+    #[test]
+    fn notifies_watchers() -> Result<(), TableErr> {
+        let mut instance = Client::new("test_files/watch_test")?;
+        let watch = instance.watch("user/");
+        let dropped = instance.watch("user/");
+        drop(dropped);
+
+        instance.put(String::from("user/1"), String::from("alice"))?;
+        instance.put(String::from("group/1"), String::from("admins"))?;
+        instance.put_batch_sorted(vec![
+            KV::new("other", "x"),
+            KV::new("user/2", "bob"),
+        ])?;
+
+        let events: Vec<ChangeEvent> = watch.try_iter().collect();
+        assert_eq!(vec![
+            ChangeEvent { key: String::from("user/1"), kind: ChangeKind::Put },
+            ChangeEvent { key: String::from("user/2"), kind: ChangeKind::Put },
+        ], events);
+        assert_eq!(1, instance.watchers.lock().expect("Watchers lock was poisoned").len());
+
+        Ok(())
+    }
+
+    #[test]
+    fn write_once_rejects_overwrites() -> Result<(), TableErr> {
+        let mut instance = ClientBuilder::new("test_files/write_once_test").mem_table_max(2).write_once(true).build()?;
+
+        instance.put(String::from("first"), String::from("1"))?;
+        assert!(matches!(instance.put(String::from("first"), String::from("2")), Err(TableErr::KeyExists(_))));
+
+        // Flushes, so the next check has to go to disk
+        instance.put(String::from("second"), String::from("2"))?;
+        assert!(matches!(instance.put(String::from("second"), String::from("3")), Err(TableErr::KeyExists(_))));
+
+        let batch = vec![
+            KV::new("first", "4"),
+            KV::new("third", "3"),
+        ];
+        assert!(matches!(instance.put_batch_sorted(batch), Err(TableErr::KeyExists(_))));
+        assert_eq!(None, instance.get("third")?);
+
+        assert_eq!(Some("1"), instance.get("first")?.as_deref());
+        assert_eq!(Some("2"), instance.get("second")?.as_deref());
+
+        Ok(())
+    }
+
+    #[test]
+    fn inserts_missing_values_lazily() -> Result<(), TableErr> {
+        let mut instance = ClientBuilder::new("test_files/get_or_insert_test").mem_table_max(2).build()?;
+        instance.put(String::from("present"), String::from("stored"))?;
+
+        let mut calls = 0;
+        assert_eq!("stored", instance.get_or_insert_with("present", || { calls += 1; String::from("computed") })?);
+        assert_eq!(0, calls);
+
+        assert_eq!("computed", instance.get_or_insert_with("absent", || { calls += 1; String::from("computed") })?);
+        assert_eq!(1, calls);
+
+        // The second put flushed the mem_table, so this is read back from disk
+        assert_eq!(Some((String::from("computed"), false)), instance.get_cached("absent")?);
+        assert_eq!("computed", instance.get_or_insert_with("absent", || { calls += 1; String::from("recomputed") })?);
+        assert_eq!(1, calls);
+
+        Ok(())
+    }
+
+    #[test]
+    fn flushes_on_byte_size() -> Result<(), TableErr> {
+        let mut instance = ClientBuilder::new("test_files/max_bytes_test").mem_table_max(10).mem_table_max_bytes(2500).build()?;
+
+        instance.put(String::from("a"), "x".repeat(1000))?;
+        instance.put(String::from("b"), "x".repeat(1000))?;
+        // Overwrites replace the old value's size rather than adding to it
+        instance.put(String::from("b"), "y".repeat(1000))?;
+        assert_eq!(2, instance.mem_table().entries.len());
+
+        instance.put(String::from("c"), "x".repeat(1000))?;
+        assert!(instance.mem_table().entries.is_empty());
+        assert_eq!(0, instance.mem_table().bytes);
+        assert_eq!(Some(("y".repeat(1000), false)), instance.get_cached("b")?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn iterates_everything_merged() -> Result<(), TableErr> {
+        let mut instance = ClientBuilder::new("test_files/client_merged_iter_test").mem_table_max(2).build()?;
+        instance.put(String::from("b"), String::from("old"))?;
+        instance.put(String::from("d"), String::from("d"))?;
+        instance.put(String::from("a"), String::from("a"))?;
+        instance.put(String::from("c"), String::from("c"))?;
+        // Still in the mem_table, shadowing the flushed value
+        instance.put(String::from("b"), String::from("new"))?;
+
+        let merged: Vec<KV> = instance.merged_iter()?.collect::<Result<_, _>>()?;
+        let keys: Vec<&str> = merged.iter().map(|kv| kv.key.as_str()).collect();
+        assert_eq!(vec!["a", "b", "c", "d"], keys);
+        assert_eq!("new", merged[1].value);
+
+        Ok(())
+    }
+
+    #[test]
+    fn creates_missing_directories() -> Result<(), TableErr> {
+        let _ = std::fs::remove_dir_all("test_files/create_test");
+        let db_name = "test_files/create_test/nested/db";
+
+        assert!(matches!(ClientBuilder::new(db_name).build(), Err(TableErr::IO(_))));
+        assert!(!Path::new("test_files/create_test").exists());
+
+        let mut instance = ClientBuilder::new(db_name).create_if_missing(true).mem_table_max(1).build()?;
+        assert!(Path::new("test_files/create_test/nested").is_dir());
+
+        instance.put(String::from("foo"), String::from("bar"))?;
+        assert_eq!(Some((String::from("bar"), false)), instance.get_cached("foo")?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn keeps_writes_when_a_flush_fails() -> Result<(), TableErr> {
+        let _ = std::fs::remove_dir_all("test_files/flush_error_test");
+        std::fs::create_dir_all("test_files/flush_error_test")?;
+        let mut instance = ClientBuilder::new("test_files/flush_error_test/db").mem_table_max(2).build()?;
+
+        // A directory where the first table's data is written makes the flush fail
+        let blocker = "test_files/flush_error_test/db-0-1.data.tmp";
+        std::fs::create_dir(blocker)?;
+        instance.put(String::from("a"), String::from("1"))?;
+        assert!(matches!(instance.put(String::from("b"), String::from("2")), Err(TableErr::IO(_))));
+        assert_eq!(Some("1"), instance.get("a")?.as_deref());
+        assert_eq!(Some("2"), instance.get("b")?.as_deref());
+
+        std::fs::remove_dir(blocker)?;
+        instance.flush()?;
+        assert_eq!(0, instance.mem_table().entries.len());
+        assert_eq!(Some("1"), instance.get("a")?.as_deref());
+        assert_eq!(Some("2"), instance.get("b")?.as_deref());
+
+        Ok(())
+    }
+
+    #[test]
+    fn deletes_shadow_older_values() -> Result<(), TableErr> {
+        let mut instance = ClientBuilder::new("test_files/delete_test").mem_table_max(2).build()?;
+        let watch = instance.watch("gone");
+
+        instance.put(String::from("gone"), String::from("soon"))?;
+        instance.put(String::from("kept"), String::from("here"))?;
+        instance.pin("kept")?;
+
+        // Still in the mem_table, with the old value on disk
+        instance.delete("gone")?;
+        assert_eq!(None, instance.get("gone")?);
+        assert_eq!(None, instance.get_cached("gone")?);
+
+        // Flushes the tombstone, which has to hide the value in the older table
+        instance.delete("kept")?;
+        assert_eq!(None, instance.get("gone")?);
+        assert_eq!(None, instance.get_cached("gone")?);
+        assert_eq!(None, instance.get_cached("kept")?);
+        assert_eq!(None, instance.get("kept")?);
+        assert!(instance.merged_iter()?.next().is_none());
+
+        let kinds: Vec<ChangeKind> = watch.try_iter().map(|event| event.kind).collect();
+        assert_eq!(vec![ChangeKind::Put, ChangeKind::Delete], kinds);
+
+        instance.put(String::from("gone"), String::from("back"))?;
+        assert_eq!(Some("back"), instance.get("gone")?.as_deref());
+
+        Ok(())
+    }
+
+    #[test]
+    fn write_once_rejects_deletes() -> Result<(), TableErr> {
+        let mut instance = ClientBuilder::new("test_files/write_once_delete_test").write_once(true).build()?;
+
+        assert!(matches!(instance.delete("missing"), Err(TableErr::WriteOnce(_))));
+        instance.put(String::from("kept"), String::from("forever"))?;
+        assert!(matches!(instance.delete("kept"), Err(TableErr::WriteOnce(_))));
+        assert_eq!(Some("forever"), instance.get("kept")?.as_deref());
+
+        Ok(())
+    }
+
+    #[test]
+    fn flushes_on_drop() -> Result<(), TableErr> {
+        let _ = std::fs::remove_dir_all("test_files/drop_test");
+        std::fs::create_dir_all("test_files/drop_test")?;
+        let db_name = "test_files/drop_test/db";
+
+        let mut instance = Client::new(db_name)?;
+        for key in ["a", "b", "c"] {
+            instance.put(key.to_string(), format!("{}-value", key))?;
+        }
+        drop(instance);
+
+        let tree = LsmTree::load(db_name)?;
+        for key in ["a", "b", "c"] {
+            assert_eq!(format!("{}-value", key), tree.read(key)?);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn reopens_existing_database() -> Result<(), TableErr> {
+        let _ = std::fs::remove_dir_all("test_files/reopen_test");
+        std::fs::create_dir_all("test_files/reopen_test")?;
+        let db_name = "test_files/reopen_test/db";
+
+        let mut instance = ClientBuilder::new(db_name).mem_table_max(2).build()?;
+        for i in 0..7 {
+            instance.put(format!("key{}", i), format!("value{}", i))?;
+        }
+        drop(instance);
+
+        let mut reopened = Client::open(db_name)?;
+        for i in 0..7 {
+            assert_eq!(Some(format!("value{}", i)), reopened.get(&format!("key{}", i))?);
+        }
+
+        // New writes mustn't clobber the tables that were already there
+        reopened.put(String::from("key0"), String::from("updated"))?;
+        drop(reopened);
+        let reopened = Client::open(db_name)?;
+        assert_eq!(Some("updated"), reopened.get("key0")?.as_deref());
+        assert_eq!(Some("value6"), reopened.get("key6")?.as_deref());
+
+        Ok(())
+    }
+
+    #[test]
+    fn recovers_unflushed_writes() -> Result<(), TableErr> {
+        let _ = std::fs::remove_dir_all("test_files/wal_test");
+        std::fs::create_dir_all("test_files/wal_test")?;
+        let db_name = "test_files/wal_test/db";
+
+        let mut instance = ClientBuilder::new(db_name).mem_table_max(4).build()?;
+        for i in 0..6 {
+            instance.put(format!("key{}", i), format!("value{}", i))?;
+        }
+        instance.delete("key1")?;
+        // Crashing means Drop never gets the chance to flush the last two writes
+        std::mem::forget(instance);
+
+        let reopened = Client::open(db_name)?;
+        assert_eq!(Some("value0"), reopened.get("key0")?.as_deref());
+        assert_eq!(Some("value5"), reopened.get("key5")?.as_deref());
+        assert_eq!(Some((String::from("value4"), true)), reopened.get_cached("key4")?);
+        assert_eq!(None, reopened.get("key1")?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn reports_torn_wal_records() -> Result<(), TableErr> {
+        let _ = std::fs::remove_dir_all("test_files/wal_torn_test");
+        std::fs::create_dir_all("test_files/wal_torn_test")?;
+        let db_name = "test_files/wal_torn_test/db";
+
+        let mut instance = ClientBuilder::new(db_name).mem_table_max(100).build()?;
+        instance.put(String::from("a"), String::from("1"))?;
+        std::mem::forget(instance);
+        // Part of a record header, as if the process died while appending it
+        let mut wal_file = std::fs::OpenOptions::new().append(true).open(wal_name(db_name))?;
+        std::io::Write::write_all(&mut wal_file, &[0, 0, 0, 9])?;
+        drop(wal_file);
+
+        let reopened = Client::open(db_name)?;
+        assert_eq!(4, reopened.wal_discarded_bytes());
+        assert_eq!(Some(String::from("1")), reopened.get("a")?);
+        std::mem::forget(reopened);
+
+        assert_eq!(0, Client::open(db_name)?.wal_discarded_bytes());
+
+        Ok(())
+    }
+
+    #[test]
+    fn recovers_synced_writes() -> Result<(), TableErr> {
+        let _ = std::fs::remove_dir_all("test_files/wal_sync_test");
+        std::fs::create_dir_all("test_files/wal_sync_test")?;
+        let db_name = "test_files/wal_sync_test/db";
+
+        let mut instance = ClientBuilder::new(db_name).mem_table_max(100).build()?;
+        for i in 0..10 {
+            instance.put(format!("key{}", i), format!("value{}", i))?;
+        }
+        instance.delete("key3")?;
+        instance.sync()?;
+        std::mem::forget(instance);
+
+        let reopened = Client::open(db_name)?;
+        for i in 0..10 {
+            let expected = (i != 3).then(|| format!("value{}", i));
+            assert_eq!(expected, reopened.get(&format!("key{}", i))?);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn opens_with_the_strategy_it_was_built_with() -> Result<(), TableErr> {
+        let _ = std::fs::remove_dir_all("test_files/client_strategy_test");
+        let db_name = "test_files/client_strategy_test/db";
+
+        let mut instance = ClientBuilder::new(db_name).create_if_missing(true).compaction_strategy(CompactionStrategy::SizeTiered).mem_table_max(1).build()?;
+        instance.put(String::from("a"), String::from("1"))?;
+        drop(instance);
+
+        assert!(ClientBuilder::new(db_name).compaction_strategy(CompactionStrategy::SizeTiered).open().is_ok());
+        assert!(ClientBuilder::new(db_name).open().is_ok());
+        assert!(matches!(ClientBuilder::new(db_name).compaction_strategy(CompactionStrategy::Leveled).open(), Err(TableErr::InvalidArgument(_))));
+
+        Ok(())
+    }
+
+    #[test]
+    fn flushes_durably() -> Result<(), TableErr> {
+        let _ = std::fs::remove_dir_all("test_files/durable_test");
+        let db_name = "test_files/durable_test/db";
+
+        let mut instance = ClientBuilder::new(db_name).create_if_missing(true).durable(true).mem_table_max(2).build()?;
+        for i in 0..5 {
+            instance.put(format!("key{}", i), format!("value{}", i))?;
+        }
+        drop(instance);
+
+        let reopened = Client::open(db_name)?;
+        for i in 0..5 {
+            assert_eq!(Some(format!("value{}", i)), reopened.get(&format!("key{}", i))?);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn checks_whether_keys_exist() -> Result<(), TableErr> {
+        let mut instance = ClientBuilder::new("test_files/client_contains_test").mem_table_max(2).build()?;
+        instance.put(String::from("on_disk"), String::from("1"))?;
+        instance.put(String::from("deleted_on_disk"), String::from("2"))?;
+        instance.put(String::from("in_memory"), String::from("3"))?;
+        instance.delete("deleted_on_disk")?;
+        instance.put(String::from("deleted_in_memory"), String::from("4"))?;
+        instance.delete("deleted_in_memory")?;
+
+        assert!(instance.contains_key("on_disk")?);
+        assert!(instance.contains_key("in_memory")?);
+        assert!(!instance.contains_key("deleted_on_disk")?);
+        assert!(!instance.contains_key("deleted_in_memory")?);
+        assert!(!instance.contains_key("missing")?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn writes_unsorted_batches() -> Result<(), TableErr> {
+        let _ = std::fs::remove_dir_all("test_files/put_batch_test");
+        std::fs::create_dir_all("test_files/put_batch_test")?;
+        let db_name = "test_files/put_batch_test/db";
+
+        let mut instance = ClientBuilder::new(db_name).mem_table_max(100).build()?;
+        // Backwards, with every key's first value replaced later in the batch
+        let batch = (0..1000).rev().map(|i| (format!("key{:04}", i), String::from("stale")))
+            .chain((0..1000).map(|i| (format!("key{:04}", i), i.to_string())));
+        instance.put_batch(batch)?;
+
+        // The batch is flushed as a whole, even though it's far bigger than the mem_table
+        assert_eq!(1, instance.lsm_tree.levels()[0].table_indices.len());
+        for i in 0..1000 {
+            assert_eq!(Some(i.to_string()), instance.get(&format!("key{:04}", i))?);
+        }
+
+        let keys: Vec<String> = instance.merged_iter()?.map(|entry| entry.map(|kv| kv.key)).collect::<Result<_, _>>()?;
+        assert_eq!((0..1000).map(|i| format!("key{:04}", i)).collect::<Vec<_>>(), keys);
+
+        let mut write_once = ClientBuilder::new("test_files/put_batch_test/write_once").write_once(true).build()?;
+        let duplicated = vec![(String::from("a"), String::from("1")), (String::from("a"), String::from("2"))];
+        assert!(matches!(write_once.put_batch(duplicated), Err(TableErr::KeyExists(_))));
+
+        Ok(())
+    }
+
+    #[test]
+    fn reads_ranges_with_the_latest_values() -> Result<(), TableErr> {
+        let _ = std::fs::remove_dir_all("test_files/client_range_test");
+        std::fs::create_dir_all("test_files/client_range_test")?;
+
+        let mut instance = ClientBuilder::new("test_files/client_range_test/db").mem_table_max(5).build()?;
+        for key in ["a", "b", "c", "d", "e"] {
+            instance.put(key.to_string(), String::from("old"))?;
+        }
+        assert!(instance.mem_table().entries.is_empty());
+
+        instance.put(String::from("b"), String::from("new"))?;
+        instance.delete("c")?;
+        instance.put(String::from("bb"), String::from("added"))?;
+
+        let range: Vec<(String, String)> = instance.range("b", "e")?.into_iter().map(|kv| (kv.key, kv.value)).collect();
+        let expected = vec![("b", "new"), ("bb", "added"), ("d", "old")];
+        assert_eq!(expected.into_iter().map(|(k, v)| (k.to_string(), v.to_string())).collect::<Vec<_>>(), range);
+
+        assert!(instance.range("e", "b")?.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn compacts_in_the_background() -> Result<(), TableErr> {
+        let _ = std::fs::remove_dir_all("test_files/client_background_test");
+        std::fs::create_dir_all("test_files/client_background_test")?;
+
+        let mut instance = ClientBuilder::new("test_files/client_background_test/db")
+            .mem_table_max(1)
+            .background_compaction(true)
+            .build()?;
+        for i in 0..30 {
+            instance.put(format!("k{:02}", i), i.to_string())?;
+        }
+
+        instance.lsm_tree.stop_background_compaction()?;
+        assert!(instance.lsm_tree.compaction_backlog() <= 10);
+        for i in 0..30 {
+            assert_eq!(Some(i.to_string()), instance.get(&format!("k{:02}", i))?);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn shuts_down_cleanly() -> Result<(), TableErr> {
+        let _ = std::fs::remove_dir_all("test_files/client_shutdown_test");
+        std::fs::create_dir_all("test_files/client_shutdown_test")?;
+        let db_name = "test_files/client_shutdown_test/db";
+
+        let mut instance = ClientBuilder::new(db_name)
+            .mem_table_max(3)
+            .background_compaction(true)
+            .build()?;
+        for i in 0..40 {
+            instance.put(format!("k{:02}", i), i.to_string())?;
+        }
+        assert!(!instance.mem_table().entries.is_empty());
+        instance.shutdown()?;
+
+        let reopened = Client::open(db_name)?;
+        assert!(reopened.mem_table().entries.is_empty());
+        assert!(reopened.lsm_tree.compaction_backlog() <= 10);
+        for i in 0..40 {
+            assert_eq!(Some(i.to_string()), reopened.get(&format!("k{:02}", i))?);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn shares_between_threads() -> Result<(), TableErr> {
+        let _ = std::fs::remove_dir_all("test_files/shared_client_test");
+        std::fs::create_dir_all("test_files/shared_client_test")?;
+
+        let shared = SharedClient::new(ClientBuilder::new("test_files/shared_client_test/db").mem_table_max(5).build()?);
+        for i in 0..20 {
+            shared.put(format!("old{:03}", i), i.to_string())?;
+        }
+
+        let writer = {
+            let shared = shared.clone();
+            std::thread::spawn(move || -> Result<(), TableErr> {
+                for i in 0..100 {
+                    shared.put(format!("new{:03}", i), i.to_string())?;
+                }
+                Ok(())
+            })
+        };
+        let readers: Vec<_> = (0..4).map(|_| {
+            let shared = shared.clone();
+            std::thread::spawn(move || -> Result<(), TableErr> {
+                for round in 0..50 {
+                    let i = round % 20;
+                    assert_eq!(Some(i.to_string()), shared.get(&format!("old{:03}", i))?);
+                }
+                Ok(())
+            })
+        }).collect();
+
+        writer.join().expect("Writer panicked")?;
+        for reader in readers {
+            reader.join().expect("Reader panicked")?;
+        }
+
+        for i in 0..100 {
+            assert_eq!(Some(i.to_string()), shared.get(&format!("new{:03}", i))?);
+        }
+        assert!(shared.read().contains_key("old000")?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn gets_many_keys_in_order() -> Result<(), TableErr> {
+        let _ = std::fs::remove_dir_all("test_files/client_get_many_test");
+        std::fs::create_dir_all("test_files/client_get_many_test")?;
+
+        let mut instance = ClientBuilder::new("test_files/client_get_many_test/db").mem_table_max(3).build()?;
+        for key in ["a", "b", "c"] {
+            instance.put(key.to_string(), format!("{}-disk", key))?;
+        }
+        assert!(instance.mem_table().entries.is_empty());
+        instance.put(String::from("b"), String::from("b-memory"))?;
+        instance.put(String::from("d"), String::from("d-memory"))?;
+
+        let values = instance.get_many(&["d", "missing", "a", "b", "c"])?;
+        let expected = [Some("d-memory"), None, Some("a-disk"), Some("b-memory"), Some("c-disk")];
+        assert_eq!(expected.iter().map(|value| value.map(String::from)).collect::<Vec<_>>(), values);
+
+        Ok(())
+    }
+
+    #[test]
+    fn lists_keys() -> Result<(), TableErr> {
+        let _ = std::fs::remove_dir_all("test_files/client_keys_test");
+        std::fs::create_dir_all("test_files/client_keys_test")?;
+
+        let mut instance = ClientBuilder::new("test_files/client_keys_test/db").mem_table_max(3).build()?;
+        for key in ["c", "a", "b"] {
+            instance.put(key.to_string(), String::from("disk"))?;
+        }
+        instance.put(String::from("b"), String::from("memory"))?;
+        instance.put(String::from("d"), String::from("memory"))?;
+        instance.delete("a")?;
+
+        let keys: Vec<String> = instance.keys()?.collect::<Result<_, _>>()?;
+        assert_eq!(vec!["b", "c", "d"], keys);
+
+        Ok(())
+    }
+
+    #[test]
+    fn retains_matching_entries() -> Result<(), TableErr> {
+        let _ = std::fs::remove_dir_all("test_files/client_retain_test");
+        std::fs::create_dir_all("test_files/client_retain_test")?;
+
+        let mut instance = ClientBuilder::new("test_files/client_retain_test/db").mem_table_max(7).build()?;
+        for i in 0..50 {
+            instance.put(format!("key{:02}", i), i.to_string())?;
+        }
+
+        let deleted = instance.retain(|key, _| key["key".len()..].parse::<u32>().is_ok_and(|n| n % 2 == 0))?;
+        assert_eq!(25, deleted);
+        for i in 0..50 {
+            let expected = (i % 2 == 0).then(|| i.to_string());
+            assert_eq!(expected, instance.get(&format!("key{:02}", i))?);
+        }
+        assert_eq!(25, instance.keys()?.count());
+
+        // Nothing left to delete
+        assert_eq!(0, instance.retain(|key, _| key["key".len()..].parse::<u32>().is_ok_and(|n| n % 2 == 0))?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn reports_value_sizes() -> Result<(), TableErr> {
+        let _ = std::fs::remove_dir_all("test_files/client_size_report_test");
+        std::fs::create_dir_all("test_files/client_size_report_test")?;
+
+        let mut instance = ClientBuilder::new("test_files/client_size_report_test/db").mem_table_max(3).build()?;
+        let mut expected = BTreeMap::new();
+        for round in 1..=4 {
+            for i in 0..20 {
+                // Each round overwrites a different share of the keys with longer values
+                if i % round == 0 {
+                    let key = format!("key{:02}", i);
+                    let value = "x".repeat(round * 10 + i);
+                    expected.insert(key.clone(), value.len());
+                    instance.put(key, value)?;
+                }
+            }
+        }
+        instance.delete("key07")?;
+        expected.remove("key07");
+        assert!(instance.lsm_tree.levels().len() > 1);
+        // Some of the newest values are still only in the mem_table
+        assert!(!instance.mem_table().entries.is_empty());
+
+        let report: Vec<(String, usize)> = instance.size_report()?.collect::<Result<_, _>>()?;
+        assert_eq!(expected.into_iter().collect::<Vec<_>>(), report);
+
+        Ok(())
+    }
+
+    #[test]
+    fn collects_garbage() -> Result<(), TableErr> {
+        let dir = "test_files/client_gc_test";
+        let _ = std::fs::remove_dir_all(dir);
+        std::fs::create_dir_all(dir)?;
+        let table_bytes = || -> Result<u64, TableErr> {
+            let mut total = 0;
+            for entry in std::fs::read_dir(dir)? {
+                let entry = entry?;
+                let name = entry.file_name().to_string_lossy().to_string();
+                if name.ends_with(".data") || name.ends_with(".index") {
+                    total += entry.metadata()?.len();
+                }
+            }
+            Ok(total)
+        };
+
+        let mut instance = ClientBuilder::new(&format!("{}/db", dir)).mem_table_max(20).build()?;
+        for i in 0..100 {
+            instance.put(format!("key{:03}", i), "x".repeat(100))?;
+        }
+        for i in 0..80 {
+            instance.delete(&format!("key{:03}", i))?;
+        }
+        instance.flush()?;
+        assert_eq!(80, instance.lsm_tree.tombstone_count()?);
+        let before = table_bytes()?;
+
+        instance.gc()?;
+        assert_eq!(0, instance.lsm_tree.tombstone_count()?);
+        assert!(table_bytes()? < before / 2, "{} bytes before, {} after", before, table_bytes()?);
+        assert_eq!(None, instance.get("key000")?);
+        assert_eq!(Some("x".repeat(100)), instance.get("key099")?);
+        assert_eq!(20, instance.keys()?.count());
+
+        Ok(())
+    }
+
+    #[test]
+    fn scans_glob_patterns() -> Result<(), TableErr> {
+        let _ = std::fs::remove_dir_all("test_files/client_glob_test");
+        std::fs::create_dir_all("test_files/client_glob_test")?;
+
+        let mut instance = ClientBuilder::new("test_files/client_glob_test/db").mem_table_max(4).build()?;
+        for key in ["user:1", "user:2", "user:10", "users", "admin:1", "a_suffix", "user:suffix", "suffix!"] {
+            instance.put(key.to_string(), format!("{}-value", key))?;
+        }
+        instance.delete("user:2")?;
+
+        let glob = |pattern: &str| -> Result<Vec<String>, TableErr> {
+            instance.scan_glob(pattern)?.map(|entry| entry.map(|kv| kv.key)).collect()
+        };
+        assert_eq!(vec!["user:1", "user:10", "user:suffix"], glob("user:*")?);
+        assert_eq!(vec!["user:1"], glob("user:?")?);
+        assert_eq!(vec!["a_suffix", "user:suffix"], glob("*suffix")?);
+        assert_eq!(vec!["admin:1", "user:1"], glob("*:?")?);
+        assert_eq!(vec!["users"], glob("users")?);
+        assert!(glob("user:2")?.is_empty());
+
+        let values: Vec<KV> = instance.scan_glob("admin:*")?.collect::<Result<_, _>>()?;
+        assert_eq!("admin:1-value", values[0].value);
+
+        assert_eq!(Some(String::from("user;")), prefix_end("user:"));
+        assert_eq!(None, prefix_end(""));
+
+        Ok(())
+    }
+
+    #[test]
+    fn flushes_on_demand() -> Result<(), TableErr> {
+        let _ = std::fs::remove_dir_all("test_files/client_flush_now_test");
+        std::fs::create_dir_all("test_files/client_flush_now_test")?;
+
+        let mut instance = Client::new("test_files/client_flush_now_test/db")?;
+        instance.flush()?;
+        assert!(instance.lsm_tree.levels().is_empty());
+
+        for key in ["a", "b", "c"] {
+            instance.put(key.to_string(), key.to_string())?;
+        }
+        instance.flush()?;
+
+        assert!(instance.mem_table().entries.is_empty());
+        assert!(Path::new("test_files/client_flush_now_test/db-0-1.index").exists());
+        assert!(Path::new("test_files/client_flush_now_test/db-0-1.data").exists());
+        assert_eq!(Some("b"), instance.get("b")?.as_deref());
+
+        Ok(())
+    }
+
+    #[test]
+    fn mem_table_values_shadow_flushed_ones() -> Result<(), TableErr> {
+        let _ = std::fs::remove_dir_all("test_files/client_shadow_test");
+        std::fs::create_dir_all("test_files/client_shadow_test")?;
+
+        let mut instance = Client::new("test_files/client_shadow_test/db")?;
+        instance.put(String::from("key"), String::from("old"))?;
+        instance.put(String::from("other"), String::from("other"))?;
+        instance.flush()?;
+        assert_eq!(Some("old"), instance.get("key")?.as_deref());
+
+        instance.put(String::from("key"), String::from("new"))?;
+        assert_eq!(Some("new"), instance.get("key")?.as_deref());
+        assert_eq!(Some((String::from("new"), true)), instance.get_cached("key")?);
+
+        // Once the new value's flushed too, it's the newer table that wins
+        instance.flush()?;
+        assert_eq!(Some("new"), instance.get("key")?.as_deref());
+        assert_eq!(Some("other"), instance.get("other")?.as_deref());
 
         Ok(())
     }
@@ -1 +1,5 @@
-pub(crate) mod table;
+pub mod table;
+pub mod checksum;
+pub mod wal;
+pub mod bloom;
+pub mod compression;
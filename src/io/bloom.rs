@@ -0,0 +1,94 @@
+use crate::io::table::TableErr;
+
+/// Aim for about one false positive in a hundred lookups for keys that aren't there
+pub const DEFAULT_FALSE_POSITIVE_RATE: f64 = 0.01;
+const HASH_COUNT_BYTES: usize = 4;
+
+/// A Bloom filter over a table's keys. `may_contain` is never wrong about a key that was
+/// inserted, so a `false` means the table definitely doesn't have the key and its index
+/// doesn't need to be scanned at all.
+#[derive(Debug, PartialEq)]
+pub struct BloomFilter {
+    bits: Vec<u8>,
+    hash_count: u32,
+}
+
+impl BloomFilter {
+    /// Sizes the filter so that once `expected_keys` have been inserted, a key that wasn't
+    /// has roughly a `false_positive_rate` chance of being reported as present
+    pub fn with_capacity(expected_keys: usize, false_positive_rate: f64) -> BloomFilter {
+        let keys = expected_keys.max(1) as f64;
+        let bit_count = (-keys * false_positive_rate.ln() / (2f64.ln() * 2f64.ln())).ceil().max(64.0);
+        let hash_count = (bit_count / keys * 2f64.ln()).round().max(1.0);
+
+        BloomFilter {
+            bits: vec![0; (bit_count as usize).div_ceil(8)],
+            hash_count: hash_count as u32,
+        }
+    }
+
+    pub fn insert(&mut self, key: &str) {
+        for bit in self.bit_indices(key) {
+            self.bits[bit / 8] |= 1 << (bit % 8);
+        }
+    }
+
+    pub fn may_contain(&self, key: &str) -> bool {
+        self.bit_indices(key).all(|bit| self.bits[bit / 8] & (1 << (bit % 8)) != 0)
+    }
+
+    /// The filter as it's stored on disk: the hash count as a u32 BE, then the bits
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = self.hash_count.to_be_bytes().to_vec();
+        bytes.extend(&self.bits);
+
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<BloomFilter, TableErr> {
+        if bytes.len() <= HASH_COUNT_BYTES {
+            return Err(TableErr::BadFile(String::from("The Bloom filter is truncated")));
+        }
+        let (hash_count, bits) = bytes.split_at(HASH_COUNT_BYTES);
+
+        Ok(BloomFilter {
+            bits: bits.to_vec(),
+            hash_count: u32::from_be_bytes(hash_count.try_into().expect("Slice should be 4 bytes")),
+        })
+    }
+
+    /// Derives every hash from two halves of one 64 bit hash, which is as good as having
+    /// `hash_count` independent ones for a Bloom filter
+    fn bit_indices(&self, key: &str) -> impl Iterator<Item = usize> {
+        let hash = fnv1a(key.as_bytes());
+        let (first, second) = (hash & 0xFFFF_FFFF, hash >> 32);
+        let bit_count = (self.bits.len() * 8) as u64;
+
+        (0..u64::from(self.hash_count)).map(move |i| (first.wrapping_add(i.wrapping_mul(second)) % bit_count) as usize)
+    }
+}
+
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xCBF2_9CE4_8422_2325, |hash, byte| (hash ^ u64::from(*byte)).wrapping_mul(0x0000_0100_0000_01B3))
+}
+
+#[cfg(test)]
+mod test {
+    use crate::io::bloom::*;
+
+    #[test]
+    fn finds_every_inserted_key() -> Result<(), TableErr> {
+        let mut filter = BloomFilter::with_capacity(1000, DEFAULT_FALSE_POSITIVE_RATE);
+        for i in 0..1000 {
+            filter.insert(&format!("key{}", i));
+        }
+
+        let filter = BloomFilter::from_bytes(&filter.to_bytes())?;
+        assert!((0..1000).all(|i| filter.may_contain(&format!("key{}", i))));
+
+        let false_positives = (0..10_000).filter(|i| filter.may_contain(&format!("other{}", i))).count();
+        assert!(false_positives < 300, "{} false positives", false_positives);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,86 @@
+// Built at compile time so there's no setup cost on first use
+const CRC32_TABLE: [u32; 256] = crc_table(0xEDB8_8320);
+const CRC32C_TABLE: [u32; 256] = crc_table(0x82F6_3B78);
+
+/// Which algorithm a table's value checksums were computed with
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum ChecksumKind {
+    Crc32,
+    #[default]
+    Crc32c,
+}
+
+impl ChecksumKind {
+    pub fn checksum(&self, bytes: &[u8]) -> u32 {
+        match self {
+            ChecksumKind::Crc32 => crc32(bytes),
+            ChecksumKind::Crc32c => crc32c(bytes),
+        }
+    }
+
+    /// The name that's recorded alongside each checksum
+    pub fn name(&self) -> &'static str {
+        match self {
+            ChecksumKind::Crc32 => "crc32",
+            ChecksumKind::Crc32c => "crc32c",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<ChecksumKind> {
+        [ChecksumKind::Crc32, ChecksumKind::Crc32c].into_iter().find(|kind| kind.name() == name)
+    }
+}
+
+const fn crc_table(polynomial: u32) -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ polynomial
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+
+    table
+}
+
+fn crc(table: &[u32; 256], bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in bytes {
+        crc = (crc >> 8) ^ table[((crc ^ u32::from(*byte)) & 0xFF) as usize];
+    }
+
+    !crc
+}
+
+/// CRC-32 (IEEE), as used by zip and friends
+pub fn crc32(bytes: &[u8]) -> u32 {
+    crc(&CRC32_TABLE, bytes)
+}
+
+/// CRC-32C (Castagnoli), which catches more errors than CRC-32 and has hardware support on
+/// most CPUs
+pub fn crc32c(bytes: &[u8]) -> u32 {
+    crc(&CRC32C_TABLE, bytes)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::io::checksum::*;
+
+    #[test]
+    fn matches_reference_values() {
+        assert_eq!(0, crc32(b""));
+        assert_eq!(0xCBF4_3926, crc32(b"123456789"));
+        assert_eq!(0, crc32c(b""));
+        assert_eq!(0xE306_9283, crc32c(b"123456789"));
+    }
+}
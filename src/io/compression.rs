@@ -0,0 +1,182 @@
+use crate::io::table::TableErr;
+
+const LENGTH_BYTES: usize = 4;
+const MIN_MATCH: usize = 4;
+const MAX_MATCH: usize = MIN_MATCH + 0x7F;
+const MAX_LITERAL_RUN: usize = 0x80;
+const MAX_OFFSET: usize = u16::MAX as usize;
+const HASH_BITS: u32 = 12;
+
+/// How the values in a table's data file are compressed. Values are compressed one at a time,
+/// so a point read only ever decompresses the value it's after.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum Compression {
+    #[default]
+    None,
+    /// A small LZ77 variant, see [compress]
+    Lz,
+}
+
+impl Compression {
+    pub fn compress(&self, bytes: Vec<u8>) -> Vec<u8> {
+        match self {
+            Compression::None => bytes,
+            Compression::Lz => compress(&bytes),
+        }
+    }
+
+    pub fn decompress(&self, bytes: Vec<u8>) -> Result<Vec<u8>, TableErr> {
+        match self {
+            Compression::None => Ok(bytes),
+            Compression::Lz => decompress(&bytes),
+        }
+    }
+
+    /// The name that's recorded in front of each compressed value's position
+    pub fn name(&self) -> &'static str {
+        match self {
+            Compression::None => "none",
+            Compression::Lz => "lz",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Compression> {
+        [Compression::None, Compression::Lz].into_iter().find(|compression| compression.name() == name)
+    }
+}
+
+/// Compresses the bytes with a greedy LZ77. The output is the uncompressed length as a u32 BE,
+/// then a series of tokens. A token byte below 0x80 is followed by that many plus one literal
+/// bytes. Anything else is a match: the low seven bits plus four give its length, and the
+/// next two bytes (BE) how far back the match starts.
+pub fn compress(bytes: &[u8]) -> Vec<u8> {
+    let mut out = u32::try_from(bytes.len()).expect("Couldn't parse usize into u32").to_be_bytes().to_vec();
+    let mut recent = vec![usize::MAX; 1 << HASH_BITS];
+
+    let mut literal_start = 0;
+    let mut position = 0;
+    while position + MIN_MATCH <= bytes.len() {
+        let hash = hash(&bytes[position..position + MIN_MATCH]);
+        let candidate = recent[hash];
+        recent[hash] = position;
+
+        let is_match = candidate != usize::MAX
+            && position - candidate <= MAX_OFFSET
+            && bytes[candidate..candidate + MIN_MATCH] == bytes[position..position + MIN_MATCH];
+        if !is_match {
+            position += 1;
+            continue;
+        }
+
+        let mut len = MIN_MATCH;
+        while len < MAX_MATCH && position + len < bytes.len() && bytes[candidate + len] == bytes[position + len] {
+            len += 1;
+        }
+
+        push_literals(&mut out, &bytes[literal_start..position]);
+        out.push(0x80 | u8::try_from(len - MIN_MATCH).expect("Match length should fit in seven bits"));
+        out.extend(u16::try_from(position - candidate).expect("Offset should fit in a u16").to_be_bytes());
+
+        position += len;
+        literal_start = position;
+    }
+    push_literals(&mut out, &bytes[literal_start..]);
+
+    out
+}
+
+pub fn decompress(bytes: &[u8]) -> Result<Vec<u8>, TableErr> {
+    let corrupt = || TableErr::BadFile(String::from("Compressed value is corrupt"));
+
+    let len_bytes = bytes.get(..LENGTH_BYTES).ok_or_else(corrupt)?;
+    let len = usize::try_from(u32::from_be_bytes(len_bytes.try_into().expect("Slice should be 4 bytes"))).expect("Couldn't parse u32 into usize");
+    // The length is only a claim until the tokens back it up, so don't allocate for more than
+    // they could produce. Literals never expand, and a three byte match produces at most
+    // MAX_MATCH bytes.
+    if len > (bytes.len() - LENGTH_BYTES).div_ceil(3) * MAX_MATCH {
+        return Err(corrupt());
+    }
+
+    let mut out = Vec::with_capacity(len);
+    let mut position = LENGTH_BYTES;
+    while position < bytes.len() {
+        let token = usize::from(bytes[position]);
+        position += 1;
+
+        if token < MAX_LITERAL_RUN {
+            let literals = bytes.get(position..position + token + 1).ok_or_else(corrupt)?;
+            out.extend(literals);
+            position += token + 1;
+        } else {
+            let offset_bytes = bytes.get(position..position + 2).ok_or_else(corrupt)?;
+            let offset = usize::from(u16::from_be_bytes(offset_bytes.try_into().expect("Slice should be 2 bytes")));
+            position += 2;
+
+            if offset == 0 || offset > out.len() {
+                return Err(corrupt());
+            }
+            // Matches can overlap the bytes they produce, so copy one at a time
+            let start = out.len() - offset;
+            for i in 0..(token & 0x7F) + MIN_MATCH {
+                out.push(out[start + i]);
+            }
+        }
+
+        if out.len() > len {
+            return Err(corrupt());
+        }
+    }
+
+    if out.len() != len {
+        return Err(corrupt());
+    }
+
+    Ok(out)
+}
+
+fn push_literals(out: &mut Vec<u8>, literals: &[u8]) {
+    for run in literals.chunks(MAX_LITERAL_RUN) {
+        out.push(u8::try_from(run.len() - 1).expect("Literal runs should fit in seven bits"));
+        out.extend(run);
+    }
+}
+
+fn hash(bytes: &[u8]) -> usize {
+    let word = u32::from_le_bytes(bytes.try_into().expect("Hashed slices should be 4 bytes"));
+    (word.wrapping_mul(2_654_435_761) >> (32 - HASH_BITS)) as usize
+}
+
+#[cfg(test)]
+mod test {
+    use crate::io::compression::*;
+
+    #[test]
+    fn round_trips() -> Result<(), TableErr> {
+        let repetitive = "the quick brown fox jumps over the lazy dog. ".repeat(100).into_bytes();
+        let binary: Vec<u8> = (0..5000u32).map(|i| (i * 7 % 251) as u8).collect();
+        for bytes in [Vec::new(), b"abc".to_vec(), vec![0; 1000], repetitive.clone(), binary] {
+            assert_eq!(bytes, decompress(&compress(&bytes))?);
+        }
+
+        assert!(compress(&repetitive).len() < repetitive.len() / 10);
+
+        let mut corrupt = compress(&repetitive);
+        corrupt.truncate(corrupt.len() - 3);
+        assert!(matches!(decompress(&corrupt), Err(TableErr::BadFile(_))));
+
+        Ok(())
+    }
+
+    #[test]
+    fn rejects_impossible_lengths() -> Result<(), TableErr> {
+        let mut huge = u32::MAX.to_be_bytes().to_vec();
+        huge.extend([0, b'a']);
+        assert!(matches!(decompress(&huge), Err(TableErr::BadFile(_))));
+
+        // Long runs expand about as much as anything can, and still fit under the bound
+        let run = vec![0; 100_000];
+        assert_eq!(run, decompress(&compress(&run))?);
+
+        Ok(())
+    }
+}
@@ -1,264 +1,1371 @@
+use crate::io::bloom::{ BloomFilter, DEFAULT_FALSE_POSITIVE_RATE };
+use crate::io::checksum::{ crc32, ChecksumKind };
+use crate::io::compression::Compression;
 use crate::lsm::kv::KV;
-use crate::lsm::merge_iter::{ MergeIter, MergeDecision, kv_merge };
+use crate::lsm::merge_iter::{ EntryIter, merge_newest_first, merge_newest_first_live };
+use std::borrow::Cow;
+use std::collections::{ HashMap, HashSet };
 use std::fs::File;
-use std::io::{self, BufRead};
+use std::fmt;
+use std::io::{self, BufRead, Read, Seek, SeekFrom, Write};
 use std::num::ParseIntError;
+use std::thread;
+use std::time::{ Duration, Instant };
 
 #[derive(Debug)]
 pub enum TableErr {
     IO(String),
     KeyNotFound(String),
     BadFile(String),
+    ReadOnly(String),
+    WriteStalled(String),
+    InvalidKey(String),
+    /// The key already has a value and the database doesn't allow overwriting it
+    KeyExists(String),
+    /// The key's newest record is a tombstone
+    KeyDeleted(String),
+    /// An option or argument was out of range, e.g. when configuring a tree
+    InvalidArgument(String),
+    /// The database is write-once, so values can't be deleted once they're written
+    WriteOnce(String),
 }
 
 const INDEX_FILE_SUFFIX: &str = ".index";
 const DATA_FILE_SUFFIX: &str = ".data";
+const BLOOM_FILE_SUFFIX: &str = ".bloom";
+const SPARSE_INDEX_FILE_SUFFIX: &str = ".sparse";
+/// Every this many index lines, the sparse index records where the line starts
+const SPARSE_INDEX_INTERVAL: usize = 16;
+const TEMP_FILE_SUFFIX: &str = ".tmp";
+const INLINE_MARKER: &str = "inline,";
+const TOMBSTONE_MARKER: &str = "tombstone";
+const TRANSFER_MAGIC: &[u8; 8] = b"SLOTHTBL";
+const FRAME_LEN_BYTES: usize = 4;
+/// Stands in for the value length in a tombstone's frame, which has no value bytes
+const TOMBSTONE_FRAME_LEN: u32 = u32::MAX;
+/// How much of a rate-limited data file is written between pauses
+const THROTTLE_CHUNK_BYTES: usize = 4096;
+
+pub type EncodeFn = fn(&[u8]) -> Vec<u8>;
+pub type DecodeFn = fn(&[u8]) -> Result<Vec<u8>, TableErr>;
+
+/// Options controlling how a table is written and read. The defaults match the plain format that
+/// [flush] writes, which checksums every value and verifies it when it's read.
+#[derive(Debug, Clone)]
+pub struct TableOptions {
+    /// Values shorter than this many bytes are stored directly in their index line
+    /// (`key:inline,<value>`) instead of in the data file, saving a seek for tiny values.
+    /// Zero disables inlining. Values are never inlined when `encode` is set, since the index
+    /// itself isn't encoded.
+    pub inline_threshold: usize,
+    /// Applied to each value before it's written to the data file (e.g. to encrypt it).
+    /// Offsets in the index address the encoded bytes. Inlined values are stored as-is.
+    pub encode: Option<EncodeFn>,
+    /// Reverses `encode` after a value has been read back from the data file
+    pub decode: Option<DecodeFn>,
+    /// Ends the index file with a newline. Readers accept either form.
+    pub trailing_newline: bool,
+    /// Stores a checksum of each data-file value (as written, i.e. after `encode`) in its
+    /// index line: `key:start,length,checksum,kind`. The kind is left off for CRC32, which
+    /// is all that older tables used. On by default.
+    pub checksums: bool,
+    /// The algorithm used when `checksums` is on. Readers go by the kind recorded with each
+    /// checksum, so this doesn't need to match when reading.
+    pub checksum_kind: ChecksumKind,
+    /// Verifies stored checksums whenever a value is read, so that corruption is reported
+    /// rather than read back as a wrong value. On by default; entries without a checksum are
+    /// never verified.
+    pub verify_on_read: bool,
+    /// Prefixes each value in the data file with its key and the value's length, so that a
+    /// lost index can be recovered with [rebuild_index]. Index offsets still point straight at
+    /// the value. Framed tables never inline values, since those would only exist in the index.
+    pub framed: bool,
+    /// Caps how fast the data file is written, in bytes per second, by pausing between
+    /// chunks. Meant for compaction, so that it doesn't starve foreground I/O. `None` (or
+    /// zero) writes as fast as possible.
+    pub write_rate_limit: Option<u64>,
+    /// Syncs both files to disk before they're renamed into place, and then the directory
+    /// they're in, so that a flushed table survives a power failure. Off by default, since
+    /// syncing makes every flush wait on the disk.
+    pub durable: bool,
+    /// Compresses each value in the data file before `encode` is applied. The codec is recorded
+    /// in front of each compressed value's position (`key:lz,start,length,...`), so readers
+    /// don't need the same setting. Inlined values aren't compressed.
+    pub compression: Compression,
+}
+
+impl Default for TableOptions {
+    fn default() -> Self {
+        TableOptions {
+            inline_threshold: 0,
+            encode: None,
+            decode: None,
+            trailing_newline: false,
+            checksums: true,
+            checksum_kind: ChecksumKind::default(),
+            verify_on_read: true,
+            framed: false,
+            write_rate_limit: None,
+            durable: false,
+            compression: Compression::default(),
+        }
+    }
+}
+
+/// Summarises the table written out by a merge or rewrite
+#[derive(Debug, PartialEq)]
+pub struct MergeStats {
+    pub entries_written: usize,
+    pub data_bytes_written: u64,
+}
+
+/// Summarises a table that's just been flushed
+#[derive(Debug, PartialEq)]
+pub struct TableStats {
+    /// Every entry written, tombstones included
+    pub entry_count: usize,
+    pub data_bytes: u64,
+    pub index_bytes: u64,
+    /// The smallest and largest keys, or `None` for an empty table
+    pub min_key: Option<String>,
+    pub max_key: Option<String>,
+}
+
+/// Merges any number of tables into a new one at `dest`. Where a key appears in more than one
+/// source, the value from the earliest source wins, so sources should be listed newest first.
+/// A single source is just copied, which drops any gaps in its data file.
+pub fn merge_and_flush(sources: &[&str], dest: &str) -> Result<MergeStats, TableErr> {
+    merge_and_flush_with_options(sources, dest, &TableOptions::default())
+}
+
+/// Merges `files` into `dest` in a single pass, with earlier files taking precedence. The same
+/// as [merge_and_flush], under the name compaction of more than two tables goes by.
+pub fn merge_and_flush_many(files: &[&str], dest: &str) -> Result<MergeStats, TableErr> {
+    merge_and_flush(files, dest)
+}
+
+/// Like [merge_and_flush] for exactly two tables, with `left` taking precedence
+pub fn merge_two_and_flush(left_file_name: &str, right_file_name: &str, new_file_name: &str) -> Result<MergeStats, TableErr> {
+    merge_and_flush(&[left_file_name, right_file_name], new_file_name)
+}
+
+/// Like [merge_and_flush], but writes the merged table according to the given [TableOptions]
+pub fn merge_and_flush_with_options(sources: &[&str], dest: &str, options: &TableOptions) -> Result<MergeStats, TableErr> {
+    merge_sources(sources, dest, options, false)
+}
+
+/// Like [merge_and_flush_with_options], but tombstones are dropped along with the values they
+/// shadow. Only safe when there's nothing older than the sources that they'd need to hide.
+pub fn merge_and_flush_dropping_tombstones(sources: &[&str], dest: &str, options: &TableOptions) -> Result<MergeStats, TableErr> {
+    merge_sources(sources, dest, options, true)
+}
+
+fn merge_sources(sources: &[&str], dest: &str, options: &TableOptions, drop_tombstones: bool) -> Result<MergeStats, TableErr> {
+    let mut iterators: Vec<EntryIter> = Vec::new();
+    for source in sources {
+        iterators.push(Box::new(iterate_entries(source)?));
+    }
+
+    let merged = if drop_tombstones {
+        merge_newest_first_live(iterators)
+    } else {
+        merge_newest_first(iterators)
+    };
+    // Streamed straight into the new table, which bails out on the first bad entry from any
+    // source before anything is written
+    let stats = flush_entries(dest, merged.map(|entry| entry.map(kv_entry)), options)?;
+
+    Ok(MergeStats {
+        entries_written: stats.entry_count,
+        data_bytes_written: stats.data_bytes,
+    })
+}
+
+/// Rewrites a table into a fresh, gap-free copy at `dest` containing only its live entries.
+/// Entries that have been superseded within the table (later duplicates of a key, which
+/// lookups never reach) and any unreferenced bytes in the data file are dropped. The copy is
+/// written with the same `options` the table is read with, so its format doesn't change.
+///
+/// The original is left alone. A table is several files, so it can't be swapped for the copy
+/// in place without a crash part way through mixing the two; switching readers over is up to
+/// the caller (see [crate::lsm::tree::LsmTree::rewrite_table]).
+pub fn rewrite(file_name: &str, dest: &str, options: &TableOptions) -> Result<MergeStats, TableErr> {
+    let mut live: Vec<KV> = Vec::new();
+    for entry in iterate_entries_with_options(file_name, options.clone())? {
+        let kv = entry?;
+        // Lookups stop at the first matching line, so that's the live one
+        if live.last().is_some_and(|last| last.key == kv.key) {
+            continue;
+        }
+        live.push(kv);
+    }
+
+    let stats = flush_with_options(dest, live, options)?;
+
+    Ok(MergeStats {
+        entries_written: stats.entry_count,
+        data_bytes_written: stats.data_bytes,
+    })
+}
+
+/// Appends `second` to the end of `first`, writing the combined table to `dest`. Every key in
+/// `second` has to sort after every key in `first`, which is what allows the data files to
+/// simply be placed back to back.
+///
+/// Index lines are parsed and re-serialised rather than copied byte for byte, so it doesn't
+/// matter whether either source index ends with a newline; the boundary lines never run
+/// together.
+pub fn concat(first: &str, second: &str, dest: &str) -> Result<(), TableErr> {
+    let first_lines = read_index_lines(first)?;
+    let second_lines = read_index_lines(second)?;
+
+    if let (Some(last), Some(next)) = (first_lines.last(), second_lines.first()) {
+        if index_key(next) <= index_key(last) {
+            return Err(TableErr::BadFile(format!("Can't append {} to {}: their key ranges overlap", second, first)));
+        }
+    }
+
+    let mut out_data = std::fs::read(data_fn(first))?;
+    let offset = u64::try_from(out_data.len()).expect("Couldn't parse usize into u64");
+    out_data.extend(std::fs::read(data_fn(second))?);
+
+    let mut out_index = first_lines;
+    for line in second_lines {
+        match ValueLocation::from_key(&line)? {
+            ValueLocation::Inline(_) | ValueLocation::Tombstone => out_index.push(line),
+            ValueLocation::Data(position) => out_index.push(format!("{}:{}", escape_key(&index_key(&line)), DataPosition(position.0 + offset, position.1, position.2, position.3))),
+        }
+    }
+
+    std::fs::write(data_fn(dest), out_data)?;
+    remove_sidecars(dest)?;
+    std::fs::write(index_fn(dest), index_contents(&out_index, false))?;
+
+    Ok(())
+}
+
+fn read_index_lines(file_name: &str) -> Result<Vec<String>, TableErr> {
+    let index_reader = io::BufReader::new(File::open(index_fn(file_name))?);
+
+    Ok(index_reader.lines().collect::<Result<Vec<_>, _>>()?)
+}
+
+/// The (unescaped) key an index line is for
+fn index_key(line: &str) -> Cow<'_, str> {
+    unescape_key(line.split(":").next().unwrap_or(line))
+}
+
+/// Characters that would break up an index line if they appeared in a key, along with what
+/// they're written as. `%` is in there so that escaped keys can be told apart from keys that
+/// just happen to contain something that looks like an escape.
+const KEY_ESCAPES: [(char, &str); 5] = [('%', "%25"), (':', "%3A"), (',', "%2C"), ('\n', "%0A"), ('\r', "%0D")];
 
-pub fn merge_and_flush(left_file_name: &str, right_file_name: &str, new_file_name: &str) -> Result<(), TableErr> {
-    let left_iter = iterate_entries(left_file_name)?;
-    let right_iter = iterate_entries(right_file_name)?;
+/// Percent-encodes the characters in a key that would otherwise be read as part of the index
+/// format. Most keys don't have any, so they're returned as they are.
+pub(crate) fn escape_key(key: &str) -> Cow<'_, str> {
+    if !key.contains(|c| KEY_ESCAPES.iter().any(|(escaped, _)| *escaped == c)) {
+        return Cow::Borrowed(key);
+    }
+
+    let mut escaped = String::with_capacity(key.len());
+    for c in key.chars() {
+        match KEY_ESCAPES.iter().find(|(escaped_char, _)| *escaped_char == c) {
+            Some((_, escape)) => escaped.push_str(escape),
+            None => escaped.push(c),
+        }
+    }
+
+    Cow::Owned(escaped)
+}
+
+/// Reverses [escape_key]. Anything that isn't a recognised escape is left alone, so keys written
+/// before keys were escaped still read back the same (as long as they don't contain an escape).
+pub(crate) fn unescape_key(key: &str) -> Cow<'_, str> {
+    if !key.contains('%') {
+        return Cow::Borrowed(key);
+    }
 
-    let merge_iter = MergeIter::new(left_iter, right_iter, |left_result, right_result| {
-        match (left_result, right_result) {
-            (Err(_), _) => MergeDecision::Left(false),
-            (_, Err(_)) => MergeDecision::Right(false),
-            (Ok(left), Ok(right)) => kv_merge(left, right),
+    let mut unescaped = String::with_capacity(key.len());
+    let mut rest = key;
+    while let Some(percent) = rest.find('%') {
+        unescaped.push_str(&rest[..percent]);
+        rest = &rest[percent..];
+
+        match KEY_ESCAPES.iter().find(|(_, escape)| rest.starts_with(escape)) {
+            Some((c, escape)) => {
+                unescaped.push(*c);
+                rest = &rest[escape.len()..];
+            },
+            None => {
+                unescaped.push('%');
+                rest = &rest[1..];
+            },
         }
-    }).map(|result| { result.expect("") });
+    }
+    unescaped.push_str(rest);
+
+    Cow::Owned(unescaped)
+}
+
+fn index_contents(lines: &[String], trailing_newline: bool) -> String {
+    let mut contents = lines.join("\n");
+    if trailing_newline && !lines.is_empty() {
+        contents.push('\n');
+    }
+
+    contents
+}
+
+/// Streams a whole table to the given writer, e.g. a socket to a follower. The frame is the
+/// magic bytes, then the index and data files each prefixed with their length as a big-endian
+/// u64, and finally a big-endian CRC32 over both files so that [receive] can tell when a
+/// transfer was cut short or mangled.
+pub fn send(file_name: &str, mut writer: impl Write) -> Result<(), TableErr> {
+    let index = std::fs::read(index_fn(file_name))?;
+    let data = std::fs::read(data_fn(file_name))?;
+
+    writer.write_all(TRANSFER_MAGIC)?;
+    for part in [&index, &data] {
+        writer.write_all(&u64::try_from(part.len()).expect("Couldn't parse usize into u64").to_be_bytes())?;
+        writer.write_all(part)?;
+    }
+    writer.write_all(&crc32(&[index, data].concat()).to_be_bytes())?;
+    writer.flush()?;
+
+    Ok(())
+}
+
+/// Reads a table written by [send] and stores it as `dest_name`. Nothing is written to disk
+/// unless the whole frame arrived intact.
+pub fn receive(mut reader: impl Read, dest_name: &str) -> Result<(), TableErr> {
+    let mut magic = [0; 8];
+    read_frame(&mut reader, &mut magic)?;
+    if &magic != TRANSFER_MAGIC {
+        return Err(TableErr::BadFile(String::from("Transfer doesn't start with a table frame")));
+    }
+
+    let index = read_frame_part(&mut reader)?;
+    let data = read_frame_part(&mut reader)?;
+
+    let mut checksum = [0; 4];
+    read_frame(&mut reader, &mut checksum)?;
+    if u32::from_be_bytes(checksum) != crc32(&[index.as_slice(), data.as_slice()].concat()) {
+        return Err(TableErr::BadFile(String::from("Checksum mismatch in table transfer")));
+    }
 
-    let _ = flush(new_file_name, merge_iter);
+    std::fs::write(data_fn(dest_name), data)?;
+    remove_sidecars(dest_name)?;
+    std::fs::write(index_fn(dest_name), index)?;
 
     Ok(())
 }
 
+fn read_frame_part(reader: &mut impl Read) -> Result<Vec<u8>, TableErr> {
+    let mut len = [0; 8];
+    read_frame(reader, &mut len)?;
+
+    let Ok(len) = usize::try_from(u64::from_be_bytes(len)) else {
+        return Err(TableErr::BadFile(String::from("Table transfer part is too large")));
+    };
+
+    let mut part = Vec::new();
+    reader.take(len as u64).read_to_end(&mut part)?;
+    if part.len() != len {
+        return Err(TableErr::BadFile(String::from("Table transfer was truncated")));
+    }
+
+    Ok(part)
+}
+
+fn read_frame(reader: &mut impl Read, buffer: &mut [u8]) -> Result<(), TableErr> {
+    reader.read_exact(buffer).map_err(|e| match e.kind() {
+        io::ErrorKind::UnexpectedEof => TableErr::BadFile(String::from("Table transfer was truncated")),
+        _ => e.into(),
+    })
+}
+
 pub fn clean(file_name: &str) -> Result<(), TableErr> {
     std::fs::remove_file(index_fn(file_name))?;
     std::fs::remove_file(data_fn(file_name))?;
+    remove_sidecars(file_name)?;
+
+    Ok(())
+}
+
+/// Removes the Bloom filter and sparse index that a flush writes alongside the table. Tables
+/// written before those existed (or by anything other than a flush) don't have them, so there
+/// may be nothing to remove. A table whose index is replaced some other way has to lose them,
+/// or they could send lookups for keys the new index does have in the wrong direction.
+fn remove_sidecars(file_name: &str) -> Result<(), TableErr> {
+    for sidecar in [bloom_fn(file_name), sparse_index_fn(file_name)] {
+        match std::fs::remove_file(sidecar) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+            _ => {},
+        }
+    }
 
     Ok(())
 }
 
+/// Whether the table's Bloom filter allows for it containing the key. Tables without a
+/// filter might contain anything.
+fn bloom_may_contain(file_name: &str, key: &str) -> Result<bool, TableErr> {
+    Ok(read_bloom(file_name)?.is_none_or(|bloom| bloom.may_contain(key)))
+}
+
+/// The table's Bloom filter, or `None` for tables written before they had one
+fn read_bloom(file_name: &str) -> Result<Option<BloomFilter>, TableErr> {
+    match std::fs::read(bloom_fn(file_name)) {
+        Ok(bytes) => Ok(Some(BloomFilter::from_bytes(&bytes)?)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
 
 /// Writes the data from the given iterator to disk.
 /// 
 /// Index files consist of newline-delimited pairs of key:position, where position encodes both 
-/// the position and length of each key's corresponding value.
+/// the position and length of each key's corresponding value. Deleted keys are written as
+/// `key:tombstone` and have nothing in the data file.
 ///
 /// The data files are just every value concatenated and written to disk as a string.
+///
+/// Alongside those, a `.bloom` file holds a [BloomFilter] of every key (tombstones included),
+/// so that lookups for keys the table doesn't have can usually skip the index entirely. If the
+/// keys are sorted, a `.sparse` file also records where every 16th index line starts
+/// (`key:offset`), so that lookups only have to scan a small window of the index.
 /// 
-pub fn flush<'a>(file_name: &str, in_data: impl IntoIterator<Item = KV>) -> Result<(), TableErr> {
+pub fn flush(file_name: &str, in_data: impl IntoIterator<Item = KV>) -> Result<TableStats, TableErr> {
+    flush_with_options(file_name, in_data, &TableOptions::default())
+}
+
+/// Like [flush], but sorts the entries by key first. [flush] keeps whatever order it's given,
+/// and unsorted tables can't use a sparse index or be merged. Where a key appears more than
+/// once, the last of its entries is kept, as with [flush].
+pub fn flush_unsorted(file_name: &str, in_data: impl IntoIterator<Item = KV>) -> Result<TableStats, TableErr> {
+    let mut sorted: Vec<KV> = in_data.into_iter().collect();
+    // Stable, so duplicates stay in the order they were given
+    sorted.sort_by(|left, right| left.key.cmp(&right.key));
+
+    flush(file_name, sorted)
+}
+
+/// Like [flush], but writes the table according to the given [TableOptions]
+pub fn flush_with_options(file_name: &str, in_data: impl IntoIterator<Item = KV>, options: &TableOptions) -> Result<TableStats, TableErr> {
+    flush_entries(file_name, in_data.into_iter().map(|kv| Ok(kv_entry(kv))), options)
+}
+
+/// The key and value of a [KV] as [flush_entries] takes them
+fn kv_entry(kv: KV) -> (String, Option<Vec<u8>>) {
+    let value = (!kv.tombstone).then(|| kv.value.into_bytes());
+    (kv.key, value)
+}
+
+/// Like [flush], for values that are arbitrary bytes rather than UTF-8 strings (e.g. images
+/// or serialised messages). Read them back with [read_bytes].
+pub fn flush_bytes(file_name: &str, in_data: impl IntoIterator<Item = (String, Vec<u8>)>) -> Result<TableStats, TableErr> {
+    flush_entries(file_name, in_data.into_iter().map(|(key, value)| Ok((key, Some(value)))), &TableOptions::default())
+}
+
+/// Writes the table out. Values are `None` for tombstones. The first error from `in_data` is
+/// returned before any file is written.
+fn flush_entries(file_name: &str, in_data: impl IntoIterator<Item = Result<(String, Option<Vec<u8>>), TableErr>>, options: &TableOptions) -> Result<TableStats, TableErr> {
     let index_file_name = index_fn(file_name);
     let data_file_name = data_fn(file_name);
         
-    let mut out_data: Vec<String> = Vec::new();
+    let mut out_data: Vec<u8> = Vec::new();
     let mut out_index: Vec<String> = Vec::new();
 
+    let mut keys: Vec<String> = Vec::new();
     let mut position = 0;
-    for datum in in_data {
-        out_index.push(format!("{}:{},{}", datum.key, position, datum.value.len()));
+    let mut in_data = in_data.into_iter().peekable();
+    while let Some(entry) = in_data.next() {
+        let (key, value) = entry?;
+        // Lookups stop at the first line for a key, so a run of duplicates would read as its
+        // oldest value. The last one's the one that was meant, so it's the only one kept.
+        if in_data.peek().is_some_and(|next| matches!(next, Ok((next_key, _)) if *next_key == key)) {
+            continue;
+        }
+        keys.push(key.to_string());
+        let Some(value) = value else {
+            if options.framed {
+                out_data.extend(frame_len(key.len()));
+                out_data.extend(key.as_bytes());
+                out_data.extend(TOMBSTONE_FRAME_LEN.to_be_bytes());
+                position += 2 * FRAME_LEN_BYTES + key.len();
+            }
+            out_index.push(format!("{}:{}", escape_key(&key), TOMBSTONE_MARKER));
+            continue;
+        };
+
+        // Inlined values live on the index line, so they can't span lines (or not be text)
+        if !options.framed && options.encode.is_none() && value.len() < options.inline_threshold {
+            if let Some(inline_value) = std::str::from_utf8(&value).ok().filter(|value| !value.contains('\n')) {
+                out_index.push(format!("{}:{}{}", escape_key(&key), INLINE_MARKER, inline_value));
+                continue;
+            }
+        }
+
+        let value = options.compression.compress(value);
+        let value = match options.encode {
+            Some(encode) => encode(&value),
+            None => value,
+        };
 
-        position = position + datum.value.len();
-        out_data.push(datum.value.clone());
+        if options.framed {
+            out_data.extend(frame_len(key.len()));
+            out_data.extend(key.as_bytes());
+            out_data.extend(frame_len(value.len()));
+            position += 2 * FRAME_LEN_BYTES + key.len();
+        }
+
+        let checksum = if options.checksums { Some((options.checksum_kind, options.checksum_kind.checksum(&value))) } else { None };
+        let data_position = DataPosition(u64::try_from(position).expect("Couldn't parse usize into u64"), u64::try_from(value.len()).expect("Couldn't parse usize into u64"), checksum, options.compression);
+        out_index.push(format!("{}:{}", escape_key(&key), data_position));
+
+        position += value.len();
+        out_data.extend(value);
     }
 
-    if let Err(data_write_error) = std::fs::write(data_file_name, out_data.join("")) {
+    // Both files are written under temporary names and only renamed into place once they're
+    // complete, data first, so the index never points at data that isn't (fully) there yet
+    let temp_data_file_name = temp_fn(&data_file_name);
+    let temp_index_file_name = temp_fn(&index_file_name);
+    let bloom_file_name = bloom_fn(file_name);
+    let temp_bloom_file_name = temp_fn(&bloom_file_name);
+    if let Err(data_write_error) = write_data(&temp_data_file_name, &out_data, options.write_rate_limit, options.durable) {
         return Err(TableErr::IO(format!("Failed to write data file: {:?}", data_write_error)));
     }
-    if let Err(index_write_error) = std::fs::write(index_file_name, out_index.join("\n")) {
+
+    let mut bloom = BloomFilter::with_capacity(keys.len(), DEFAULT_FALSE_POSITIVE_RATE);
+    for key in &keys {
+        bloom.insert(key);
+    }
+    if let Err(bloom_write_error) = write_data(&temp_bloom_file_name, &bloom.to_bytes(), None, options.durable) {
+        return Err(TableErr::IO(format!("Failed to write Bloom filter: {:?}", bloom_write_error)));
+    }
+    let index_contents = index_contents(&out_index, options.trailing_newline);
+    if let Err(index_write_error) = write_data(&temp_index_file_name, index_contents.as_bytes(), None, options.durable) {
         return Err(TableErr::IO(format!("Failed to write index file: {:?}", index_write_error)));
     }
 
-    Ok(())
+    // Unsorted tables can't be searched, so they don't get a sparse index
+    let sparse_index_file_name = sparse_index_fn(file_name);
+    let temp_sparse_index_file_name = temp_fn(&sparse_index_file_name);
+    let sorted = keys.windows(2).all(|pair| pair[0] <= pair[1]);
+    if sorted {
+        if let Err(sparse_write_error) = write_data(&temp_sparse_index_file_name, sparse_index(&out_index).as_bytes(), None, options.durable) {
+            return Err(TableErr::IO(format!("Failed to write sparse index: {:?}", sparse_write_error)));
+        }
+    }
+
+    // The sparse index holds offsets into the index, so it can only go in once the index has.
+    // Until then, any old one is removed so that it's never paired with the new index.
+    remove_sidecars(file_name)?;
+    std::fs::rename(temp_data_file_name, data_file_name)?;
+    std::fs::rename(temp_bloom_file_name, bloom_file_name)?;
+    std::fs::rename(&temp_index_file_name, &index_file_name)?;
+    if sorted {
+        std::fs::rename(temp_sparse_index_file_name, &sparse_index_file_name)?;
+    }
+    if options.durable {
+        sync_dir(&index_file_name)?;
+    }
+
+    Ok(TableStats {
+        entry_count: keys.len(),
+        data_bytes: u64::try_from(out_data.len()).expect("Couldn't parse usize into u64"),
+        index_bytes: u64::try_from(index_contents.len()).expect("Couldn't parse usize into u64"),
+        min_key: keys.iter().min().cloned(),
+        max_key: keys.iter().max().cloned(),
+    })
 }
 
-pub fn file_contains(file_name: &str, key: &str) -> Result<bool, TableErr> {
-    println!("Checking whether {} contains {}", file_name, key);
-    match data_file_position(file_name, key) {
-        Ok(_) => return Ok(true),
-        Err(TableErr::KeyNotFound(_)) => return Ok(false),
-        Err(e) => Err(e),
+/// Records the byte offset of every [SPARSE_INDEX_INTERVAL]th line of the index
+fn sparse_index(index_lines: &[String]) -> String {
+    let mut sparse_lines = Vec::new();
+    let mut offset = 0;
+    for (i, line) in index_lines.iter().enumerate() {
+        if i % SPARSE_INDEX_INTERVAL == 0 {
+            // The key's still escaped, exactly as it is on the index line
+            sparse_lines.push(format!("{}:{}", line.split(":").next().unwrap_or(line), offset));
+        }
+        offset += line.len() + 1;
     }
+
+    sparse_lines.join("\n")
 }
 
-/// Reads the value for the given key.
-/// TODO: This currently reads the whole file into memory. That's obviously
-/// not what we want to be doing. We have the position of the value in the 
-/// file, so skip straight there and read it.
-pub fn read(file_name: &str, key: &str) -> Result<String, TableErr> {
-    println!("Checking {:?} for {:?}", file_name, key);
-    let position = data_file_position(file_name, key)?;
-    
-    read_at_position(file_name, position)
+/// A table's sparse index, read into memory so that it can be kept with the table instead of
+/// being read from disk for every lookup. Pass it to [read_indexed] or [contains_live_indexed].
+#[derive(Debug)]
+pub struct SparseIndex {
+    /// Every sampled key, with the offset of its line in the index file
+    samples: Vec<(String, u64)>,
 }
 
-fn read_at_position(file_name: &str, position: DataPosition) -> Result<String, TableErr> {
-    let data_file_name = data_fn(file_name);
-    let data = std::fs::read_to_string(data_file_name);
+impl SparseIndex {
+    /// Reads the table's sparse index, or returns `None` for tables that don't have one
+    /// (because they're unsorted, or were written before there were sparse indices)
+    pub fn load(file_name: &str) -> Result<Option<SparseIndex>, TableErr> {
+        let sparse = match std::fs::read_to_string(sparse_index_fn(file_name)) {
+            Ok(sparse) => sparse,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
 
-    let start: usize = position.0.try_into().expect("Couldn't parse u32 into usize");
-    let end: usize = (position.0 + position.1).try_into().expect("Couldn't parse u32 sum into usize");
+        let mut samples = Vec::new();
+        for line in sparse.lines() {
+            let Some((_, offset)) = line.rsplit_once(":") else {
+                return Err(TableErr::BadFile(format!("The sparse index line '{}' is malformed", line)));
+            };
+            samples.push((index_key(line).into_owned(), offset.parse::<u64>()?));
+        }
 
-    Ok(data?[start..end].to_string())
+        Ok(Some(SparseIndex { samples }))
+    }
 }
 
-pub fn iterate_entries<'a>(file_name: &'a str) -> Result<impl Iterator<Item = Result<KV, TableErr>> + 'a, TableErr> {
-    let index_file_name = index_fn(file_name);
-    
-    let index_reader = io::BufReader::new(File::open(index_file_name)?);
-    
-    Ok(index_reader.lines().map(|key_or_err| {
-        // This is really inefficient for the moment. The idea is that read_at_position will get 
-        // a faster implementation one day.
-        let key_and_position = key_or_err?;
-        let value = read_at_position(file_name, DataPosition::from_key(&key_and_position)?)?;
+/// Where in the index a scan for the key should start. Returns the offset of the last sampled
+/// line whose key sorts before the key (so that the first of any duplicates is found), and
+/// whether the index is known to be sorted, in which case the scan can stop as soon as it's
+/// gone past the key. Tables without a sparse index are scanned from the start.
+fn index_scan_start(sparse: Option<&SparseIndex>, key: &str) -> (u64, bool) {
+    let Some(sparse) = sparse else {
+        return (0, false);
+    };
 
-        // At this point, if the key's malformed, we would've returned an Err already.
-        let key = String::from(key_and_position.split(":").collect::<Vec<&str>>()[0]);
+    let after = sparse.samples.partition_point(|(sampled_key, _)| sampled_key.as_str() < key);
+    let start = after.checked_sub(1).map_or(0, |sample| sparse.samples[sample].1);
 
-        Ok(
-            KV { 
-                key,
-                value,
-            }
-        )   
-    }))
+    (start, true)
 }
 
-/// The position of data in the data file. First value is the start position, second is its
-/// length
-#[derive(Debug)]
-struct DataPosition(u32, u32);
+/// Syncs the directory a file is in, which is what makes creating or renaming the file durable
+pub(crate) fn sync_dir(file_name: &str) -> io::Result<()> {
+    // Directories can only be opened (and so synced) like this on Unix
+    if !cfg!(unix) {
+        return Ok(());
+    }
 
-impl DataPosition {
-    fn from_strings(position: &str, length: &str) -> Result<DataPosition, TableErr> {
-        let Ok(position_val) = position.parse::<u32>() else {
-            return Err(TableErr::BadFile(format!("The position '{}' is invalid", position)));
-        };
+    let dir = match std::path::Path::new(file_name).parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => std::path::Path::new("."),
+    };
+    File::open(dir)?.sync_all()
+}
 
-        let Ok(length_val) = length.parse::<u32>() else {
-            return Err(TableErr::BadFile(format!("The length '{}' is invalid", length)));
-        };
+fn write_data(data_file_name: &str, data: &[u8], rate_limit: Option<u64>, sync: bool) -> io::Result<()> {
+    let mut data_file = File::create(data_file_name)?;
 
-        Ok(DataPosition(position_val, length_val)) 
+    match rate_limit.filter(|limit| *limit > 0) {
+        Some(bytes_per_sec) => write_throttled(&mut data_file, data, bytes_per_sec)?,
+        None => data_file.write_all(data)?,
     }
 
-    fn from_key(key: &str) -> Result<DataPosition, TableErr> {
-        let parts: Vec<&str> = key.split(":").collect();
-        if parts.len() < 2 {
-            return Err(TableErr::BadFile(format!("The key '{}' did not contain a corresponding position", key)));
-        }
+    if sync {
+        data_file.sync_all()?;
+    }
 
-        let entries: Vec<&str> = parts[1].split(",").collect();
+    Ok(())
+}
 
-        if entries.len() < 2 {
-            return Err(TableErr::BadFile(format!("The position/lenth string '{}' is malformed", parts[1])));
+fn write_throttled(data_file: &mut File, data: &[u8], bytes_per_sec: u64) -> io::Result<()> {
+    let start = Instant::now();
+    let mut written = 0;
+    for chunk in data.chunks(THROTTLE_CHUNK_BYTES) {
+        data_file.write_all(chunk)?;
+        written += chunk.len();
+
+        // Wait until the bytes written so far are back within the limit
+        let due = Duration::from_secs_f64(written as f64 / bytes_per_sec as f64);
+        if let Some(wait) = due.checked_sub(start.elapsed()) {
+            thread::sleep(wait);
         }
-
-        Self::from_strings(entries[0], entries[1])
     }
+
+    Ok(())
 }
 
-fn data_file_position(file_name: &str, key: &str) -> Result<DataPosition, TableErr> {
-    let index_file_name = index_fn(file_name);
-    
-    println!("Index file name is {}", index_file_name);
+fn frame_len(len: usize) -> [u8; FRAME_LEN_BYTES] {
+    u32::try_from(len).expect("Couldn't parse usize into u32").to_be_bytes()
+}
 
-    let index_file_reader = io::BufReader::new(File::open(index_file_name)?);
+/// Recreates a table's index by scanning its data file, for when the index has been lost or
+/// corrupted. Only works for tables flushed with [TableOptions::framed], since otherwise the
+/// data file doesn't record where values start or which keys they belong to. Checksums and
+/// codecs aren't stored in the frames, so the rebuilt index doesn't have any, and compressed
+/// values would read back still compressed.
+pub fn rebuild_index(file_name: &str) -> Result<(), TableErr> {
+    let data = std::fs::read(data_fn(file_name))?;
 
-    for line in index_file_reader.lines() {
-        let l = line?;
+    let mut out_index: Vec<String> = Vec::new();
+    let mut position = 0;
+    while position < data.len() {
+        let key = read_framed(&data, &mut position)?;
+        let Ok(key) = String::from_utf8(key.to_vec()) else {
+            return Err(TableErr::BadFile(format!("The key framed before byte {} of {} is not valid UTF-8", position, file_name)));
+        };
 
-        if l.starts_with(key) {
-            return Ok(DataPosition::from_key(&l)?);
+        if data.get(position..position + FRAME_LEN_BYTES) == Some(&TOMBSTONE_FRAME_LEN.to_be_bytes()) {
+            out_index.push(format!("{}:{}", escape_key(&key), TOMBSTONE_MARKER));
+            position += FRAME_LEN_BYTES;
+            continue;
         }
+
+        let value_start = position + FRAME_LEN_BYTES;
+        let value_len = read_framed(&data, &mut position)?.len();
+        let data_position = DataPosition(u64::try_from(value_start).expect("Couldn't parse usize into u64"), u64::try_from(value_len).expect("Couldn't parse usize into u64"), None, Compression::None);
+        out_index.push(format!("{}:{}", escape_key(&key), data_position));
     }
 
-    Err(TableErr::KeyNotFound(key.to_string()))
+    remove_sidecars(file_name)?;
+    std::fs::write(index_fn(file_name), index_contents(&out_index, false))?;
+
+    Ok(())
 }
 
-impl From<std::io::Error> for TableErr {
-    fn from(error: std::io::Error) -> Self {
-        return TableErr::IO(format!("Failed to open file: {:?}", error));
-    }
+/// Reads one length-prefixed part of a frame starting at `position`, leaving `position` just
+/// past it
+fn read_framed<'a>(data: &'a [u8], position: &mut usize) -> Result<&'a [u8], TableErr> {
+    let truncated = || TableErr::BadFile(format!("The frame at byte {} is truncated", position));
+
+    let len_bytes = data.get(*position..*position + FRAME_LEN_BYTES).ok_or_else(truncated)?;
+    let len = usize::try_from(u32::from_be_bytes(len_bytes.try_into().expect("Slice should be 4 bytes"))).expect("Couldn't parse u32 into usize");
+    let part = data.get(*position + FRAME_LEN_BYTES..*position + FRAME_LEN_BYTES + len).ok_or_else(truncated)?;
+    *position += FRAME_LEN_BYTES + len;
+
+    Ok(part)
 }
 
-impl From<ParseIntError> for TableErr {
-    fn from(err: ParseIntError) -> Self {
-        return TableErr::BadFile(format!("Failed to parse Int: {:?}", err));
+/// Checks that a table's index and data file agree with each other: every index line has to
+/// parse, and the values it references have to end exactly at the end of the data file. A
+/// short data file means a write was cut off; a long one means it has trailing garbage.
+pub fn check_consistency(file_name: &str) -> Result<(), TableErr> {
+    let index_reader = io::BufReader::new(File::open(index_fn(file_name))?);
+
+    let mut expected_data_len = 0;
+    for line in index_reader.lines() {
+        if let ValueLocation::Data(position) = ValueLocation::from_key(&line?)? {
+            expected_data_len = u64::max(expected_data_len, position.0 + position.1);
+        }
     }
-}
 
-fn index_fn(name: &str) -> String {
-    format!("{}{}", name, INDEX_FILE_SUFFIX)
-}
+    let data_len = std::fs::metadata(data_fn(file_name))?.len();
+    if data_len < expected_data_len {
+        return Err(TableErr::BadFile(format!("Data file for {} is truncated: the index references {} bytes but only {} exist", file_name, expected_data_len, data_len)));
+    }
+    if data_len > expected_data_len {
+        return Err(TableErr::BadFile(format!("Data file for {} has {} trailing bytes not referenced by the index", file_name, data_len - expected_data_len)));
+    }
 
-fn data_fn(name: &str) -> String {
-    format!("{}{}", name, DATA_FILE_SUFFIX)
+    Ok(())
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use std::sync::Once;
+/// Estimates the number of keys in a table from roughly one in every `sample_rate` index
+/// lines, seeking between them rather than reading the whole index. A `sample_rate` of 1 (or
+/// 0) reads every line and gives the exact count.
+///
+/// The estimate is the index length divided by the mean length of the sampled lines, so it's
+/// exact when every line is the same length. Otherwise its relative error is that of the
+/// sampled mean: with `k` samples of lines whose lengths have a coefficient of variation `c`,
+/// that's within about `2c / sqrt(k)` most of the time. Index lines are dominated by the key
+/// and the data offset, so tables with similarly sized keys come out well under that.
+pub fn approx_key_count(file_name: &str, sample_rate: u64) -> Result<u64, TableErr> {
+    let index_len = std::fs::metadata(index_fn(file_name))?.len();
+    if index_len == 0 {
+        return Ok(0);
+    }
 
-    static INIT: Once = Once::new();
-    const TEST_FILE_NAME: &str = "test_files/disk_test";
+    let mut index_reader = io::BufReader::new(File::open(index_fn(file_name))?);
+    if sample_rate <= 1 {
+        return Ok(u64::try_from(index_reader.lines().count()).expect("Couldn't parse usize into u64"));
+    }
 
-    fn test_data() -> [KV; 5] {
-        [
-            KV { key: String::from("bar"), value: String::from("barble") },
-            KV { key: String::from("baz"), value: String::from("bazzle") },
-            KV { key: String::from("daz"), value: String::from("dazzle") },
-            KV { key: String::from("foo"), value: String::from("fooble") },
-            KV { key: String::from("raz"), value: String::from("razzle") },
-        ]
+    // The first line is our only guess at the line length, so it sets how far apart the
+    // probes are
+    let mut line = String::new();
+    let mut sampled_bytes = u64::try_from(index_reader.read_line(&mut line)?).expect("Couldn't parse usize into u64");
+    let mut sampled_lines = 1;
+    let stride = sampled_bytes * sample_rate;
+
+    let mut offset = stride;
+    while offset < index_len {
+        // Probes land mid-line, so skip to the start of the next one. Starting a byte early
+        // means a probe that lands right on the start of a line still samples that line.
+        index_reader.seek(SeekFrom::Start(offset - 1))?;
+        line.clear();
+        index_reader.read_line(&mut line)?;
+
+        line.clear();
+        let line_len = index_reader.read_line(&mut line)?;
+        if line_len == 0 {
+            break;
+        }
+
+        sampled_bytes += u64::try_from(line_len).expect("Couldn't parse usize into u64");
+        sampled_lines += 1;
+        offset += stride;
     }
 
+    Ok((index_len * sampled_lines + sampled_bytes / 2) / sampled_bytes)
+}
 
-    /// Initializes the test data and returns it in case a test wants to compare to it
-    fn test_init() {
-        INIT.call_once(|| {
-            let data = test_data();
+/// The table's size on disk in bytes, counting its index and data files
+pub fn size(file_name: &str) -> Result<u64, TableErr> {
+    Ok(std::fs::metadata(index_fn(file_name))?.len() + std::fs::metadata(data_fn(file_name))?.len())
+}
 
-            flush(TEST_FILE_NAME, data.into_iter()).expect("Failed to initialize test data");
-        });
+pub fn file_contains(file_name: &str, key: &str) -> Result<bool, TableErr> {
+    if !bloom_may_contain(file_name, key)? {
+        return Ok(false);
     }
 
+    match value_location(file_name, key) {
+        Ok(_) => Ok(true),
+        Err(TableErr::KeyNotFound(_)) => Ok(false),
+        Err(e) => Err(e),
+    }
+}
 
-    #[test]
-    fn flushes() -> Result<(), TableErr> {
-        // Not the most exciting test, but if this one fails, then it makes it clear that
-        // it's the flushing itself that's failing
-        test_init();
+/// Whether the table has a live value for the key, without reading the value. `Some(false)`
+/// means the table has a tombstone for the key, and `None` that it has no record of the key at
+/// all, so older tables still need checking.
+pub fn contains_live(file_name: &str, key: &str) -> Result<Option<bool>, TableErr> {
+    contains_live_indexed(file_name, key, SparseIndex::load(file_name)?.as_ref())
+}
 
-        let data_file_contents = std::fs::read_to_string(format!("{}{}", TEST_FILE_NAME, ".data"))?;
-        let index_file_contents = std::fs::read_to_string(format!("{}{}", TEST_FILE_NAME, ".index"))?;
+/// Like [contains_live], with the table's sparse index already loaded
+pub fn contains_live_indexed(file_name: &str, key: &str, sparse: Option<&SparseIndex>) -> Result<Option<bool>, TableErr> {
+    if !bloom_may_contain(file_name, key)? {
+        return Ok(None);
+    }
 
-        assert_eq!("barblebazzledazzlefooblerazzle", data_file_contents);
-        assert_eq!("bar:0,6\nbaz:6,6\ndaz:12,6\nfoo:18,6\nraz:24,6", index_file_contents);
+    match value_location_indexed(file_name, key, sparse) {
+        Ok(ValueLocation::Tombstone) => Ok(Some(false)),
+        Ok(_) => Ok(Some(true)),
+        Err(TableErr::KeyNotFound(_)) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
 
-        Ok(())
+/// Looks up several keys in one pass over the index, with the data file opened at most once.
+/// Keys the table has are returned with their value, or `None` if the table has a tombstone
+/// for them. Keys the table has no record of are left out.
+pub fn read_many(file_name: &str, keys: &[&str]) -> Result<HashMap<String, Option<String>>, TableErr> {
+    let mut found = HashMap::new();
+    let bloom = read_bloom(file_name)?;
+    let mut wanted: HashSet<&str> = keys.iter().copied()
+        .filter(|key| !key.is_empty() && bloom.as_ref().is_none_or(|bloom| bloom.may_contain(key)))
+        .collect();
+    if wanted.is_empty() {
+        return Ok(found);
     }
 
-    #[test]
-    fn contains_works() -> Result<(), TableErr> {
-        std::fs::write("test_files/test_contains.index", "and:0,1\nthe:1,1\nmome:2,8\nraths:10,7\noutgrabe:17,10")?;
+    let options = TableOptions::default();
+    let mut data_file: Option<DataFile> = None;
+    for line in io::BufReader::new(File::open(index_fn(file_name))?).lines() {
+        let line = line?;
+        let key = index_key(&line);
+        // Only the first line for a key counts, like in value_location
+        if !wanted.remove(key.as_ref()) {
+            continue;
+        }
 
-        assert!(file_contains("test_files/test_contains", "and")?);
-        assert!(file_contains("test_files/test_contains", "raths")?);
-        assert!(file_contains("test_files/test_contains", "outgrabe")?);
+        let value = match ValueLocation::from_key(&line)? {
+            ValueLocation::Inline(value) => Some(value),
+            ValueLocation::Data(position) => {
+                let data_file = match &mut data_file {
+                    Some(data_file) => data_file,
+                    None => data_file.insert(DataFile::open(file_name)?),
+                };
+                Some(data_file.read(position, &options)?)
+            },
+            ValueLocation::Tombstone => None,
+        };
+        found.insert(key.into_owned(), value);
 
-        assert!(!file_contains("test_files/test_contains", "foo")?);
-        Ok(())
+        if wanted.is_empty() {
+            break;
+        }
     }
 
-    #[test]
-    fn reads() -> Result<(), TableErr> {
-        test_init();
+    Ok(found)
+}
 
-        assert_eq!("razzle", read(TEST_FILE_NAME, "raz")?);
+/// Reads the value for the given key. Only the value's bytes are read from the data file, so
+/// this doesn't get slower as the table grows (beyond the index scan).
+pub fn read(file_name: &str, key: &str) -> Result<String, TableErr> {
+    read_with_options(file_name, key, &TableOptions::default())
+}
 
-        Ok(())
+/// Like [read], for tables written with [flush_with_options]
+pub fn read_with_options(file_name: &str, key: &str, options: &TableOptions) -> Result<String, TableErr> {
+    match value_location(file_name, key)? {
+        ValueLocation::Inline(value) => Ok(value),
+        ValueLocation::Data(position) => read_at_position(file_name, position, options),
+        ValueLocation::Tombstone => Err(TableErr::KeyDeleted(key.to_string())),
+    }
+}
+
+/// Like [read], with the table's sparse index already loaded. Keys that the table's Bloom
+/// filter rules out are reported missing without the index being read at all.
+pub fn read_indexed(file_name: &str, key: &str, sparse: Option<&SparseIndex>) -> Result<String, TableErr> {
+    if !bloom_may_contain(file_name, key)? {
+        return Err(TableErr::KeyNotFound(key.to_string()));
+    }
+
+    match value_location_indexed(file_name, key, sparse)? {
+        ValueLocation::Inline(value) => Ok(value),
+        ValueLocation::Data(position) => read_at_position(file_name, position, &TableOptions::default()),
+        ValueLocation::Tombstone => Err(TableErr::KeyDeleted(key.to_string())),
+    }
+}
+
+/// Like [read], but returns the value's bytes as they are, without requiring them to be UTF-8.
+/// For tables written with [flush_bytes].
+pub fn read_bytes(file_name: &str, key: &str) -> Result<Vec<u8>, TableErr> {
+    match value_location(file_name, key)? {
+        ValueLocation::Inline(value) => Ok(value.into_bytes()),
+        ValueLocation::Data(position) => DataFile::open(file_name)?.read_bytes(position, &TableOptions::default()),
+        ValueLocation::Tombstone => Err(TableErr::KeyDeleted(key.to_string())),
+    }
+}
+
+/// Reads up to `len` bytes of the value for the given key, starting `offset` bytes into the
+/// value. The read is clamped to the end of the value, so large values can be fetched in
+/// chunks without reading the whole thing. Returns `None` if the table doesn't contain the key.
+pub fn read_slice(file_name: &str, key: &str, offset: usize, len: usize) -> Result<Option<Vec<u8>>, TableErr> {
+    read_slice_with_options(file_name, key, offset, len, &TableOptions::default())
+}
+
+/// Like [read_slice], for tables written with [flush_with_options]. Encoded or compressed
+/// values don't line up byte for byte with what's on disk, so those are read and decoded in
+/// full before they're sliced.
+pub fn read_slice_with_options(file_name: &str, key: &str, offset: usize, len: usize, options: &TableOptions) -> Result<Option<Vec<u8>>, TableErr> {
+    let position = match value_location(file_name, key) {
+        Ok(ValueLocation::Data(position)) => position,
+        Ok(ValueLocation::Inline(value)) => {
+            let start = offset.min(value.len());
+            let end = start + len.min(value.len() - start);
+            return Ok(Some(value.as_bytes()[start..end].to_vec()));
+        },
+        Ok(ValueLocation::Tombstone) | Err(TableErr::KeyNotFound(_)) => return Ok(None),
+        Err(e) => return Err(e),
+    };
+
+    if options.decode.is_some() || position.3 != Compression::None {
+        let value = DataFile::open(file_name)?.read_bytes(position, options)?;
+        let start = offset.min(value.len());
+        let end = start + len.min(value.len() - start);
+        return Ok(Some(value[start..end].to_vec()));
+    }
+
+    let value_len = position_to_usize(position.1)?;
+    let start = offset.min(value_len);
+    let slice_len = len.min(value_len - start);
+
+    let mut data_file = File::open(data_fn(file_name))?;
+    data_file.seek(SeekFrom::Start(position.0 + u64::try_from(start).expect("Couldn't parse usize into u64")))?;
+
+    let mut slice = vec![0; slice_len];
+    data_file.read_exact(&mut slice)?;
+
+    Ok(Some(slice))
+}
+
+fn read_at_position(file_name: &str, position: DataPosition, options: &TableOptions) -> Result<String, TableErr> {
+    let mut data_file = DataFile::open(file_name)?;
+    data_file.read(position, options)
+}
+
+/// An open data file, for reading any number of values without reopening it each time
+struct DataFile {
+    file_name: String,
+    file: File,
+    len: u64,
+}
+
+impl DataFile {
+    fn open(file_name: &str) -> Result<DataFile, TableErr> {
+        let file = File::open(data_fn(file_name))?;
+        let len = file.metadata()?.len();
+
+        Ok(DataFile { file_name: file_name.to_string(), file, len })
+    }
+
+    fn read(&mut self, position: DataPosition, options: &TableOptions) -> Result<String, TableErr> {
+        let value = self.read_bytes(position, options)?;
+
+        String::from_utf8(value).map_err(|e| TableErr::BadFile(format!("Value is not valid UTF-8: {:?}", e)))
+    }
+
+    /// Reads the value's bytes, checked and decoded according to `options`
+    fn read_bytes(&mut self, position: DataPosition, options: &TableOptions) -> Result<Vec<u8>, TableErr> {
+        // Checked up front so that a corrupt length can't make us allocate a huge buffer
+        if position.0 + position.1 > self.len {
+            return Err(TableErr::BadFile(format!("{:?} is past the end of the data file", position)));
+        }
+        self.file.seek(SeekFrom::Start(position.0))?;
+
+        let mut stored = vec![0; position_to_usize(position.1)?];
+        self.file.read_exact(&mut stored)?;
+
+        if let (true, Some((kind, checksum))) = (options.verify_on_read, position.2) {
+            if kind.checksum(&stored) != checksum {
+                return Err(TableErr::BadFile(format!("Checksum mismatch for the value at {:?} in {}", position, self.file_name)));
+            }
+        }
+
+        let decoded = match options.decode {
+            Some(decode) => decode(&stored)?,
+            None => stored,
+        };
+
+        // Going by what was recorded when the value was written, not by the options
+        position.3.decompress(decoded)
+    }
+}
+
+/// Iterates over every entry in the table in key order. Each entry is read separately, so
+/// an individual entry can fail; callers that want to stop at the first failure can
+/// `.collect::<Result<Vec<_>, _>>()`.
+pub fn iterate_entries(file_name: &str) -> Result<impl Iterator<Item = Result<KV, TableErr>>, TableErr> {
+    iterate_entries_with_options(file_name, TableOptions::default())
+}
+
+/// Like [iterate_entries], for tables written with [flush_with_options]
+pub fn iterate_entries_with_options(file_name: &str, options: TableOptions) -> Result<impl Iterator<Item = Result<KV, TableErr>>, TableErr> {
+    let index_reader = io::BufReader::new(File::open(index_fn(file_name))?);
+    let mut entry_reader = EntryReader::new(file_name, options);
+
+    Ok(index_reader.lines().map(move |key_or_err| entry_reader.read(&key_or_err?)))
+}
+
+/// Like [iterate_entries], but starting at the first key that's at least `start`. With a
+/// sparse index, reading starts at the sampled line before `start` rather than at the top of
+/// the index, and the lines before `start` are skipped without reading their values. Tables
+/// without one are scanned for `start` from the beginning, so they're assumed to be sorted.
+pub fn iterate_entries_from(file_name: &str, start: &str, sparse: Option<&SparseIndex>) -> Result<impl Iterator<Item = Result<KV, TableErr>>, TableErr> {
+    let (offset, _) = index_scan_start(sparse, start);
+    let mut index_file = File::open(index_fn(file_name))?;
+    index_file.seek(SeekFrom::Start(offset))?;
+    let mut entry_reader = EntryReader::new(file_name, TableOptions::default());
+    let start = start.to_string();
+
+    Ok(io::BufReader::new(index_file).lines()
+        .skip_while(move |key_or_err| matches!(key_or_err, Ok(line) if index_key(line).as_ref() < start.as_str()))
+        .map(move |key_or_err| entry_reader.read(&key_or_err?)))
+}
+
+/// Like [iterate_entries], but in reverse key order, largest first. The index lines are all
+/// read up front, but values are still only read as the iterator gets to them.
+pub fn iterate_entries_rev(file_name: &str) -> Result<impl Iterator<Item = Result<KV, TableErr>>, TableErr> {
+    let index_lines = read_index_lines(file_name)?;
+    let mut entry_reader = EntryReader::new(file_name, TableOptions::default());
+
+    Ok(index_lines.into_iter().rev().map(move |key_and_position| entry_reader.read(&key_and_position)))
+}
+
+/// Like [iterate_entries], but only reading the index. Every entry's value is left empty, but
+/// tombstones are still marked, so the entries can be merged like any others.
+pub fn iterate_keys(file_name: &str) -> Result<impl Iterator<Item = Result<KV, TableErr>>, TableErr> {
+    Ok(iterate_value_sizes(file_name)?.map(|entry| entry.map(|(kv, _)| kv)))
+}
+
+/// Like [iterate_keys], but with each entry's value size as recorded in the index: the length
+/// of its bytes in the data file (after compression and `encode`), or of an inlined value.
+/// Tombstones have a size of zero.
+pub fn iterate_value_sizes(file_name: &str) -> Result<impl Iterator<Item = Result<(KV, usize), TableErr>>, TableErr> {
+    let index_reader = io::BufReader::new(File::open(index_fn(file_name))?);
+
+    Ok(index_reader.lines().map(|key_or_err| {
+        let key_and_position = key_or_err?;
+        let (size, tombstone) = match ValueLocation::from_key(&key_and_position)? {
+            ValueLocation::Data(position) => (position_to_usize(position.1)?, false),
+            ValueLocation::Inline(value) => (value.len(), false),
+            ValueLocation::Tombstone => (0, true),
+        };
+
+        Ok((KV { key: index_key(&key_and_position).into_owned(), value: String::new(), tombstone }, size))
+    }))
+}
+
+/// Turns index lines into entries, reading values from a data file that's opened on the first
+/// value that's actually in it and then reused for the rest
+struct EntryReader {
+    // Owned so that the iterators don't borrow from their caller
+    file_name: String,
+    options: TableOptions,
+    data_file: Option<DataFile>,
+}
+
+impl EntryReader {
+    fn new(file_name: &str, options: TableOptions) -> EntryReader {
+        EntryReader { file_name: file_name.to_string(), options, data_file: None }
+    }
+
+    fn read(&mut self, key_and_position: &str) -> Result<KV, TableErr> {
+        let value = match ValueLocation::from_key(key_and_position)? {
+            ValueLocation::Inline(value) => value,
+            ValueLocation::Data(position) => {
+                let data_file = match &mut self.data_file {
+                    Some(data_file) => data_file,
+                    None => self.data_file.insert(DataFile::open(&self.file_name)?),
+                };
+                data_file.read(position, &self.options)?
+            },
+            ValueLocation::Tombstone => return Ok(KV::tombstone(&index_key(key_and_position))),
+        };
+
+        // At this point, if the key's malformed, we would've returned an Err already.
+        let key = index_key(key_and_position).into_owned();
+
+        Ok(KV::new(key, value))
+    }
+}
+
+/// Where the value for a key lives: usually somewhere in the data file, but tiny values may
+/// have been inlined into the index line. Deleted keys have no value at all.
+#[derive(Debug)]
+enum ValueLocation {
+    Data(DataPosition),
+    Inline(String),
+    Tombstone,
+}
+
+impl ValueLocation {
+    fn from_key(key: &str) -> Result<ValueLocation, TableErr> {
+        if let Some((_, location)) = key.split_once(':') {
+            if let Some(value) = location.strip_prefix(INLINE_MARKER) {
+                return Ok(ValueLocation::Inline(value.to_string()));
+            }
+            if location == TOMBSTONE_MARKER {
+                return Ok(ValueLocation::Tombstone);
+            }
+        }
+
+        Ok(ValueLocation::Data(DataPosition::from_key(key)?))
+    }
+}
+
+/// The position of data in the data file. First value is the start position, second is its
+/// length, third is the value's checksum and the algorithm behind it, if one was written, and
+/// fourth is how the value was compressed.
+#[derive(Debug)]
+struct DataPosition(u64, u64, Option<(ChecksumKind, u32)>, Compression);
+
+impl fmt::Display for DataPosition {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.3 != Compression::None {
+            write!(f, "{},", self.3.name())?;
+        }
+        match self.2 {
+            Some((ChecksumKind::Crc32, checksum)) => write!(f, "{},{},{:08x}", self.0, self.1, checksum),
+            Some((kind, checksum)) => write!(f, "{},{},{:08x},{}", self.0, self.1, checksum, kind.name()),
+            None => write!(f, "{},{}", self.0, self.1),
+        }
+    }
+}
+
+impl DataPosition {
+    fn from_strings(position: &str, length: &str) -> Result<DataPosition, TableErr> {
+        let Ok(position_val) = position.parse::<u64>() else {
+            return Err(TableErr::BadFile(format!("The position '{}' is invalid", position)));
+        };
+
+        let Ok(length_val) = length.parse::<u64>() else {
+            return Err(TableErr::BadFile(format!("The length '{}' is invalid", length)));
+        };
+
+        // Everything else adds the two together, so this is the one place that has to check
+        if position_val.checked_add(length_val).is_none() {
+            return Err(TableErr::BadFile(format!("The value at {} with length {} ends past the largest possible offset", position, length)));
+        }
+
+        Ok(DataPosition(position_val, length_val, None, Compression::None))
+    }
+
+    fn from_key(key: &str) -> Result<DataPosition, TableErr> {
+        let parts: Vec<&str> = key.split(":").collect();
+        if parts.len() < 2 {
+            return Err(TableErr::BadFile(format!("The key '{}' did not contain a corresponding position", key)));
+        }
+
+        let mut entries: Vec<&str> = parts[1].split(",").collect();
+        // Positions always start with a digit, so a codec can't be mistaken for one
+        let compression = match entries.first().and_then(|name| Compression::from_name(name)) {
+            Some(compression) => {
+                entries.remove(0);
+                compression
+            },
+            None => Compression::None,
+        };
+
+        if entries.len() < 2 {
+            return Err(TableErr::BadFile(format!("The position/lenth string '{}' is malformed", parts[1])));
+        }
+
+        let mut position = Self::from_strings(entries[0], entries[1])?;
+        if let Some(checksum) = entries.get(2) {
+            let Ok(checksum_val) = u32::from_str_radix(checksum, 16) else {
+                return Err(TableErr::BadFile(format!("The checksum '{}' is invalid", checksum)));
+            };
+            let kind = match entries.get(3) {
+                Some(name) => ChecksumKind::from_name(name).ok_or_else(|| TableErr::BadFile(format!("The checksum kind '{}' is unknown", name)))?,
+                None => ChecksumKind::Crc32,
+            };
+            position.2 = Some((kind, checksum_val));
+        }
+        position.3 = compression;
+
+        Ok(position)
+    }
+}
+
+fn position_to_usize(n: u64) -> Result<usize, TableErr> {
+    usize::try_from(n).map_err(|_| TableErr::BadFile(format!("{} is too large to address on this platform", n)))
+}
+
+fn value_location(file_name: &str, key: &str) -> Result<ValueLocation, TableErr> {
+    value_location_indexed(file_name, key, SparseIndex::load(file_name)?.as_ref())
+}
+
+fn value_location_indexed(file_name: &str, key: &str, sparse: Option<&SparseIndex>) -> Result<ValueLocation, TableErr> {
+    // Keys are never empty, so don't bother scanning for one
+    if key.is_empty() {
+        return Err(TableErr::KeyNotFound(key.to_string()));
+    }
+
+    let index_file_name = index_fn(file_name);
+    
+    let (start, sorted) = index_scan_start(sparse, key);
+    let mut index_file = File::open(index_file_name)?;
+    index_file.seek(SeekFrom::Start(start))?;
+    let index_file_reader = io::BufReader::new(index_file);
+
+    for line in index_file_reader.lines() {
+        let l = line?;
+        let line_key = index_key(&l);
+
+        // Compare the whole key, or "bar" would match the line for "barble"
+        if line_key == key {
+            return ValueLocation::from_key(&l);
+        }
+        if sorted && line_key.as_ref() > key {
+            break;
+        }
+    }
+
+    Err(TableErr::KeyNotFound(key.to_string()))
+}
+
+impl From<std::io::Error> for TableErr {
+    fn from(error: std::io::Error) -> Self {
+        TableErr::IO(format!("Failed to open file: {:?}", error))
+    }
+}
+
+impl From<ParseIntError> for TableErr {
+    fn from(err: ParseIntError) -> Self {
+        TableErr::BadFile(format!("Failed to parse Int: {:?}", err))
+    }
+}
+
+fn index_fn(name: &str) -> String {
+    format!("{}{}", name, INDEX_FILE_SUFFIX)
+}
+
+fn data_fn(name: &str) -> String {
+    format!("{}{}", name, DATA_FILE_SUFFIX)
+}
+
+fn bloom_fn(name: &str) -> String {
+    format!("{}{}", name, BLOOM_FILE_SUFFIX)
+}
+
+fn sparse_index_fn(name: &str) -> String {
+    format!("{}{}", name, SPARSE_INDEX_FILE_SUFFIX)
+}
+
+fn temp_fn(file_name: &str) -> String {
+    format!("{}{}", file_name, TEMP_FILE_SUFFIX)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Once;
+
+    static INIT: Once = Once::new();
+    const TEST_FILE_NAME: &str = "test_files/disk_test";
+
+    /// The index line that the default options write for `value`, stored at `start`
+    fn data_line(key: &str, start: usize, value: &str) -> String {
+        format!("{}:{},{},{:08x},crc32c", key, start, value.len(), ChecksumKind::Crc32c.checksum(value.as_bytes()))
+    }
+
+    /// [test_data]'s index lines, as the default options write them
+    fn test_data_index() -> String {
+        test_data().iter().enumerate().map(|(i, kv)| data_line(&kv.key, i * 6, &kv.value)).collect::<Vec<_>>().join("\n")
+    }
+
+    fn test_data() -> [KV; 5] {
+        [
+            KV::new("bar", "barble"),
+            KV::new("baz", "bazzle"),
+            KV::new("daz", "dazzle"),
+            KV::new("foo", "fooble"),
+            KV::new("raz", "razzle"),
+        ]
+    }
+
+
+    /// Initializes the test data and returns it in case a test wants to compare to it
+    fn test_init() {
+        INIT.call_once(|| {
+            let data = test_data();
+
+            flush(TEST_FILE_NAME, data).expect("Failed to initialize test data");
+        });
+    }
+
+
+    #[test]
+    fn flushes() -> Result<(), TableErr> {
+        // Not the most exciting test, but if this one fails, then it makes it clear that
+        // it's the flushing itself that's failing
+        test_init();
+
+        let data_file_contents = std::fs::read_to_string(format!("{}{}", TEST_FILE_NAME, ".data"))?;
+        let index_file_contents = std::fs::read_to_string(format!("{}{}", TEST_FILE_NAME, ".index"))?;
+
+        assert_eq!("barblebazzledazzlefooblerazzle", data_file_contents);
+        assert_eq!(test_data_index(), index_file_contents);
+
+        Ok(())
+    }
+
+    #[test]
+    fn contains_works() -> Result<(), TableErr> {
+        std::fs::write("test_files/test_contains.index", "and:0,1\nthe:1,1\nmome:2,8\nraths:10,7\noutgrabe:17,10")?;
+
+        assert!(file_contains("test_files/test_contains", "and")?);
+        assert!(file_contains("test_files/test_contains", "raths")?);
+        assert!(file_contains("test_files/test_contains", "outgrabe")?);
+
+        assert!(!file_contains("test_files/test_contains", "foo")?);
+        Ok(())
+    }
+
+    #[test]
+    fn reads() -> Result<(), TableErr> {
+        test_init();
+
+        assert_eq!("razzle", read(TEST_FILE_NAME, "raz")?);
+
+        Ok(())
     }
 
     #[test]
@@ -268,14 +1375,11 @@ mod tests {
 
         let iterator = iterate_entries(TEST_FILE_NAME)?;
 
-        let mut index = 0;
-        for kv in iterator {
+        for (index, kv) in iterator.enumerate() {
             let input_kv = &data[index];
             let iter_kv = kv.unwrap();
             assert_eq!(input_kv.key, iter_kv.key);
             assert_eq!(input_kv.value, iter_kv.value);
-
-            index += 1;
         }
 
         Ok(())
@@ -285,22 +1389,853 @@ mod tests {
     fn merges() -> Result<(), TableErr> {
         test_init();
         let test_data_2 = [
-            KV { key: String::from("bang"), value: String::from("bangle") },
-            KV { key: String::from("far"), value: String::from("farbing") },
+            KV::new("bang", "bangle"),
+            KV::new("far", "farbing"),
         ];
 
-        let _ = flush("test_files/test_data_2", test_data_2.into_iter());
-        let _ = merge_and_flush(TEST_FILE_NAME, "test_files/test_data_2", "test_files/merged_data");
+        let _ = flush("test_files/test_data_2", test_data_2);
+        let _ = merge_two_and_flush(TEST_FILE_NAME, "test_files/test_data_2", "test_files/merged_data");
 
         let data_file_contents = std::fs::read_to_string(format!("{}{}", "test_files/merged_data", ".data"))?;
         let index_file_contents = std::fs::read_to_string(format!("{}{}", "test_files/merged_data", ".index"))?;
 
         assert_eq!("banglebarblebazzledazzlefarbingfooblerazzle", data_file_contents);
-        assert_eq!("bang:0,6\nbar:6,6\nbaz:12,6\ndaz:18,6\nfar:24,7\nfoo:31,6\nraz:37,6", index_file_contents);
+        let expected = [
+            data_line("bang", 0, "bangle"),
+            data_line("bar", 6, "barble"),
+            data_line("baz", 12, "bazzle"),
+            data_line("daz", 18, "dazzle"),
+            data_line("far", 24, "farbing"),
+            data_line("foo", 31, "fooble"),
+            data_line("raz", 37, "razzle"),
+        ];
+        assert_eq!(expected.join("\n"), index_file_contents);
         Ok(())
     }
 
+    #[test]
+    fn reads_slices() -> Result<(), TableErr> {
+        let blob: String = (0..1000).map(|i| char::from(b'a' + (i % 26) as u8)).collect();
+        let data = [
+            KV::new("before", "short"),
+            KV::new("blob", blob.clone()),
+            KV::new("cafe", "after"),
+        ];
+        flush("test_files/slice_test", data)?;
+
+        let mut reassembled = Vec::new();
+        let mut offset = 0;
+        while offset < blob.len() {
+            let slice = read_slice("test_files/slice_test", "blob", offset, 300)?.expect("Key should be present");
+            assert!(slice.len() <= 300);
+            offset += slice.len();
+            reassembled.extend(slice);
+        }
 
-        
-}
+        assert_eq!(blob.as_bytes(), reassembled.as_slice());
+        assert_eq!(Some(Vec::new()), read_slice("test_files/slice_test", "blob", 2000, 10)?);
+        assert_eq!(None, read_slice("test_files/slice_test", "missing", 0, 10)?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn inlines_tiny_values() -> Result<(), TableErr> {
+        let large_value = "x".repeat(64);
+        let data = [
+            KV::new("big", large_value.clone()),
+            KV::new("tiny", "ab"),
+            KV::new("wee", "c,d:e"),
+        ];
+        let options = TableOptions { inline_threshold: 8, ..TableOptions::default() };
+        flush_with_options("test_files/inline_test", data.clone(), &options)?;
+
+        let index_file_contents = std::fs::read_to_string("test_files/inline_test.index")?;
+        assert_eq!(format!("{}\ntiny:inline,ab\nwee:inline,c,d:e", data_line("big", 0, &large_value)), index_file_contents);
+
+        assert_eq!(large_value, read("test_files/inline_test", "big")?);
+        assert_eq!("ab", read("test_files/inline_test", "tiny")?);
 
+        let iterated = iterate_entries("test_files/inline_test")?.collect::<Result<Vec<_>, _>>()?;
+        assert_eq!(data.iter().map(|kv| &kv.value).collect::<Vec<_>>(), iterated.iter().map(|kv| &kv.value).collect::<Vec<_>>());
+
+        // Inlined values must be readable without the data file at all
+        std::fs::remove_file("test_files/inline_test.data")?;
+        assert_eq!("c,d:e", read("test_files/inline_test", "wee")?);
+        assert!(read("test_files/inline_test", "big").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn merge_surfaces_read_errors() -> Result<(), TableErr> {
+        test_init();
+
+        // An "index file" that's actually a directory opens fine but fails on the first read
+        let _ = std::fs::create_dir("test_files/directory_table.index");
+
+        let result = merge_two_and_flush(TEST_FILE_NAME, "test_files/directory_table", "test_files/merged_directory_table");
+        assert!(matches!(result, Err(TableErr::IO(_))));
+
+        Ok(())
+    }
+
+    #[test]
+    fn checks_consistency() -> Result<(), TableErr> {
+        let table_name = "test_files/consistency_test";
+        flush(table_name, test_data())?;
+        check_consistency(table_name)?;
+
+        let data_file_name = format!("{}{}", table_name, ".data");
+        let data = std::fs::read(&data_file_name)?;
+
+        std::fs::write(&data_file_name, &data[..data.len() - 1])?;
+        match check_consistency(table_name) {
+            Err(TableErr::BadFile(message)) => assert!(message.contains("truncated")),
+            other => panic!("Expected a truncated data file, got {:?}", other),
+        }
+
+        std::fs::write(&data_file_name, [data.as_slice(), b"garbage"].concat())?;
+        match check_consistency(table_name) {
+            Err(TableErr::BadFile(message)) => assert!(message.contains("trailing")),
+            other => panic!("Expected trailing bytes, got {:?}", other),
+        }
+
+        std::fs::write(format!("{}{}", table_name, ".index"), "bar:0,6\nbaz")?;
+        assert!(matches!(check_consistency(table_name), Err(TableErr::BadFile(_))));
+
+        Ok(())
+    }
+
+    fn xor_cipher(bytes: &[u8]) -> Vec<u8> {
+        bytes.iter().map(|byte| byte ^ 0x2a).collect()
+    }
+
+    #[test]
+    fn applies_value_hooks() -> Result<(), TableErr> {
+        let options = TableOptions {
+            encode: Some(xor_cipher),
+            decode: Some(|bytes| Ok(xor_cipher(bytes))),
+            ..TableOptions::default()
+        };
+        flush_with_options("test_files/hooks_test", test_data(), &options)?;
+
+        let data_file_contents = std::fs::read("test_files/hooks_test.data")?;
+        assert_ne!(b"barblebazzledazzlefooblerazzle".as_slice(), data_file_contents.as_slice());
+        assert_eq!(xor_cipher(b"barblebazzledazzlefooblerazzle"), data_file_contents);
+
+        assert_eq!("dazzle", read_with_options("test_files/hooks_test", "daz", &options)?);
+
+        let iterated = iterate_entries_with_options("test_files/hooks_test", options)?.collect::<Result<Vec<_>, _>>()?;
+        for (input_kv, iter_kv) in test_data().iter().zip(iterated) {
+            assert_eq!(input_kv.value, iter_kv.value);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn never_inlines_encoded_values() -> Result<(), TableErr> {
+        let file_name = "test_files/inline_hooks_test";
+        let options = TableOptions {
+            inline_threshold: 100,
+            encode: Some(xor_cipher),
+            decode: Some(|bytes| Ok(xor_cipher(bytes))),
+            ..TableOptions::default()
+        };
+        flush_with_options(file_name, test_data(), &options)?;
+
+        for on_disk in [std::fs::read(index_fn(file_name))?, std::fs::read(data_fn(file_name))?] {
+            assert!(!on_disk.windows(6).any(|window| window == b"dazzle"));
+        }
+        assert_eq!("dazzle", read_with_options(file_name, "daz", &options)?);
+        assert_eq!(Some(b"zzl".to_vec()), read_slice_with_options(file_name, "daz", 2, 3, &options)?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn rewrites() -> Result<(), TableErr> {
+        let table_name = "test_files/rewrite_test";
+        let rewritten_name = "test_files/rewrite_test_copy";
+        // Simulates a table whose data file still holds superseded values: "old!" and "gone"
+        // aren't referenced by any live entry.
+        std::fs::write(format!("{}{}", table_name, ".data"), "old!appleyesgonebanana")?;
+        std::fs::write(format!("{}{}", table_name, ".index"), "a:4,5\na:0,4\nb:16,6")?;
+
+        let stats = rewrite(table_name, rewritten_name, &TableOptions::default())?;
+
+        assert_eq!(MergeStats { entries_written: 2, data_bytes_written: 11 }, stats);
+        assert_eq!("applebanana", std::fs::read_to_string(data_fn(rewritten_name))?);
+        assert_eq!("apple", read(rewritten_name, "a")?);
+        assert_eq!("banana", read(rewritten_name, "b")?);
+        check_consistency(rewritten_name)?;
+        // The original is untouched
+        assert_eq!("old!appleyesgonebanana", std::fs::read_to_string(data_fn(table_name))?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn rewrites_with_options() -> Result<(), TableErr> {
+        let options = TableOptions { checksums: true, compression: Compression::Lz, inline_threshold: 4, ..TableOptions::default() };
+        flush_with_options("test_files/rewrite_options_test", test_data(), &options)?;
+
+        rewrite("test_files/rewrite_options_test", "test_files/rewrite_options_test_copy", &options)?;
+
+        assert_eq!(read_index_lines("test_files/rewrite_options_test")?, read_index_lines("test_files/rewrite_options_test_copy")?);
+        assert_eq!("dazzle", read_with_options("test_files/rewrite_options_test_copy", "daz", &options)?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn parses_either_index_ending() -> Result<(), TableErr> {
+        let options = TableOptions { trailing_newline: true, ..TableOptions::default() };
+        flush_with_options("test_files/trailing_newline_test", test_data(), &options)?;
+        flush("test_files/no_trailing_newline_test", test_data())?;
+
+        let index_file_contents = std::fs::read_to_string("test_files/trailing_newline_test.index")?;
+        assert_eq!(format!("{}\n", test_data_index()), index_file_contents);
+
+        let with_newline = iterate_entries("test_files/trailing_newline_test")?.collect::<Result<Vec<_>, _>>()?;
+        let without_newline = iterate_entries("test_files/no_trailing_newline_test")?.collect::<Result<Vec<_>, _>>()?;
+        assert_eq!(
+            with_newline.iter().map(|kv| (&kv.key, &kv.value)).collect::<Vec<_>>(),
+            without_newline.iter().map(|kv| (&kv.key, &kv.value)).collect::<Vec<_>>()
+        );
+        check_consistency("test_files/trailing_newline_test")?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn concatenates() -> Result<(), TableErr> {
+        let [bar, baz, daz, foo, raz] = test_data();
+        let with_newline = TableOptions { trailing_newline: true, ..TableOptions::default() };
+
+        flush_with_options("test_files/concat_first", [bar, baz], &with_newline)?;
+        flush("test_files/concat_second", [daz, foo])?;
+        flush_with_options("test_files/concat_third", [raz], &with_newline)?;
+
+        concat("test_files/concat_first", "test_files/concat_second", "test_files/concat_partial")?;
+        concat("test_files/concat_partial", "test_files/concat_third", "test_files/concat_all")?;
+
+        let index_file_contents = std::fs::read_to_string("test_files/concat_all.index")?;
+        assert_eq!(test_data_index(), index_file_contents);
+        assert_eq!("dazzle", read("test_files/concat_all", "daz")?);
+        assert_eq!("razzle", read("test_files/concat_all", "raz")?);
+
+        assert!(concat("test_files/concat_second", "test_files/concat_first", "test_files/concat_overlap").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn empty_key_is_not_found() -> Result<(), TableErr> {
+        test_init();
+
+        assert!(!file_contains(TEST_FILE_NAME, "")?);
+        assert!(matches!(read(TEST_FILE_NAME, ""), Err(TableErr::KeyNotFound(_))));
+        assert_eq!(None, read_slice(TEST_FILE_NAME, "", 0, 10)?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn verifies_checksums_on_read() -> Result<(), TableErr> {
+        let table_name = "test_files/checksum_test";
+        let write_options = TableOptions { checksum_kind: ChecksumKind::Crc32, ..TableOptions::default() };
+        flush_with_options(table_name, test_data(), &write_options)?;
+
+        let index_file_contents = std::fs::read_to_string(format!("{}{}", table_name, ".index"))?;
+        assert!(index_file_contents.starts_with(&format!("bar:0,6,{:08x}\n", crc32(b"barble"))));
+
+        // Corrupt the first byte of "fooble"
+        let data_file_name = format!("{}{}", table_name, ".data");
+        let mut data = std::fs::read(&data_file_name)?;
+        data[18] = b'g';
+        std::fs::write(&data_file_name, data)?;
+
+        assert!(matches!(read(table_name, "foo"), Err(TableErr::BadFile(message)) if message.contains("Checksum mismatch")));
+        assert_eq!("razzle", read(table_name, "raz")?);
+
+        // Unless it's turned off
+        let unverified = TableOptions { verify_on_read: false, ..TableOptions::default() };
+        assert_eq!("gooble", read_with_options(table_name, "foo", &unverified)?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn default_tables_detect_corruption() -> Result<(), TableErr> {
+        let table_name = "test_files/default_checksum_test";
+        flush(table_name, test_data())?;
+
+        let data_file_name = data_fn(table_name);
+        let mut data = std::fs::read(&data_file_name)?;
+        data[6] ^= 0x01;
+        std::fs::write(&data_file_name, data)?;
+
+        assert!(matches!(read(table_name, "baz"), Err(TableErr::BadFile(_))));
+        assert_eq!("barble", read(table_name, "bar")?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn sends_and_receives() -> Result<(), TableErr> {
+        test_init();
+
+        let mut pipe: Vec<u8> = Vec::new();
+        send(TEST_FILE_NAME, &mut pipe)?;
+        receive(pipe.as_slice(), "test_files/received_table")?;
+
+        let sent = iterate_entries(TEST_FILE_NAME)?.collect::<Result<Vec<_>, _>>()?;
+        let received = iterate_entries("test_files/received_table")?.collect::<Result<Vec<_>, _>>()?;
+        assert_eq!(
+            sent.iter().map(|kv| (&kv.key, &kv.value)).collect::<Vec<_>>(),
+            received.iter().map(|kv| (&kv.key, &kv.value)).collect::<Vec<_>>()
+        );
+        assert_eq!("fooble", read("test_files/received_table", "foo")?);
+
+        let truncated = receive(&pipe[..pipe.len() - 10], "test_files/truncated_table");
+        assert!(matches!(truncated, Err(TableErr::BadFile(_))));
+        assert!(!std::path::Path::new("test_files/truncated_table.index").exists());
+
+        let last = pipe.len() - 5;
+        pipe[last] ^= 0xFF;
+        assert!(matches!(receive(pipe.as_slice(), "test_files/corrupted_table"), Err(TableErr::BadFile(_))));
+
+        Ok(())
+    }
+
+    #[test]
+    fn approximates_key_count() -> Result<(), TableErr> {
+        let file_name = "test_files/approx_count_test";
+        let data = (0..10_000).map(|i| KV::new(format!("key{}", i), "v".repeat(i % 50 + 1)));
+        flush(file_name, data)?;
+
+        assert_eq!(10_000, approx_key_count(file_name, 1)?);
+
+        let approx = approx_key_count(file_name, 100)?;
+        assert!((9_500..=10_500).contains(&approx), "Estimated {} keys", approx);
+
+        Ok(())
+    }
+
+    #[test]
+    fn records_checksum_kind() -> Result<(), TableErr> {
+        for kind in [ChecksumKind::Crc32, ChecksumKind::Crc32c] {
+            let table_name = format!("test_files/checksum_kind_test_{}", kind.name());
+            let write_options = TableOptions { checksums: true, checksum_kind: kind, ..TableOptions::default() };
+            flush_with_options(&table_name, test_data(), &write_options)?;
+
+            let index_file_contents = std::fs::read_to_string(format!("{}{}", table_name, ".index"))?;
+            let first_line = index_file_contents.lines().next().expect("The index should not be empty");
+            let position = DataPosition::from_key(first_line)?;
+            assert_eq!(Some((kind, kind.checksum(b"barble"))), position.2);
+
+            // Readers pick the algorithm up from the index, whatever their own options say
+            let verifying = TableOptions { verify_on_read: true, ..TableOptions::default() };
+            assert_eq!("barble", read_with_options(&table_name, "bar", &verifying)?);
+
+            let data_file_name = format!("{}{}", table_name, ".data");
+            let mut data = std::fs::read(&data_file_name)?;
+            data[0] = b'c';
+            std::fs::write(&data_file_name, data)?;
+
+            assert!(matches!(read_with_options(&table_name, "bar", &verifying), Err(TableErr::BadFile(_))));
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn rebuilds_framed_index() -> Result<(), TableErr> {
+        let table_name = "test_files/framed_test";
+        let options = TableOptions { framed: true, inline_threshold: 100, ..TableOptions::default() };
+        flush_with_options(table_name, test_data(), &options)?;
+        check_consistency(table_name)?;
+
+        let before: Vec<KV> = iterate_entries(table_name)?.collect::<Result<_, _>>()?;
+        std::fs::remove_file(format!("{}{}", table_name, ".index"))?;
+        assert!(read(table_name, "foo").is_err());
+
+        rebuild_index(table_name)?;
+
+        let after: Vec<KV> = iterate_entries(table_name)?.collect::<Result<_, _>>()?;
+        assert_eq!(before.len(), after.len());
+        for (expected, actual) in before.iter().zip(after.iter()) {
+            assert_eq!(expected.key, actual.key);
+            assert_eq!(expected.value, actual.value);
+        }
+        assert_eq!("fooble", read(table_name, "foo")?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn rejects_truncated_frames() -> Result<(), TableErr> {
+        let table_name = "test_files/truncated_frame_test";
+        flush_with_options(table_name, test_data(), &TableOptions { framed: true, ..TableOptions::default() })?;
+
+        let data_file_name = format!("{}{}", table_name, ".data");
+        let data = std::fs::read(&data_file_name)?;
+        std::fs::write(&data_file_name, &data[..data.len() - 1])?;
+
+        assert!(matches!(rebuild_index(table_name), Err(TableErr::BadFile(_))));
+
+        Ok(())
+    }
+
+    #[test]
+    fn merges_three_overlapping_tables_newest_first() -> Result<(), TableErr> {
+        let newest = "test_files/merge_three_newest";
+        let middle = "test_files/merge_three_middle";
+        let oldest = "test_files/merge_three_oldest";
+        flush(newest, vec![KV::new("b", "newest"), KV::new("d", "newest")])?;
+        flush(middle, vec![KV::new("a", "middle"), KV::new("b", "middle"), KV::new("c", "middle")])?;
+        flush(oldest, vec![KV::new("a", "oldest"), KV::new("c", "oldest"), KV::new("d", "oldest"), KV::new("e", "oldest")])?;
+
+        let dest = "test_files/merge_three_dest";
+        let stats = merge_and_flush_many(&[newest, middle, oldest], dest)?;
+        assert_eq!(5, stats.entries_written);
+
+        let merged: Vec<(String, String)> = iterate_entries(dest)?.map(|entry| entry.map(|kv| (kv.key, kv.value))).collect::<Result<_, _>>()?;
+        let expected = [("a", "middle"), ("b", "newest"), ("c", "middle"), ("d", "newest"), ("e", "oldest")];
+        assert_eq!(expected.map(|(key, value)| (key.to_string(), value.to_string())).to_vec(), merged);
+
+        Ok(())
+    }
+
+    #[test]
+    fn merges_any_number_of_tables() -> Result<(), TableErr> {
+        let kv = |key: &str, value: &str| KV::new(key, value);
+        let read_all = |name: &str| -> Result<Vec<(String, String)>, TableErr> {
+            iterate_entries(name)?.map(|entry| entry.map(|kv| (kv.key, kv.value))).collect()
+        };
+        let pairs = |expected: &[(&str, &str)]| -> Vec<(String, String)> {
+            expected.iter().map(|(key, value)| (key.to_string(), value.to_string())).collect()
+        };
+
+        let names: Vec<String> = (0..5).map(|i| format!("test_files/merge_many_{}", i)).collect();
+        // Every table has "shared", plus a key of its own
+        for (i, name) in names.iter().enumerate() {
+            flush(name, vec![kv(&format!("own{}", i), &i.to_string()), kv("shared", &i.to_string())])?;
+        }
+        let sources: Vec<&str> = names.iter().map(String::as_str).collect();
+
+        let stats = merge_and_flush(&sources[..1], "test_files/merged_one")?;
+        assert_eq!(pairs(&[("own0", "0"), ("shared", "0")]), read_all("test_files/merged_one")?);
+        assert_eq!(MergeStats { entries_written: 2, data_bytes_written: 2 }, stats);
+
+        merge_and_flush(&sources[..2], "test_files/merged_two")?;
+        assert_eq!(pairs(&[("own0", "0"), ("own1", "1"), ("shared", "0")]), read_all("test_files/merged_two")?);
+
+        // Later sources are older, so reversing them flips which "shared" wins
+        let mut reversed = sources.clone();
+        reversed.reverse();
+        merge_and_flush(&reversed, "test_files/merged_five")?;
+        assert_eq!(pairs(&[("own0", "0"), ("own1", "1"), ("own2", "2"), ("own3", "3"), ("own4", "4"), ("shared", "4")]), read_all("test_files/merged_five")?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn reads_only_the_value_from_the_data_file() -> Result<(), TableErr> {
+        let value = "x".repeat(1000);
+        let table_name = "test_files/large_read_test";
+        flush(table_name, (0..5000).map(|i| KV::new(format!("key{:05}", i), value.as_str())))?;
+
+        let ValueLocation::Data(position) = value_location(table_name, "key02500")? else {
+            panic!("The value should be in the data file");
+        };
+        let value_end = position.0 + position.1;
+        assert_eq!(1000, position.1);
+        let mut data_file = DataFile::open(table_name)?;
+        assert_eq!(value, data_file.read(position, &TableOptions::default())?);
+
+        // Reading the whole 5MB data file would leave the cursor at its end
+        assert_eq!(value_end, data_file.file.stream_position()?);
+        assert_eq!(5_000_000, data_file.len);
+
+        Ok(())
+    }
+
+    #[test]
+    fn rejects_positions_past_the_end() -> Result<(), TableErr> {
+        std::fs::write("test_files/short_data_test.index", "foo:0,10")?;
+        std::fs::write("test_files/short_data_test.data", "fooble")?;
+
+        assert!(matches!(read("test_files/short_data_test", "foo"), Err(TableErr::BadFile(_))));
+
+        Ok(())
+    }
+
+    #[test]
+    fn matches_whole_keys() -> Result<(), TableErr> {
+        let table_name = "test_files/prefix_key_test";
+        flush(table_name, vec![
+            KV::new("foo", "short"),
+            KV::new("foobar", "long"),
+        ])?;
+        // Index order isn't guaranteed to put the shorter key first, so check both orders
+        std::fs::write("test_files/reversed_prefix_key_test.index", "foobar:5,4\nfoo:0,5")?;
+        std::fs::copy(format!("{}{}", table_name, ".data"), "test_files/reversed_prefix_key_test.data")?;
+
+        for name in [table_name, "test_files/reversed_prefix_key_test"] {
+            assert_eq!("short", read(name, "foo")?);
+            assert_eq!("long", read(name, "foobar")?);
+            assert!(matches!(read(name, "fo"), Err(TableErr::KeyNotFound(_))));
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn parses_positions_past_four_gigabytes() -> Result<(), TableErr> {
+        let position = DataPosition::from_key("big:4294967296,5000000000")?;
+        assert_eq!((4_294_967_296, 5_000_000_000), (position.0, position.1));
+        assert_eq!("4294967296,5000000000", position.to_string());
+
+        assert!(matches!(DataPosition::from_key("overflow:18446744073709551615,1"), Err(TableErr::BadFile(_))));
+
+        // The data file is nowhere near that big, which has to be an error rather than a panic
+        std::fs::write("test_files/large_position_test.index", "big:4294967296,5000000000")?;
+        std::fs::write("test_files/large_position_test.data", "tiny")?;
+        assert!(matches!(read("test_files/large_position_test", "big"), Err(TableErr::BadFile(_))));
+        assert!(matches!(check_consistency("test_files/large_position_test"), Err(TableErr::BadFile(_))));
+
+        Ok(())
+    }
+
+    #[test]
+    fn round_trips_tombstones() -> Result<(), TableErr> {
+        let table_name = "test_files/tombstone_test";
+        let entries = vec![
+            KV::new("alive", "here"),
+            KV::tombstone("deleted"),
+            KV::new("later", "also here"),
+        ];
+        flush(table_name, entries.clone())?;
+
+        let index_file_contents = std::fs::read_to_string(format!("{}{}", table_name, ".index"))?;
+        assert_eq!(format!("{}\ndeleted:tombstone\n{}", data_line("alive", 0, "here"), data_line("later", 4, "also here")), index_file_contents);
+        check_consistency(table_name)?;
+
+        assert_eq!("here", read(table_name, "alive")?);
+        assert!(matches!(read(table_name, "deleted"), Err(TableErr::KeyDeleted(_))));
+        assert!(matches!(read(table_name, "missing"), Err(TableErr::KeyNotFound(_))));
+        assert_eq!(None, read_slice(table_name, "deleted", 0, 4)?);
+
+        let iterated: Vec<KV> = iterate_entries(table_name)?.collect::<Result<_, _>>()?;
+        assert_eq!(vec![false, true, false], iterated.iter().map(|kv| kv.tombstone).collect::<Vec<_>>());
+        assert_eq!("deleted", iterated[1].key);
+
+        // Framed tables have to keep tombstones through an index rebuild too
+        let framed_name = "test_files/framed_tombstone_test";
+        flush_with_options(framed_name, entries, &TableOptions { framed: true, ..TableOptions::default() })?;
+        std::fs::remove_file(format!("{}{}", framed_name, ".index"))?;
+        rebuild_index(framed_name)?;
+        assert!(matches!(read(framed_name, "deleted"), Err(TableErr::KeyDeleted(_))));
+        assert_eq!("also here", read(framed_name, "later")?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn iterates_with_one_data_file_handle() -> Result<(), TableErr> {
+        let file_name = "test_files/single_handle_test";
+        let data: Vec<KV> = (0..20)
+            .map(|i| KV::new(format!("key{:02}", i), format!("value{}", i)))
+            .collect();
+        flush(file_name, data.clone())?;
+
+        let mut entries = iterate_entries(file_name)?;
+        assert_eq!(data[0].value, entries.next().expect("Table shouldn't be empty")?.value);
+
+        // The iterator already has the data file open, so it doesn't need the name any more
+        std::fs::remove_file(data_fn(file_name))?;
+        let rest = entries.collect::<Result<Vec<_>, _>>()?;
+
+        assert_eq!(19, rest.len());
+        for (kv, expected) in rest.iter().zip(&data[1..]) {
+            assert_eq!(expected.key, kv.key);
+            assert_eq!(expected.value, kv.value);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn merge_fails_cleanly_on_corrupt_tables() -> Result<(), TableErr> {
+        let good = "test_files/merge_error_good";
+        let corrupt = "test_files/merge_error_corrupt";
+        let dest = "test_files/merge_error_dest";
+        let _ = std::fs::remove_file(index_fn(dest));
+        let _ = std::fs::remove_file(data_fn(dest));
+
+        flush(good, vec![KV::new("a", "1")])?;
+        flush(corrupt, vec![KV::new("a", "2")])?;
+        let mut index = std::fs::OpenOptions::new().append(true).open(index_fn(corrupt))?;
+        write!(index, "\nb:not,a,position")?;
+
+        assert!(matches!(merge_and_flush(&[good, corrupt], dest), Err(TableErr::BadFile(_))));
+        // Nothing is written unless every entry could be read
+        assert!(!std::path::Path::new(&index_fn(dest)).exists());
+
+        Ok(())
+    }
+
+    #[test]
+    fn escapes_keys_in_the_index() -> Result<(), TableErr> {
+        let file_name = "test_files/escaped_keys_test";
+        let keys = ["100%", "a:b", "comma,key", "line1\nline2", "plain", "tomb:stone"];
+        let data: Vec<KV> = keys.iter().map(|key| match *key {
+            "tomb:stone" => KV::tombstone(key),
+            _ => KV::new(*key, format!("value of {}", key)),
+        }).collect();
+        let options = TableOptions { inline_threshold: 12, ..TableOptions::default() };
+        flush_with_options(file_name, data, &options)?;
+
+        assert_eq!(keys.len(), read_index_lines(file_name)?.len());
+        for key in &keys[..5] {
+            assert_eq!(format!("value of {}", key), read(file_name, key)?);
+        }
+        assert!(matches!(read(file_name, "tomb:stone"), Err(TableErr::KeyDeleted(_))));
+        assert!(matches!(read(file_name, "a"), Err(TableErr::KeyNotFound(_))));
+
+        let iterated: Vec<String> = iterate_entries(file_name)?.map(|entry| entry.map(|kv| kv.key)).collect::<Result<_, _>>()?;
+        assert_eq!(keys.to_vec(), iterated);
+
+        // Percent signs that don't start an escape are left alone
+        assert_eq!("50%off", unescape_key("50%off"));
+        assert_eq!("a:b%", unescape_key(&escape_key("a:b%")));
+
+        Ok(())
+    }
+
+    #[test]
+    fn round_trips_binary_values() -> Result<(), TableErr> {
+        let file_name = "test_files/binary_values_test";
+        let binary = vec![0x00, 0xFF, 0xC3, 0x28, 0x00, b'\n', 0x80];
+        flush_bytes(file_name, vec![
+            (String::from("binary"), binary.clone()),
+            (String::from("text"), b"plain".to_vec()),
+        ])?;
+
+        assert_eq!(binary, read_bytes(file_name, "binary")?);
+        assert_eq!(b"plain".to_vec(), read_bytes(file_name, "text")?);
+        assert_eq!("plain", read(file_name, "text")?);
+        // The string API still refuses to hand out something that isn't a string
+        assert!(matches!(read(file_name, "binary"), Err(TableErr::BadFile(_))));
+
+        Ok(())
+    }
+
+    #[test]
+    fn flushes_through_temporary_files() -> Result<(), TableErr> {
+        let dir = std::path::Path::new("test_files/atomic_flush");
+        let _ = std::fs::remove_dir_all(dir);
+        std::fs::create_dir_all(dir)?;
+        let file_name = "test_files/atomic_flush/table";
+
+        flush(file_name, test_data())?;
+        // Flushing over an existing table replaces it just the same
+        flush(file_name, test_data().into_iter().rev().take(1))?;
+
+        let mut files: Vec<String> = std::fs::read_dir(dir)?
+            .map(|entry| entry.map(|entry| entry.file_name().to_string_lossy().to_string()))
+            .collect::<Result<_, _>>()?;
+        files.sort();
+        assert_eq!(vec!["table.bloom", "table.data", "table.index", "table.sparse"], files);
+        assert_eq!(1, iterate_entries(file_name)?.count());
+
+        Ok(())
+    }
+
+    #[test]
+    fn skips_the_index_for_filtered_keys() -> Result<(), TableErr> {
+        let file_name = "test_files/bloom_test";
+        let data: Vec<KV> = (0..100).map(|i| KV::new(format!("key{:03}", i), i.to_string())).collect();
+        flush(file_name, data)?;
+        assert!(file_contains(file_name, "key042")?);
+
+        // With the index gone, only the filter can answer
+        std::fs::remove_file(index_fn(file_name))?;
+        for key in ["missing", "key100", "yek042"] {
+            assert!(!file_contains(file_name, key)?, "{} got past the filter", key);
+        }
+        assert!(file_contains(file_name, "key042").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn seeks_near_the_key_in_large_tables() -> Result<(), TableErr> {
+        let file_name = "test_files/sparse_index_test";
+        let data: Vec<KV> = (0..10_000).map(|i| KV::new(format!("key{:05}", i), i.to_string())).collect();
+        flush(file_name, data)?;
+
+        // Scanning through this line would fail, so lookups can't go anywhere near it
+        let mut index = std::fs::read(index_fn(file_name))?;
+        let first_line_len = index.iter().position(|b| *b == b'\n').expect("Index should have several lines");
+        index[..first_line_len].fill(0xFF);
+        std::fs::write(index_fn(file_name), index)?;
+
+        assert_eq!("9999", read(file_name, "key09999")?);
+        assert_eq!("5000", read(file_name, "key05000")?);
+        assert!(!file_contains(file_name, "key05000a")?);
+        assert!(read(file_name, "key00001").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn checks_for_live_keys() -> Result<(), TableErr> {
+        let file_name = "test_files/contains_live_test";
+        flush(file_name, vec![
+            KV::new("a", "1"),
+            KV::tombstone("b"),
+        ])?;
+
+        assert_eq!(Some(true), contains_live(file_name, "a")?);
+        assert_eq!(Some(false), contains_live(file_name, "b")?);
+        assert_eq!(None, contains_live(file_name, "c")?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn round_trips_compressed_values() -> Result<(), TableErr> {
+        let file_name = "test_files/compression_test";
+        let data: Vec<KV> = (0..50).map(|i| KV::new(format!("key{:02}", i), format!("{} ", i).repeat(200))).collect();
+        let options = TableOptions { compression: Compression::Lz, checksums: true, verify_on_read: true, ..TableOptions::default() };
+        flush_with_options(file_name, data.clone(), &options)?;
+
+        let raw_len: usize = data.iter().map(|kv| kv.value.len()).sum();
+        assert!(std::fs::metadata(data_fn(file_name))?.len() < raw_len as u64 / 10);
+
+        assert_eq!(data[17].value, read_with_options(file_name, "key17", &options)?);
+        let read_back = iterate_entries_with_options(file_name, options)?.collect::<Result<Vec<_>, _>>()?;
+        assert_eq!(data.iter().map(|kv| &kv.value).collect::<Vec<_>>(), read_back.iter().map(|kv| &kv.value).collect::<Vec<_>>());
+
+        // The codec is recorded with each value, so readers don't have to be told about it
+        assert!(read_index_lines(file_name)?[0].starts_with("key00:lz,0,"));
+        assert_eq!(data[17].value, read(file_name, "key17")?);
+        assert_eq!(Some(data[3].value.as_bytes()[5..15].to_vec()), read_slice(file_name, "key03", 5, 10)?);
+        let read_back = iterate_entries(file_name)?.collect::<Result<Vec<_>, _>>()?;
+        assert_eq!(data.iter().map(|kv| &kv.value).collect::<Vec<_>>(), read_back.iter().map(|kv| &kv.value).collect::<Vec<_>>());
+
+        Ok(())
+    }
+
+    #[test]
+    fn iterates_from_a_key() -> Result<(), TableErr> {
+        let file_name = "test_files/iterate_from_test";
+        let data: Vec<KV> = (0..100).map(|i| KV::new(format!("key{:02}", i), format!("value{:02}", i))).collect();
+        flush(file_name, data)?;
+        let sparse = SparseIndex::load(file_name)?;
+        assert!(sparse.is_some());
+
+        let keys = |start: &str, sparse: Option<&SparseIndex>| -> Result<Vec<String>, TableErr> {
+            iterate_entries_from(file_name, start, sparse)?.take(2).map(|entry| entry.map(|kv| kv.key)).collect()
+        };
+        assert_eq!(vec!["key50", "key51"], keys("key50", sparse.as_ref())?);
+        assert_eq!(vec!["key51", "key52"], keys("key505", sparse.as_ref())?);
+        assert_eq!(vec!["key50", "key51"], keys("key50", None)?);
+        assert!(keys("z", sparse.as_ref())?.is_empty());
+
+        // Values before the start are never read, so corrupting one doesn't get in the way
+        let mut data_bytes = std::fs::read(data_fn(file_name))?;
+        data_bytes[0] ^= 0xFF;
+        std::fs::write(data_fn(file_name), data_bytes)?;
+        assert_eq!(vec!["key50", "key51"], keys("key50", sparse.as_ref())?);
+        assert!(iterate_entries(file_name)?.next().is_some_and(|entry| entry.is_err()));
+
+        Ok(())
+    }
+
+    #[test]
+    fn iterates_in_reverse() -> Result<(), TableErr> {
+        let file_name = "test_files/reverse_iter_test";
+        let options = TableOptions { inline_threshold: 7, ..TableOptions::default() };
+        flush_with_options(file_name, test_data(), &options)?;
+
+        let reversed = iterate_entries_rev(file_name)?.collect::<Result<Vec<_>, _>>()?;
+        let expected: Vec<_> = test_data().into_iter().rev().map(|kv| (kv.key, kv.value)).collect();
+        assert_eq!(expected, reversed.into_iter().map(|kv| (kv.key, kv.value)).collect::<Vec<_>>());
+
+        Ok(())
+    }
+
+    #[test]
+    fn reports_flush_stats() -> Result<(), TableErr> {
+        let stats = flush("test_files/flush_stats_test", test_data())?;
+
+        // Five six byte values, and their index lines joined by newlines
+        assert_eq!(TableStats {
+            entry_count: 5,
+            data_bytes: 30,
+            index_bytes: u64::try_from(test_data_index().len()).expect("Couldn't parse usize into u64"),
+            min_key: Some(String::from("bar")),
+            max_key: Some(String::from("raz")),
+        }, stats);
+        assert_eq!(stats.index_bytes, std::fs::metadata(index_fn("test_files/flush_stats_test"))?.len());
+
+        let empty = flush("test_files/empty_flush_stats_test", Vec::new())?;
+        assert_eq!((0, None), (empty.entry_count, empty.min_key));
+
+        Ok(())
+    }
+
+    #[test]
+    fn keeps_the_last_of_duplicate_keys() -> Result<(), TableErr> {
+        let file_name = "test_files/flush_duplicates_test";
+        let stats = flush(file_name, vec![
+            KV::new("a", "1"),
+            KV::new("a", "2"),
+            KV::new("b", "3"),
+        ])?;
+
+        assert_eq!(2, stats.entry_count);
+        assert_eq!("2", read(file_name, "a")?);
+        let entries: Vec<(String, String)> = iterate_entries(file_name)?.map(|entry| entry.map(|kv| (kv.key, kv.value))).collect::<Result<_, _>>()?;
+        assert_eq!(vec![(String::from("a"), String::from("2")), (String::from("b"), String::from("3"))], entries);
+
+        Ok(())
+    }
+
+    #[test]
+    fn sorts_unsorted_input() -> Result<(), TableErr> {
+        let file_name = "test_files/flush_unsorted_test";
+        let mut unsorted = test_data().to_vec();
+        unsorted.swap(0, 4);
+        unsorted.swap(1, 3);
+        flush_unsorted(file_name, unsorted)?;
+
+        let keys: Vec<String> = read_index_lines(file_name)?.iter().map(|line| index_key(line).into_owned()).collect();
+        assert_eq!(vec!["bar", "baz", "daz", "foo", "raz"], keys);
+        assert!(std::path::Path::new(&sparse_index_fn(file_name)).exists());
+        assert_eq!("razzle", read(file_name, "raz")?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn reads_many_keys_at_once() -> Result<(), TableErr> {
+        let file_name = "test_files/read_many_test";
+        let mut data = test_data().to_vec();
+        data[2] = KV::tombstone("daz");
+        flush(file_name, data)?;
+
+        let found = read_many(file_name, &["raz", "bar", "daz", "nope", ""])?;
+        assert_eq!(3, found.len());
+        assert_eq!(Some(&Some(String::from("razzle"))), found.get("raz"));
+        assert_eq!(Some(&Some(String::from("barble"))), found.get("bar"));
+        assert_eq!(Some(&None), found.get("daz"));
+
+        Ok(())
+    }
+}
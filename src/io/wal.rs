@@ -0,0 +1,196 @@
+use crate::io::checksum::crc32c;
+use crate::io::table::TableErr;
+use crate::lsm::kv::KV;
+use std::fs::{ File, OpenOptions };
+use std::io::{ Seek, SeekFrom, Write };
+
+const HEADER_BYTES: usize = 8;
+const PUT_RECORD: u8 = 0;
+const DELETE_RECORD: u8 = 1;
+
+/// An append-only log of the writes that haven't made it into a table yet, so that they can be
+/// replayed into the mem_table after a crash.
+///
+/// Each record is `[u32 BE payload length][u32 BE CRC32C of the payload][payload]`, where the
+/// payload is a kind byte (put or delete), the key's length as a u32 BE, the key and then the
+/// value. A crash part way through an append leaves a record that's short or fails its
+/// checksum, which is where replaying stops.
+pub struct Wal {
+    file: File,
+    /// Syncs every append (and clear) to disk before returning
+    durable: bool,
+}
+
+/// What was recovered from a log when it was opened
+pub struct Replay {
+    /// Every complete record, in the order they were written
+    pub records: Vec<KV>,
+    /// How many bytes of incomplete records were cut off the end of the log, i.e. a write
+    /// that was still being appended when the process died. Zero after a clean shutdown.
+    pub discarded_bytes: usize,
+}
+
+impl Wal {
+    /// Opens the log, returning every complete record in it. Anything after the last complete
+    /// record is cut off so that new records follow on from it, and how much was cut off is
+    /// reported for the caller to decide what to make of it.
+    pub fn open(file_name: &str) -> Result<(Wal, Replay), TableErr> {
+        let data = match std::fs::read(file_name) {
+            Ok(data) => data,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut records = Vec::new();
+        let mut position = 0;
+        while let Some((kv, next_position)) = Self::read_record(&data, position) {
+            records.push(kv);
+            position = next_position;
+        }
+
+        let mut file = OpenOptions::new().create(true).truncate(false).write(true).open(file_name)?;
+        file.set_len(u64::try_from(position).expect("Couldn't parse usize into u64"))?;
+        file.seek(SeekFrom::End(0))?;
+
+        Ok((Wal { file, durable: false }, Replay { records, discarded_bytes: data.len() - position }))
+    }
+
+    /// Opens an empty log, throwing away whatever was in it before
+    pub fn create(file_name: &str) -> Result<Wal, TableErr> {
+        let file = OpenOptions::new().create(true).truncate(true).write(true).open(file_name)?;
+
+        Ok(Wal { file, durable: false })
+    }
+
+    /// Syncs each record to disk before `append` returns, so that an acknowledged write
+    /// survives a power failure and not just a crash. Off by default.
+    pub fn set_durable(&mut self, durable: bool) {
+        self.durable = durable;
+    }
+
+    pub fn append(&mut self, kv: &KV) -> Result<(), TableErr> {
+        let kind = if kv.tombstone { DELETE_RECORD } else { PUT_RECORD };
+        let value: &[u8] = if kv.tombstone { &[] } else { kv.value.as_bytes() };
+
+        let mut payload = vec![kind];
+        payload.extend(u32::try_from(kv.key.len()).expect("Couldn't parse usize into u32").to_be_bytes());
+        payload.extend(kv.key.as_bytes());
+        payload.extend(value);
+
+        // One write per record, so a crash can only ever leave the last one incomplete
+        let mut record = Vec::with_capacity(HEADER_BYTES + payload.len());
+        record.extend(u32::try_from(payload.len()).expect("Couldn't parse usize into u32").to_be_bytes());
+        record.extend(crc32c(&payload).to_be_bytes());
+        record.extend(payload);
+        self.file.write_all(&record)?;
+        if self.durable {
+            self.file.sync_data()?;
+        }
+
+        Ok(())
+    }
+
+    /// Syncs every record appended so far to disk, for logs that aren't durable
+    pub fn sync(&self) -> Result<(), TableErr> {
+        self.file.sync_data()?;
+
+        Ok(())
+    }
+
+    /// Empties the log, once everything in it has been flushed to a table
+    pub fn clear(&mut self) -> Result<(), TableErr> {
+        self.file.set_len(0)?;
+        self.file.seek(SeekFrom::Start(0))?;
+        if self.durable {
+            self.file.sync_data()?;
+        }
+
+        Ok(())
+    }
+
+    /// Reads the record starting at `position`, returning it along with where the next one
+    /// starts, or `None` if there isn't a complete, intact record there
+    fn read_record(data: &[u8], position: usize) -> Option<(KV, usize)> {
+        let header = data.get(position..position + HEADER_BYTES)?;
+        let len = usize::try_from(u32::from_be_bytes(header[0..4].try_into().ok()?)).ok()?;
+        let checksum = u32::from_be_bytes(header[4..8].try_into().ok()?);
+
+        let payload = data.get(position + HEADER_BYTES..position + HEADER_BYTES + len)?;
+        if crc32c(payload) != checksum {
+            return None;
+        }
+
+        let (&kind, rest) = payload.split_first()?;
+        let key_len = usize::try_from(u32::from_be_bytes(rest.get(0..4)?.try_into().ok()?)).ok()?;
+        let key = String::from_utf8(rest.get(4..4 + key_len)?.to_vec()).ok()?;
+        let value = String::from_utf8(rest.get(4 + key_len..)?.to_vec()).ok()?;
+
+        let kv = match kind {
+            PUT_RECORD => KV::new(key, value),
+            DELETE_RECORD => KV::tombstone(&key),
+            _ => return None,
+        };
+
+        Some((kv, position + HEADER_BYTES + len))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::io::wal::*;
+
+    #[test]
+    fn replays_complete_records() -> Result<(), TableErr> {
+        let file_name = "test_files/wal_replay_test.wal";
+        let mut wal = Wal::create(file_name)?;
+        wal.append(&KV::new("a", "1"))?;
+        wal.append(&KV::tombstone("b"))?;
+        wal.append(&KV::new("c", "3"))?;
+        drop(wal);
+
+        // Half of a record, as if the process died while appending it
+        let mut file = OpenOptions::new().append(true).open(file_name)?;
+        file.write_all(&[0, 0, 0, 20, 1, 2])?;
+        drop(file);
+
+        let (mut wal, replay) = Wal::open(file_name)?;
+        assert_eq!(6, replay.discarded_bytes);
+        let replayed: Vec<_> = replay.records.iter().map(|kv| (kv.key.as_str(), kv.value.as_str(), kv.tombstone)).collect();
+        assert_eq!(vec![("a", "1", false), ("b", "", true), ("c", "3", false)], replayed);
+
+        // New records go after the last complete one rather than after the partial one
+        wal.append(&KV::new("d", "4"))?;
+        drop(wal);
+        let (mut wal, replay) = Wal::open(file_name)?;
+        assert_eq!(0, replay.discarded_bytes);
+        assert_eq!(4, replay.records.len());
+        assert_eq!("d", replay.records[3].key);
+
+        wal.clear()?;
+        drop(wal);
+        assert!(Wal::open(file_name)?.1.records.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn replays_durable_records() -> Result<(), TableErr> {
+        let file_name = "test_files/wal_durable_test.wal";
+        let mut wal = Wal::create(file_name)?;
+        wal.set_durable(true);
+        wal.append(&KV::new("a", "1"))?;
+        wal.append(&KV::tombstone("a"))?;
+        drop(wal);
+
+        let (mut wal, replay) = Wal::open(file_name)?;
+        assert_eq!(2, replay.records.len());
+        assert!(replay.records[1].tombstone);
+
+        wal.set_durable(true);
+        wal.clear()?;
+        drop(wal);
+        assert!(Wal::open(file_name)?.1.records.is_empty());
+
+        Ok(())
+    }
+}
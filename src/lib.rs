@@ -1,3 +1,3 @@
-mod io;
-mod lsm;
-mod db;
+pub mod io;
+pub mod lsm;
+pub mod db;
@@ -1,3 +1,3 @@
-pub(crate) mod tree;
-pub(crate) mod kv;
-pub(crate) mod merge_iter;
+pub mod tree;
+pub mod kv;
+pub mod merge_iter;
@@ -1,7 +1,12 @@
+use crate::io::table::TableErr;
+
 #[derive(Debug)]
 pub struct KV {
     pub key: String,
     pub value: String,
+    /// Marks the key as deleted. A tombstone has no value of its own; it exists to shadow
+    /// older values for the key until compaction can drop them.
+    pub tombstone: bool,
 }
 
 impl Clone for KV {
@@ -9,7 +14,43 @@ impl Clone for KV {
         KV { 
             key: self.key.to_string(),
             value: self.value.to_string(),
+            tombstone: self.tombstone,
+        }
+    }
+}
+
+impl KV {
+    pub fn new(key: impl Into<String>, value: impl Into<String>) -> KV {
+        KV {
+            key: key.into(),
+            value: value.into(),
+            tombstone: false,
+        }
+    }
+
+    pub fn tombstone(key: &str) -> KV {
+        KV {
+            key: key.to_string(),
+            value: String::new(),
+            tombstone: true,
         }
     }
 }
 
+const U64_KEY_WIDTH: usize = 16;
+
+/// Encodes an integer as a fixed-width, big-endian hex key. Keys are compared as strings, so
+/// plain decimal would sort `"10"` before `"2"`; this encoding sorts numerically and sticks to
+/// characters that are safe in the index format.
+pub fn encode_u64(n: u64) -> String {
+    n.to_be_bytes().iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Reverses [encode_u64]
+pub fn decode_u64(key: &str) -> Result<u64, TableErr> {
+    if key.len() != U64_KEY_WIDTH {
+        return Err(TableErr::BadFile(format!("'{}' is not an encoded u64 key", key)));
+    }
+
+    Ok(u64::from_str_radix(key, 16)?)
+}
@@ -1,6 +1,14 @@
+use std::cmp::Reverse;
+use std::collections::{ BinaryHeap, VecDeque };
 use std::iter::Peekable;
-use crate::lsm::kv::KV;
 use crate::io::table::TableErr;
+use crate::lsm::kv::KV;
+
+/// A stream of table entries, any of which can fail to be read
+pub type EntryIter = Box<dyn Iterator<Item = Result<KV, TableErr>>>;
+
+/// Like [EntryIter], but with something carried alongside each entry
+pub type TaggedEntryIter<T> = Box<dyn Iterator<Item = Result<(KV, T), TableErr>>>;
 
 /// The decision that comes from the comparison of the elements of two iterators
 /// being merged. Left and Right take a boolean that indicates whether the next 
@@ -8,17 +16,25 @@ use crate::io::table::TableErr;
 /// (for example) both have the same value, but only the left should be added 
 /// to the next list. In such situations, the right value should be dropped 
 /// entirely.
+///
+/// Both emits the left value now and the right value straight after it, which is how
+/// equal keys can both survive a merge.
 pub enum MergeDecision {
     Left(bool),
     Right(bool),
+    Both,
     None,
 }
 
-pub struct MergeIter<T, I> 
-    where T : Iterator<Item = I> {
+/// The comparator can be any closure, including ones that keep state between comparisons
+/// (e.g. counting conflicts).
+pub struct MergeIter<T, I, C>
+    where T : Iterator<Item = I>, C : FnMut(&I, &I) -> MergeDecision {
     l: Peekable<T>,
     r: Peekable<T>,
-    comparator: fn(&I, &I) -> MergeDecision,
+    comparator: C,
+    /// The right value of a [MergeDecision::Both], waiting to be emitted
+    deferred: Option<I>,
 }
 
 pub fn kv_merge(left: &KV, right: &KV) -> MergeDecision {
@@ -35,15 +51,192 @@ pub fn result_merge<T, E>(maybe_left: &Result<T, E>, maybe_right: &Result<T, E>,
     match (maybe_left, maybe_right) {
         (Err(_), _) => MergeDecision::Left(false),
         (_, Err(_)) => MergeDecision::Right(false),
-        (Ok(left), Ok(right)) => (merger)(&left, &right),
+        (Ok(left), Ok(right)) => (merger)(left, right),
     }
 }
 
-impl <T, I> Iterator for MergeIter<T, I> 
-    where T : Iterator<Item = I> {
+/// Merges sources that are each sorted by key into one, preferring earlier sources when a key
+/// appears in more than one of them.
+pub fn merge_newest_first(sources: Vec<EntryIter>) -> EntryIter {
+    Box::new(NewestFirstMerge::new(sources))
+}
+
+/// Like [merge_newest_first], but leaving out keys whose newest entry is a tombstone. Errors
+/// are still let through.
+pub fn merge_newest_first_live(sources: Vec<EntryIter>) -> EntryIter {
+    Box::new(merge_newest_first(sources).filter(|entry| !matches!(entry, Ok(kv) if kv.tombstone)))
+}
+
+/// Like [merge_newest_first_live], but for entries that carry something alongside them (e.g.
+/// the size of a value that wasn't read)
+pub fn merge_newest_first_live_with<T: 'static>(sources: Vec<TaggedEntryIter<T>>) -> impl Iterator<Item = Result<(KV, T), TableErr>> {
+    NewestFirstMerge::new(sources).filter(|entry| !matches!(entry, Ok((kv, _)) if kv.tombstone))
+}
+
+/// Anything that can be merged by [NewestFirstMerge]
+trait Keyed {
+    fn kv(&self) -> &KV;
+}
+
+impl Keyed for KV {
+    fn kv(&self) -> &KV {
+        self
+    }
+}
+
+impl <T> Keyed for (KV, T) {
+    fn kv(&self) -> &KV {
+        &self.0
+    }
+}
+
+/// Merges sources that are each sorted by key, keeping only the earliest source's entry for
+/// each key. The sources' heads are kept in a heap ordered by key and then source, so each
+/// entry costs O(log k) however many tables are being read at once. Errors are passed on as
+/// soon as they're read.
+struct NewestFirstMerge<E: Keyed> {
+    sources: Vec<Box<dyn Iterator<Item = Result<E, TableErr>>>>,
+    /// The next entry from each source, if it's been read and not yet used
+    heads: Vec<Option<E>>,
+    /// The key of each head, along with the source it's from
+    heap: BinaryHeap<Reverse<(String, usize)>>,
+    /// Errors waiting to be returned, along with the source to read on from afterwards
+    errors: VecDeque<(TableErr, usize)>,
+}
+
+impl <E: Keyed> NewestFirstMerge<E> {
+    fn new(sources: Vec<Box<dyn Iterator<Item = Result<E, TableErr>>>>) -> NewestFirstMerge<E> {
+        let mut merge = NewestFirstMerge {
+            heads: sources.iter().map(|_| None).collect(),
+            sources,
+            heap: BinaryHeap::new(),
+            errors: VecDeque::new(),
+        };
+        for index in 0..merge.sources.len() {
+            merge.advance(index);
+        }
+
+        merge
+    }
+
+    /// Reads the next entry from the source into its head
+    fn advance(&mut self, index: usize) {
+        match self.sources[index].next() {
+            Some(Ok(entry)) => {
+                self.heap.push(Reverse((entry.kv().key.to_string(), index)));
+                self.heads[index] = Some(entry);
+            },
+            Some(Err(e)) => self.errors.push_back((e, index)),
+            None => {},
+        }
+    }
+}
+
+impl <E: Keyed> Iterator for NewestFirstMerge<E> {
+    type Item = Result<E, TableErr>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some((e, index)) = self.errors.pop_front() {
+            self.advance(index);
+            return Some(Err(e));
+        }
+
+        // Equal keys pop in source order, so this is the earliest source's entry
+        let Reverse((key, index)) = self.heap.pop()?;
+        let entry = self.heads[index].take().expect("Every source in the heap has a head");
+        // Older sources' entries for the same key are shadowed by it
+        while self.heap.peek().is_some_and(|Reverse((next_key, _))| *next_key == key) {
+            let Reverse((_, shadowed)) = self.heap.pop().expect("The heap was just peeked");
+            self.heads[shadowed] = None;
+            self.advance(shadowed);
+        }
+        self.advance(index);
+
+        Some(Ok(entry))
+    }
+}
+
+/// Merges any number of iterators in one pass. Sources earlier in the list are the left side
+/// of every comparison with later ones, so the comparator's tie-breaking favours them.
+///
+/// Each step compares the heads of all of the sources, so every item costs O(k). It's meant
+/// for merges that need their own comparator; merging tables newest first goes through
+/// [merge_newest_first], which keeps the heads in a heap instead.
+pub struct KWayMergeIter<T, I, C>
+    where T : Iterator<Item = I>, C : FnMut(&I, &I) -> MergeDecision {
+    sources: Vec<Peekable<T>>,
+    comparator: C,
+}
+
+impl <T, I, C> KWayMergeIter<T, I, C>
+    where T : Iterator<Item = I>, C : FnMut(&I, &I) -> MergeDecision {
+
+    pub fn new(sources: Vec<T>, comparator: C) -> KWayMergeIter<T, I, C> {
+        KWayMergeIter {
+            sources: sources.into_iter().map(|source| source.peekable()).collect(),
+            comparator,
+        }
+    }
+}
+
+impl <T, I, C> Iterator for KWayMergeIter<T, I, C>
+    where T : Iterator<Item = I>, C : FnMut(&I, &I) -> MergeDecision {
     type Item = I;
 
     fn next(&mut self) -> Option<Self::Item> {
+        let mut best: Option<usize> = None;
+        // Sources whose next value is dropped in favour of the best one's
+        let mut shadowed: Vec<usize> = Vec::new();
+
+        for index in 0..self.sources.len() {
+            // The best source always comes before the candidate, so the two can be split apart
+            let (earlier, later) = self.sources.split_at_mut(index);
+            let Some(candidate) = later[0].peek() else {
+                continue;
+            };
+            let Some(best_index) = best else {
+                best = Some(index);
+                continue;
+            };
+            let current = earlier[best_index].peek().expect("The best source should have a value");
+
+            match (self.comparator)(current, candidate) {
+                MergeDecision::Left(consume_right) => {
+                    if consume_right {
+                        shadowed.push(index);
+                    }
+                },
+                MergeDecision::Right(consume_left) => {
+                    if consume_left {
+                        shadowed.push(best_index);
+                    } else {
+                        // Whatever the old best shadowed isn't necessarily equal to this one
+                        shadowed.clear();
+                    }
+                    best = Some(index);
+                },
+                // The right value is still there to be picked on a later call
+                MergeDecision::Both | MergeDecision::None => {},
+            }
+        }
+
+        for index in shadowed {
+            self.sources[index].next();
+        }
+
+        best.and_then(|index| self.sources[index].next())
+    }
+}
+
+impl <T, I, C> Iterator for MergeIter<T, I, C>
+    where T : Iterator<Item = I>, C : FnMut(&I, &I) -> MergeDecision {
+    type Item = I;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(deferred) = self.deferred.take() {
+            return Some(deferred);
+        }
+
         let which = match (self.l.peek(), self.r.peek()) {
             (Some(left), Some(right)) => { (self.comparator)(left, right) },
             (Some(_), None) => MergeDecision::Left(false),
@@ -64,24 +257,34 @@ impl <T, I> Iterator for MergeIter<T, I>
                 }
                 self.r.next()
             },
+            MergeDecision::Both => {
+                self.deferred = self.r.next();
+                self.l.next()
+            },
             MergeDecision::None => { None },
         }
     }
 }
 
-impl <T, I> MergeIter<T, I>
-    where T : Iterator<Item = I> {
+impl <T, I, C> MergeIter<T, I, C>
+    where T : Iterator<Item = I>, C : FnMut(&I, &I) -> MergeDecision {
 
-    pub fn new(left: T, right: T, comparator: fn(&I, &I) -> MergeDecision) -> MergeIter<T, I> {
+    pub fn new(left: T, right: T, comparator: C) -> MergeIter<T, I, C> {
         MergeIter {
             l: left.peekable(),
             r: right.peekable(),
             comparator,
+            deferred: None,
         }
     }
 
+}
+
+impl <T, I> MergeIter<T, I, fn(&I, &I) -> MergeDecision>
+    where T : Iterator<Item = I> {
+
     /// Uses the order of T
-    pub fn default(left: T, right: T) -> MergeIter<T, I> 
+    pub fn default(left: T, right: T) -> MergeIter<T, I, fn(&I, &I) -> MergeDecision>
         where I : PartialOrd {
         MergeIter {
             l: left.peekable(),
@@ -92,7 +295,8 @@ impl <T, I> MergeIter<T, I>
                 } else {
                     MergeDecision::Right(false)
                 }
-            }
+            },
+            deferred: None,
         }
     }
 }
@@ -109,12 +313,118 @@ mod test {
         let merged: Vec<&i32> = MergeIter::default(left.iter(), right.iter()).collect();
 
 
-        let mut i = 0;
-        for merged_val in merged {
+        for (i, merged_val) in merged.into_iter().enumerate() {
             assert_eq!(expected[i], *merged_val);
-            i += 1;
         }
 
         Ok(())
     }
+
+    #[test]
+    fn keeps_both() -> Result<(), &'static str> {
+        let left = [(1, "l"), (3, "l"), (5, "l")];
+        let right = [(2, "r"), (3, "r"), (6, "r")];
+
+        let merged: Vec<_> = MergeIter::new(left.into_iter(), right.into_iter(), |left, right| {
+            if left.0 < right.0 {
+                MergeDecision::Left(false)
+            } else if left.0 > right.0 {
+                MergeDecision::Right(false)
+            } else {
+                MergeDecision::Both
+            }
+        }).collect();
+
+        assert_eq!(vec![(1, "l"), (2, "r"), (3, "l"), (3, "r"), (5, "l"), (6, "r")], merged);
+
+        Ok(())
+    }
+
+    #[test]
+    fn merges_many_preferring_earlier_sources() -> Result<(), &'static str> {
+        let sources = vec![
+            vec![(2, "newest"), (5, "newest")].into_iter(),
+            vec![(1, "middle"), (2, "middle"), (6, "middle")].into_iter(),
+            vec![(1, "oldest"), (2, "oldest"), (5, "oldest"), (7, "oldest")].into_iter(),
+        ];
+
+        let merged: Vec<_> = KWayMergeIter::new(sources, |left, right| {
+            if left.0 < right.0 {
+                MergeDecision::Left(false)
+            } else if left.0 > right.0 {
+                MergeDecision::Right(false)
+            } else {
+                MergeDecision::Left(true)
+            }
+        }).collect();
+
+        assert_eq!(vec![(1, "middle"), (2, "newest"), (5, "newest"), (6, "middle"), (7, "oldest")], merged);
+
+        Ok(())
+    }
+
+    #[test]
+    fn merges_many_tables_newest_first() -> Result<(), TableErr> {
+        // Each source overwrites every key that's a multiple of its index
+        let sources: Vec<EntryIter> = (1..=20).map(|source: u32| {
+            let entries: Vec<_> = (0..100).filter(|i| i % source == 0).map(|i| Ok(KV::new(format!("{:03}", i), source.to_string()))).collect();
+            Box::new(entries.into_iter()) as EntryIter
+        }).collect();
+
+        let merged = merge_newest_first(sources).collect::<Result<Vec<_>, _>>()?;
+        let expected: Vec<(String, String)> = (0..100).map(|i| {
+            let newest = (1..=20).find(|source| i % source == 0).expect("Every key is in source 1");
+            (format!("{:03}", i), newest.to_string())
+        }).collect();
+        assert_eq!(expected, merged.into_iter().map(|kv| (kv.key, kv.value)).collect::<Vec<_>>());
+
+        Ok(())
+    }
+
+    #[test]
+    fn passes_errors_through_newest_first_merges() {
+        let sources: Vec<EntryIter> = vec![
+            Box::new(vec![Ok(KV::new("b", "new")), Err(TableErr::BadFile(String::from("bad"))), Ok(KV::new("d", "new"))].into_iter()),
+            Box::new(vec![Ok(KV::new("a", "old")), Ok(KV::new("b", "old")), Ok(KV::tombstone("c"))].into_iter()),
+        ];
+
+        let merged: Vec<_> = merge_newest_first(sources).map(|entry| entry.map(|kv| (kv.key, kv.value, kv.tombstone))).collect();
+        assert!(matches!(merged[0], Ok((ref key, _, false)) if key == "a"));
+        assert!(matches!(merged[1], Ok((ref key, ref value, false)) if key == "b" && value == "new"));
+        assert!(matches!(merged[2], Err(TableErr::BadFile(_))));
+        assert!(matches!(merged[3], Ok((ref key, _, true)) if key == "c"));
+        assert!(matches!(merged[4], Ok((ref key, _, false)) if key == "d"));
+        assert_eq!(5, merged.len());
+    }
+
+    #[test]
+    fn merges_no_sources() -> Result<(), &'static str> {
+        let sources: Vec<std::vec::IntoIter<i32>> = Vec::new();
+        assert_eq!(0, KWayMergeIter::new(sources, |_, _| MergeDecision::None).count());
+
+        Ok(())
+    }
+
+    #[test]
+    fn accepts_stateful_comparators() -> Result<(), &'static str> {
+        let left = [1, 3, 4, 7];
+        let right = [2, 3, 4, 8];
+        let mut collisions = 0;
+
+        let merged: Vec<_> = MergeIter::new(left.iter(), right.iter(), |left, right| {
+            if left < right {
+                MergeDecision::Left(false)
+            } else if left > right {
+                MergeDecision::Right(false)
+            } else {
+                collisions += 1;
+                MergeDecision::Left(true)
+            }
+        }).collect();
+
+        assert_eq!(vec![&1, &2, &3, &4, &7, &8], merged);
+        assert_eq!(2, collisions);
+
+        Ok(())
+    }
 }
@@ -1,47 +1,462 @@
 use crate::io::table;
 use crate::io::table::TableErr;
 use crate::lsm::kv::KV;
-use crate::lsm::merge_iter::{ MergeIter, kv_merge, result_merge };
-use std::collections::{ VecDeque, HashMap };
+use crate::lsm::merge_iter::{ EntryIter, TaggedEntryIter, merge_newest_first_live, merge_newest_first_live_with };
+use std::collections::{ VecDeque, BTreeMap, HashSet };
 use std::fs;
+use std::io::{ self, Write };
+use std::panic::{ self, AssertUnwindSafe };
+use std::path::Path;
+use std::sync::{ Arc, Mutex, OnceLock, RwLock, Weak };
+use std::sync::mpsc::{ self, Sender };
+use std::thread::{ self, JoinHandle };
+use std::sync::atomic::{ AtomicBool, Ordering };
 
 pub struct LsmTree {
     name: String,
-    levels: Vec<LsmLevel>,
+    /// The current view of the tree's levels. Nothing ever mutates a view in place; writers
+    /// build a modified copy and swap it in, so a reader holding on to an older view can keep
+    /// probing its tables even while a compaction replaces them.
+    levels: RwLock<Arc<Vec<LsmLevel>>>,
+    /// Held while a new view is built and installed, so that only one writer is doing so at a
+    /// time. Compaction only takes it to install its result, not while it's merging.
+    writer: Mutex<()>,
+    /// Held for the whole of a compaction or table rewrite, so that nothing else replaces
+    /// tables below level 0 while a merge is running against an older view.
+    compaction: Mutex<()>,
+    /// Set for trees opened with [LsmTree::open_readonly]. Mutating operations check it before
+    /// touching anything on disk.
+    read_only: bool,
+    /// Passed on to [table::TableOptions::write_rate_limit] for tables written by compaction
+    compaction_rate_limit: Option<u64>,
+    /// Compacts after every `add`. Without it, nothing is compacted until `compact` is called.
+    auto_compact: bool,
+    /// Level n holds up to (n + 1) * scaling_factor tables before it's compacted
+    scaling_factor: u32,
+    /// Passed on to [table::TableOptions::durable] for every table the tree writes
+    durable: bool,
+    compaction_strategy: CompactionStrategy,
+    /// Set while compaction is running in the background. See
+    /// [LsmTree::start_background_compaction].
+    compactor: Mutex<Option<Compactor>>,
+    /// The last error (or panic) from background compaction, waiting to be returned by the
+    /// next `add`, `compact` or `stop_background_compaction`
+    background_error: Mutex<Option<TableErr>>,
 }
 
-#[derive(Debug)]
+/// The background compaction thread, along with the channel that tells it to compact. The
+/// thread stops once the sender is dropped and it's worked through the requests left behind.
+struct Compactor {
+    requests: Sender<()>,
+    handle: JoinHandle<()>,
+}
+
+/// How compaction picks the tables to merge out of a full level. Either way, it's always the
+/// level's oldest tables, so that everything left behind is still newer than what's merged
+/// into the next level.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum CompactionStrategy {
+    /// Merges the two oldest tables at a time
+    #[default]
+    Leveled,
+    /// Merges the oldest table along with every table after it that's of a similar size (see
+    /// [SIZE_TIER_RATIO]), so a run of equally sized flushes is merged all at once
+    SizeTiered,
+}
+
+impl CompactionStrategy {
+    /// How the strategy is recorded in the manifest
+    pub fn name(&self) -> &'static str {
+        match self {
+            CompactionStrategy::Leveled => "leveled",
+            CompactionStrategy::SizeTiered => "size_tiered",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<CompactionStrategy> {
+        [CompactionStrategy::Leveled, CompactionStrategy::SizeTiered].into_iter().find(|strategy| strategy.name() == name)
+    }
+}
+
+#[derive(Debug, Clone)]
 struct LsmLevel {
     id: String,
     count: u32,
-    tables: VecDeque<u32>,
+    tables: VecDeque<Arc<LsmTable>>,
     max_size: u32,  
 }
 
-fn kv_merge_iter<T>(l: T, r: T) -> MergeIter<T, Result<KV, TableErr>> 
-    where T : Iterator<Item = Result<KV, TableErr>> {
-    
-    MergeIter::new(l, r, |l, r| { result_merge(l, r, kv_merge) })
+/// A handle to a single table on disk. Handles are shared between every view that contains
+/// the table, so the files are only cleaned up once compaction has retired the table _and_
+/// the last view referencing it has been dropped.
+#[derive(Debug)]
+struct LsmTable {
+    name: String,
+    index: u32,
+    obsolete: AtomicBool,
+    /// The smallest and largest keys in the table, once they're known. Tables loaded from
+    /// before these were recorded don't have one.
+    key_range: OnceLock<(String, String)>,
+    /// The table's sparse index, read from disk by the first lookup that needs it. Tables are
+    /// never modified once written, so it never goes stale.
+    sparse_index: OnceLock<Option<table::SparseIndex>>,
+}
+
+/// Entries merged from the tables of a view. The iterator owns the view, so compaction can't
+/// delete the tables it's reading until it's dropped.
+struct ViewIter<I = EntryIter> {
+    merged: I,
+    /// Only held to keep the tables alive. Declared after `merged` so the table readers are
+    /// dropped first.
+    _view: Arc<Vec<LsmLevel>>,
 }
 
-const LEVEL_SCALING_FACTOR: u32 = 1;
+impl <I: Iterator> Iterator for ViewIter<I> {
+    type Item = I::Item;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.merged.next()
+    }
+}
+
+/// A read-only snapshot of a single level, for monitoring and tests
+#[derive(Debug, PartialEq)]
+pub struct LevelInfo {
+    pub index: usize,
+    /// Indices of the level's live tables, oldest first
+    pub table_indices: Vec<u32>,
+    pub max_size: u32,
+    /// The index of the most recently created table in the level
+    pub count: u32,
+}
+
+pub const DEFAULT_LEVEL_SCALING_FACTOR: u32 = 10;
+const RANGE_PREFIX: &str = "range:";
+const STRATEGY_PREFIX: &str = "strategy:";
+/// Tables are of a similar size if neither is more than this many times bigger than the other
+const SIZE_TIER_RATIO: u64 = 2;
+
+fn level_max_size(level_index: usize, scaling_factor: u32) -> u32 {
+    u32::try_from(level_index + 1).expect("Failed to convert usize -> u32") * scaling_factor
+}
 
 impl LsmTree {
     pub fn new(name: String) -> Result<LsmTree, TableErr> {
-        Ok(LsmTree {
+        Self::with_scaling_factor(name, DEFAULT_LEVEL_SCALING_FACTOR)
+    }
+
+    /// Like [LsmTree::new], but with level n holding up to (n + 1) * `scaling_factor` tables
+    /// instead of the default. Smaller factors compact sooner and more often.
+    pub fn with_scaling_factor(name: String, scaling_factor: u32) -> Result<LsmTree, TableErr> {
+        Self::with_strategy(name, scaling_factor, CompactionStrategy::default())
+    }
+
+    /// Like [LsmTree::with_scaling_factor], but compacting with the given strategy. The
+    /// strategy is recorded in the manifest, so loaded trees keep using it.
+    pub fn with_strategy(name: String, scaling_factor: u32, compaction_strategy: CompactionStrategy) -> Result<LsmTree, TableErr> {
+        if scaling_factor == 0 {
+            return Err(TableErr::InvalidArgument(String::from("The level scaling factor has to be at least 1")));
+        }
+        Ok(Self::from_levels(name, Vec::new(), scaling_factor, compaction_strategy))
+    }
+
+    fn from_levels(name: String, levels: Vec<LsmLevel>, scaling_factor: u32, compaction_strategy: CompactionStrategy) -> LsmTree {
+        LsmTree {
             name,
-            levels: Vec::new(),
-        })
+            levels: RwLock::new(Arc::new(levels)),
+            writer: Mutex::new(()),
+            compaction: Mutex::new(()),
+            read_only: false,
+            compaction_rate_limit: None,
+            auto_compact: true,
+            scaling_factor,
+            durable: false,
+            compaction_strategy,
+            compactor: Mutex::new(None),
+            background_error: Mutex::new(None),
+        }
+    }
+
+    /// Syncs every table the tree writes to disk before it's used. Off by default.
+    pub fn set_durable(&mut self, durable: bool) {
+        self.durable = durable;
+    }
+
+    /// Turns compacting after every `add` on or off. It's on by default.
+    pub fn set_auto_compact(&mut self, auto_compact: bool) {
+        self.auto_compact = auto_compact;
+    }
+
+    /// Caps how fast compaction writes, in bytes per second. Compactions are serialised, so
+    /// the limit applies to all of them together.
+    pub fn set_compaction_rate_limit(&mut self, bytes_per_sec: Option<u64>) {
+        self.compaction_rate_limit = bytes_per_sec;
+    }
+
+    /// Opens a read-only view of a database whose files have been copied into `dir`, e.g. for
+    /// point-in-time analysis of a snapshot. Reads work as usual, but `add` and `compact` are
+    /// rejected, so the snapshot can't be modified by accident.
+    pub fn open_readonly(dir: &Path) -> Result<LsmTree, TableErr> {
+        // The snapshot directory could have been copied from anywhere, so work out the db
+        // name from the manifest (or the table files, for older databases) rather than asking
+        // for it.
+        let mut db_names = HashSet::new();
+        let mut manifest_names = HashSet::new();
+        for entry in fs::read_dir(dir)? {
+            let file_name = entry?.file_name().to_string_lossy().to_string();
+            if let Some(db_name) = file_name.strip_suffix(".manifest") {
+                manifest_names.insert(db_name.to_string());
+            } else if let Some(table_name) = file_name.strip_suffix(".index") {
+                let parts: Vec<&str> = table_name.rsplitn(3, '-').collect();
+                if parts.len() == 3 {
+                    db_names.insert(parts[2].to_string());
+                }
+            }
+        }
+
+        if !manifest_names.is_empty() {
+            db_names = manifest_names;
+        }
+
+        if db_names.len() != 1 {
+            return Err(TableErr::BadFile(format!("Expected exactly one database in {}, found {:?}", dir.display(), db_names)));
+        }
+        let db_name = db_names.into_iter().next().expect("Set should contain exactly one name");
+
+        let mut tree = Self::load(&dir.join(db_name).display().to_string())?;
+        tree.read_only = true;
+
+        Ok(tree)
+    }
+
+    fn check_writable(&self) -> Result<(), TableErr> {
+        if self.read_only {
+            return Err(TableErr::ReadOnly(format!("{} was opened read-only", self.name)));
+        }
+
+        Ok(())
+    }
+
+    pub fn add(&self, in_data: impl IntoIterator<Item = KV>) -> Result<(), TableErr> {
+        self.check_writable()?;
+        self.take_background_error()?;
+        {
+            let _writer = self.writer.lock().expect("LSM tree writer lock was poisoned");
+            let mut levels = self.snapshot().as_ref().clone();
+
+            if levels.is_empty() {
+                self.add_level(&mut levels);
+            }
+            let new_table = levels[0].new_table();
+
+            let stats = table::flush_with_options(&new_table.name, in_data, &table::TableOptions { durable: self.durable, ..table::TableOptions::default() })?;
+            if let (Some(min), Some(max)) = (stats.min_key, stats.max_key) {
+                let _ = new_table.key_range.set((min, max));
+            }
+            self.install(levels)?;
+        }
+
+        // compact only takes the writer lock once it's ready to install
+        if self.auto_compact {
+            match self.compactor.lock().expect("LSM tree compactor lock was poisoned").as_ref() {
+                // The thread only stops once the sender's gone, so this can't fail
+                Some(compactor) => { let _ = compactor.requests.send(()); },
+                None => self.compact_levels()?,
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Moves the compaction that follows every `add` onto a background thread, so that `add`
+    /// returns as soon as its table is written. Reads carry on as usual in the meantime, since
+    /// compaction swaps in a new view rather than changing the one they're reading.
+    ///
+    /// The thread only holds a weak reference to the tree, so it doesn't keep it alive. It's
+    /// stopped by [LsmTree::stop_background_compaction], or when the tree is dropped. If a
+    /// compaction fails (or panics), the error is handed to the next `add`, `compact` or
+    /// `stop_background_compaction`, and later adds keep on requesting compactions.
+    pub fn start_background_compaction(tree: &Arc<LsmTree>) {
+        let mut compactor = tree.compactor.lock().expect("LSM tree compactor lock was poisoned");
+        if compactor.is_some() {
+            return;
+        }
+
+        let (requests, receiver) = mpsc::channel::<()>();
+        let tree: Weak<LsmTree> = Arc::downgrade(tree);
+        let handle = thread::spawn(move || {
+            while receiver.recv().is_ok() {
+                // One compaction catches up with any number of adds, so skip the rest of the queue
+                while receiver.try_recv().is_ok() {}
+
+                let Some(tree) = tree.upgrade() else {
+                    break;
+                };
+                let result = panic::catch_unwind(AssertUnwindSafe(|| tree.compact_levels()))
+                    .unwrap_or_else(|_| Err(TableErr::IO(format!("Background compaction of {} panicked", tree.name))));
+                if let Err(e) = result {
+                    *tree.background_error.lock().expect("LSM tree background error lock was poisoned") = Some(e);
+                }
+            }
+        });
+
+        *compactor = Some(Compactor { requests, handle });
+    }
+
+    /// Stops the background compaction thread once it's finished any compactions that have
+    /// already been requested. Later adds compact as they go again. Returns the error from
+    /// the last background compaction that failed, if nothing else has returned it yet.
+    pub fn stop_background_compaction(&self) -> Result<(), TableErr> {
+        let compactor = self.compactor.lock().expect("LSM tree compactor lock was poisoned").take();
+        if let Some(Compactor { requests, handle }) = compactor {
+            drop(requests);
+            // The thread can end up dropping the tree itself, and it can't wait on itself.
+            // Compactions are run under catch_unwind, so the thread itself never panics.
+            if handle.thread().id() != thread::current().id() {
+                let _ = handle.join();
+            }
+        }
+
+        self.take_background_error()
+    }
+
+    /// Returns (and clears) the last error from background compaction
+    fn take_background_error(&self) -> Result<(), TableErr> {
+        match self.background_error.lock().expect("LSM tree background error lock was poisoned").take() {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+
+    /// Describes the tree's current structure, one entry per level
+    pub fn levels(&self) -> Vec<LevelInfo> {
+        self.snapshot().iter().enumerate().map(|(index, level)| LevelInfo {
+            index,
+            table_indices: level.tables.iter().map(|lsm_table| lsm_table.index).collect(),
+            max_size: level.max_size,
+            count: level.count,
+        }).collect()
+    }
+
+    /// The number of level 0 tables waiting to be compacted. Nothing merges level 0 down on
+    /// its own, so this grows with every flush until `compact` runs.
+    pub fn compaction_backlog(&self) -> usize {
+        self.snapshot().first().map_or(0, |level| level.tables.len())
+    }
+
+    /// Returns the current view of the levels. The view stays valid (and its tables stay on
+    /// disk) for as long as the caller holds on to it.
+    fn snapshot(&self) -> Arc<Vec<LsmLevel>> {
+        Arc::clone(&self.levels.read().expect("LSM tree level lock was poisoned"))
+    }
+
+    /// Records the new view in the manifest and swaps it in. The manifest goes first, so once
+    /// the tables it replaces are retired there's nothing on disk that still refers to them.
+    fn install(&self, levels: Vec<LsmLevel>) -> Result<(), TableErr> {
+        self.write_manifest(&levels)?;
+        *self.levels.write().expect("LSM tree level lock was poisoned") = Arc::new(levels);
+
+        Ok(())
+    }
+
+    fn manifest_name(table_name: &str) -> String {
+        format!("{}.manifest", table_name)
+    }
+
+    /// The manifest records the scaling factor and `strategy:name`, then one line per level in
+    /// the form `level:count,max_size[,index...]`, with the indices of the level's tables
+    /// oldest first.
+    /// After those, `range:level,index,min,max` lines record the key range of each table that
+    /// has one, with the keys escaped as they are in table indices.
+    /// It's written to a temporary file and renamed into place so a crash can't leave half
+    /// of one behind.
+    fn write_manifest(&self, levels: &[LsmLevel]) -> Result<(), TableErr> {
+        let mut manifest = format!("scaling_factor:{}\n{}{}\n", self.scaling_factor, STRATEGY_PREFIX, self.compaction_strategy.name());
+        for (level_index, level) in levels.iter().enumerate() {
+            manifest.push_str(&format!("{}:{},{}", level_index, level.count, level.max_size));
+            for lsm_table in &level.tables {
+                manifest.push_str(&format!(",{}", lsm_table.index));
+            }
+            manifest.push('\n');
+        }
+        for (level_index, level) in levels.iter().enumerate() {
+            for lsm_table in &level.tables {
+                if let Some((min, max)) = lsm_table.key_range.get() {
+                    manifest.push_str(&format!("{}{},{},{},{}\n", RANGE_PREFIX, level_index, lsm_table.index, table::escape_key(min), table::escape_key(max)));
+                }
+            }
+        }
+
+        let manifest_name = Self::manifest_name(&self.name);
+        let temp_name = format!("{}.tmp", manifest_name);
+        let mut temp_file = fs::File::create(&temp_name)?;
+        temp_file.write_all(manifest.as_bytes())?;
+        // The manifest has to be on disk before the rename is, and the rename before anything
+        // that relies on it, like the WAL being cleared
+        if self.durable {
+            temp_file.sync_all()?;
+        }
+        fs::rename(&temp_name, &manifest_name)?;
+        if self.durable {
+            table::sync_dir(&manifest_name)?;
+        }
+
+        Ok(())
     }
 
-    pub fn add(&mut self, in_data: impl IntoIterator<Item = KV>) -> Result<(), TableErr> {
-        if self.levels.len() == 0 {
-            self.add_level();
+    /// Reads the scaling factor, strategy and levels back out of the manifest, or returns None
+    /// for databases that don't have one
+    fn read_manifest(table_name: &str) -> Result<Option<(u32, CompactionStrategy, Vec<LsmLevel>)>, TableErr> {
+        let manifest = match fs::read_to_string(Self::manifest_name(table_name)) {
+            Ok(manifest) => manifest,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let bad_manifest = || TableErr::BadFile(format!("The manifest for {} is malformed", table_name));
+
+        let mut lines = manifest.lines();
+        let scaling_factor = lines.next()
+            .and_then(|line| line.strip_prefix("scaling_factor:"))
+            .ok_or_else(bad_manifest)?
+            .parse()?;
+        // Manifests from before the strategy was recorded were all written by leveled trees
+        let mut lines = lines.peekable();
+        let compaction_strategy = match lines.next_if(|line| line.starts_with(STRATEGY_PREFIX)) {
+            Some(line) => CompactionStrategy::from_name(&line[STRATEGY_PREFIX.len()..]).ok_or_else(bad_manifest)?,
+            None => CompactionStrategy::Leveled,
+        };
+
+        let (range_lines, level_lines): (Vec<&str>, Vec<&str>) = lines.partition(|line| line.starts_with(RANGE_PREFIX));
+
+        let mut lsm_levels = Vec::new();
+        for (level_index, line) in level_lines.into_iter().enumerate() {
+            let (level_part, rest) = line.split_once(":").ok_or_else(bad_manifest)?;
+            if level_part.parse::<usize>()? != level_index {
+                return Err(bad_manifest());
+            }
+
+            let mut numbers = rest.split(",");
+            let count = numbers.next().ok_or_else(bad_manifest)?.parse()?;
+            let max_size = numbers.next().ok_or_else(bad_manifest)?.parse()?;
+            let indices = numbers.map(|index| index.parse()).collect::<Result<Vec<u32>, _>>()?;
+
+            lsm_levels.push(LsmLevel::existing(table_name, level_index, &indices, max_size, count));
+        }
+
+        for line in range_lines {
+            let parts: Vec<&str> = line[RANGE_PREFIX.len()..].split(",").collect();
+            let [level_index, index, min, max] = parts[..] else {
+                return Err(bad_manifest());
+            };
+            let (level_index, index): (usize, u32) = (level_index.parse()?, index.parse()?);
+
+            let lsm_table = lsm_levels.get(level_index)
+                .and_then(|level| level.tables.iter().find(|lsm_table| lsm_table.index == index))
+                .ok_or_else(bad_manifest)?;
+            let _ = lsm_table.key_range.set((table::unescape_key(min).into_owned(), table::unescape_key(max).into_owned()));
         }
-        let level = &mut self.levels[0];
-        let new_table_name = level.new_table();
 
-        table::flush(&new_table_name, in_data)
+        Ok(Some((scaling_factor, compaction_strategy, lsm_levels)))
     }
 
     /// The merge part of an LSM Tree. This is pretty inefficiently implemented for now, but
@@ -49,125 +464,393 @@ impl LsmTree {
     ///
     /// For the next implementation - this will all be much less confusing if the tables were
     /// stored in a struct with all of the methods in [table] hanging off of it.
-    fn compact(&mut self) -> Result<(), TableErr> {
-        for level_index in 0..self.levels.len() {
-            if !self.levels[0].full() {
-                return Ok(())
+    ///
+    /// Compaction merges against a copy of the current view without holding up writers, who
+    /// can keep adding to level 0 in the meantime. The merged tables are only retired once the
+    /// new view has been installed, and their files are only deleted once no reader is still
+    /// holding a view that references them.
+    pub fn compact(&self) -> Result<(), TableErr> {
+        self.take_background_error()?;
+        self.compact_levels()
+    }
+
+    /// [LsmTree::compact], without first returning any error left by background compaction
+    fn compact_levels(&self) -> Result<(), TableErr> {
+        self.check_writable()?;
+        let _compaction = self.compaction.lock().expect("LSM tree compaction lock was poisoned");
+        let mut levels = self.snapshot().as_ref().clone();
+        let mut retired = Vec::new();
+        let options = table::TableOptions { write_rate_limit: self.compaction_rate_limit, durable: self.durable, ..table::TableOptions::default() };
+
+        // levels grows as we go when the last level fills up, so this can't be a for over its range
+        let mut level_index = 0;
+        while level_index < levels.len() {
+            // It takes two tables to merge, however small the level's meant to be
+            if !levels[level_index].full() || levels[level_index].tables.len() < 2 {
+                level_index += 1;
+                continue;
             }
            
             // We need another level
-            if level_index + 1 >= self.levels.len() {
-                self.add_level();
+            if level_index + 1 >= levels.len() {
+                self.add_level(&mut levels);
             }
             
-            // A little confusing, admittedly, but return the two tables to be merged 
+            // A little confusing, admittedly, but return the oldest tables to be merged 
             // and create a new table in the next level to write to. Pass all of those
             // to the merger to actually perform the merge.
-            let compaction_candidates = self.levels[0].oldest().expect("Couldn't pull oldest from the old level");
-            let destination = self.levels[level_index + 1].new_table();
+            let compaction_candidates = levels[level_index].compaction_candidates(self.compaction_strategy)?;
+            // With nothing older below the destination, there's nothing left for tombstones to
+            // hide, so they can go
+            let bottom = levels[level_index + 1..].iter().all(|level| level.tables.is_empty());
+            let destination = levels[level_index + 1].new_table();
 
-            let _ = table::merge_and_flush(&compaction_candidates.0, &compaction_candidates.1, &destination)?; 
+            // Later tables are newer, so they take precedence
+            let source_names: Vec<&str> = compaction_candidates.iter().rev().map(|lsm_table| lsm_table.name.as_str()).collect();
+            if bottom {
+                table::merge_and_flush_dropping_tombstones(&source_names, &destination.name, &options)?;
+            } else {
+                table::merge_and_flush_with_options(&source_names, &destination.name, &options)?;
+            }
+            destination.set_merged_key_range(&compaction_candidates);
             
-            let _ = table::clean(&compaction_candidates.0)?;
-            let _ = table::clean(&compaction_candidates.1)?;
-        };
+            retired.extend(compaction_candidates);
+        }
+
+        if retired.is_empty() {
+            return Ok(());
+        }
+
+        self.install_merged(levels, retired)
+    }
+
+    /// Merges every table in the tree into a single table in the bottom level, dropping all of
+    /// the tombstones along the way. There's nothing below the bottom for a tombstone to hide,
+    /// so they can all go, along with the values they were hiding. Like `compact`, it merges
+    /// without holding up adds.
+    pub fn purge_tombstones(&self) -> Result<(), TableErr> {
+        self.check_writable()?;
+        let _compaction = self.compaction.lock().expect("LSM tree compaction lock was poisoned");
+        let mut levels = self.snapshot().as_ref().clone();
+
+        // Newest first, so that newer values win the merge
+        let mut retired = Vec::new();
+        for level in levels.iter_mut() {
+            retired.extend(level.tables.drain(..).rev());
+        }
+        if retired.is_empty() {
+            return Ok(());
+        }
+
+        // Level 0 is for flushes, so the result always goes at least one level down
+        if levels.len() < 2 {
+            self.add_level(&mut levels);
+        }
+        let bottom = levels.len() - 1;
+        let destination = levels[bottom].new_table();
+
+        let options = table::TableOptions { write_rate_limit: self.compaction_rate_limit, durable: self.durable, ..table::TableOptions::default() };
+        let source_names: Vec<&str> = retired.iter().map(|lsm_table| lsm_table.name.as_str()).collect();
+        table::merge_and_flush_dropping_tombstones(&source_names, &destination.name, &options)?;
+        destination.set_merged_key_range(&retired);
+
+        self.install_merged(levels, retired)
+    }
+
+    /// The number of tombstones in the tree's tables. Only the indices are read. A key that's
+    /// been deleted in several tables counts once for each of them.
+    pub fn tombstone_count(&self) -> Result<usize, TableErr> {
+        let mut count = 0;
+        for level in self.snapshot().iter() {
+            for lsm_table in &level.tables {
+                for entry in table::iterate_keys(&lsm_table.name)? {
+                    if entry?.tombstone {
+                        count += 1;
+                    }
+                }
+            }
+        }
+
+        Ok(count)
+    }
+
+    /// Installs the levels left by a merge against an older view, then retires the merged
+    /// tables
+    fn install_merged(&self, mut levels: Vec<LsmLevel>, retired: Vec<Arc<LsmTable>>) -> Result<(), TableErr> {
+        let _writer = self.writer.lock().expect("LSM tree writer lock was poisoned");
+        // Only adds can have happened since the copy was taken, and they only ever append to
+        // level 0. Everything below is exactly as the merge left it.
+        let current = self.snapshot();
+        let level_zero = &current[0];
+        levels[0].tables = level_zero.tables.iter()
+            .filter(|lsm_table| !retired.iter().any(|merged| Arc::ptr_eq(merged, lsm_table)))
+            .cloned()
+            .collect();
+        levels[0].count = level_zero.count;
+
+        self.install(levels)?;
+        for lsm_table in retired {
+            lsm_table.retire();
+        }
         
         Ok(())
     }
 
-    fn add_level(&mut self) {
-        let new_index = self.levels.len();
-        self.levels.push(LsmLevel { 
+    /// Replaces one of the tree's tables with a gap-free copy of itself (see [table::rewrite]).
+    /// The copy is written under a new name and swapped in through the manifest, so a crash
+    /// leaves either the old table or the new one in the tree, never a mix of their files. The
+    /// copy takes the original's place in the level, so it's no newer than it was.
+    pub fn rewrite_table(&self, level_index: usize, table_index: u32) -> Result<table::MergeStats, TableErr> {
+        self.check_writable()?;
+        let _compaction = self.compaction.lock().expect("LSM tree compaction lock was poisoned");
+        let _writer = self.writer.lock().expect("LSM tree writer lock was poisoned");
+        let mut levels = self.snapshot().as_ref().clone();
+        let missing = || TableErr::KeyNotFound(format!("{} has no table {} in level {}", self.name, table_index, level_index));
+
+        let level = levels.get_mut(level_index).ok_or_else(missing)?;
+        let position = level.tables.iter().position(|lsm_table| lsm_table.index == table_index).ok_or_else(missing)?;
+        level.count += 1;
+        let copy = Arc::new(LsmTable::new(level.table_name(level.count), level.count));
+        if let Some(key_range) = level.tables[position].key_range.get() {
+            let _ = copy.key_range.set(key_range.clone());
+        }
+
+        let options = table::TableOptions { durable: self.durable, ..table::TableOptions::default() };
+        let stats = table::rewrite(&level.tables[position].name, &copy.name, &options)?;
+        let original = std::mem::replace(&mut level.tables[position], copy);
+
+        self.install(levels)?;
+        original.retire();
+
+        Ok(stats)
+    }
+
+    /// Compacts only the keys in `[start, end)`, e.g. to keep a hot range from piling up
+    /// versions without rewriting the whole tree. Every entry in the range, from every level,
+    /// is merged into a single new table at the back of the bottom level. Nothing older is left
+    /// for them to hide, so tombstones in the range are dropped.
+    ///
+    /// Tables whose keys all fall outside the range are left exactly as they were. A table
+    /// that straddles the range is replaced in place by a copy of just its keys outside it, so
+    /// those keep their level and recency.
+    pub fn compact_range(&self, start: &str, end: &str) -> Result<(), TableErr> {
+        self.check_writable()?;
+        if start >= end {
+            return Ok(());
+        }
+        // Tables are replaced in place, like rewrite_table, so adds have to wait
+        let _compaction = self.compaction.lock().expect("LSM tree compaction lock was poisoned");
+        let _writer = self.writer.lock().expect("LSM tree writer lock was poisoned");
+        let mut levels = self.snapshot().as_ref().clone();
+        let options = table::TableOptions { write_rate_limit: self.compaction_rate_limit, durable: self.durable, ..table::TableOptions::default() };
+
+        // Newest first, so that newer values win the merge
+        let mut in_range: Vec<EntryIter> = Vec::new();
+        let mut retired = Vec::new();
+        for level in levels.iter_mut() {
+            for position in (0..level.tables.len()).rev() {
+                if !level.tables[position].overlaps(start, end) {
+                    continue;
+                }
+
+                let (inside, outside): (Vec<KV>, Vec<KV>) = table::iterate_entries(&level.tables[position].name)?
+                    .collect::<Result<Vec<_>, _>>()?
+                    .into_iter()
+                    .partition(|kv| start <= kv.key.as_str() && kv.key.as_str() < end);
+                if inside.is_empty() {
+                    continue;
+                }
+                in_range.push(Box::new(inside.into_iter().map(Ok)));
+
+                let original = if outside.is_empty() {
+                    level.tables.remove(position).expect("position is within the level")
+                } else {
+                    level.count += 1;
+                    let rest = Arc::new(LsmTable::new(level.table_name(level.count), level.count));
+                    let stats = table::flush_with_options(&rest.name, outside, &options)?;
+                    if let (Some(min), Some(max)) = (stats.min_key, stats.max_key) {
+                        let _ = rest.key_range.set((min, max));
+                    }
+                    std::mem::replace(&mut level.tables[position], rest)
+                };
+                retired.push(original);
+            }
+        }
+        if retired.is_empty() {
+            return Ok(());
+        }
+
+        let merged: Vec<KV> = merge_newest_first_live(in_range).collect::<Result<_, _>>()?;
+        // Everything in the range may have been deleted, leaving nothing to write
+        if !merged.is_empty() {
+            // Level 0 is for flushes, so the result always goes at least one level down
+            if levels.len() < 2 {
+                self.add_level(&mut levels);
+            }
+            let bottom = levels.len() - 1;
+            let destination = levels[bottom].new_table();
+            let stats = table::flush_with_options(&destination.name, merged, &options)?;
+            if let (Some(min), Some(max)) = (stats.min_key, stats.max_key) {
+                let _ = destination.key_range.set((min, max));
+            }
+        }
+
+        self.install(levels)?;
+        for lsm_table in retired {
+            lsm_table.retire();
+        }
+
+        Ok(())
+    }
+
+    fn add_level(&self, levels: &mut Vec<LsmLevel>) {
+        let new_index = levels.len();
+        levels.push(LsmLevel { 
             id: format!("{}-{}", self.name, new_index),
             count: 0,
             tables: VecDeque::new(), 
-            max_size: u32::try_from(new_index + 1).expect("Failed to convert usize -> u32") * LEVEL_SCALING_FACTOR 
+            max_size: level_max_size(new_index, self.scaling_factor),
         });
     }
 
     /// Loads a table from disk
     /// The abstraction isn't leak_ing_ here; it's leaked all over the floor and 
     /// I have no mop. Version two needs to encapsulate all of this _somewhere_.
-    fn load(table_name: &str) -> Result<LsmTree, TableErr> {
-        let files = Self::list_files(&table_name)?;
+    pub fn load(table_name: &str) -> Result<LsmTree, TableErr> {
+        let (scaling_factor, compaction_strategy, lsm_levels) = match Self::read_manifest(table_name)? {
+            Some(manifest) => manifest,
+            // Databases written before there was a manifest
+            None => (DEFAULT_LEVEL_SCALING_FACTOR, CompactionStrategy::default(), Self::levels_from_file_names(table_name)?),
+        };
+
+        #[cfg(debug_assertions)]
+        if let Some(level_zero) = lsm_levels.first() {
+            match level_zero.shared_keys() {
+                Ok(shared) if !shared.is_empty() => eprintln!(
+                    "{} level 0 tables of {} share keys (e.g. {}), so reads rely on their order having been recovered correctly",
+                    shared.len(), table_name, shared[0]
+                ),
+                Ok(_) => {},
+                // Reading the tables for real will run into the same problem and report it
+                Err(e) => eprintln!("Couldn't check the level 0 tables of {} for shared keys: {:?}", table_name, e),
+            }
+        }
+
+        Ok(Self::from_levels(table_name.to_string(), lsm_levels, scaling_factor, compaction_strategy))
+    }
+
+    /// Like [LsmTree::load], but fails if the tree was written with a different compaction
+    /// strategy. A tree's levels are shaped by its strategy, so it can't switch once written.
+    pub fn load_with_strategy(table_name: &str, compaction_strategy: CompactionStrategy) -> Result<LsmTree, TableErr> {
+        let tree = Self::load(table_name)?;
+        if tree.compaction_strategy != compaction_strategy {
+            return Err(TableErr::InvalidArgument(format!("{} uses the {} compaction strategy, not {}", table_name, tree.compaction_strategy.name(), compaction_strategy.name())));
+        }
+
+        Ok(tree)
+    }
+
+    /// Works out the levels from the names of the table files in the db's directory
+    fn levels_from_file_names(table_name: &str) -> Result<Vec<LsmLevel>, TableErr> {
+        let files = Self::list_files(table_name)?;
 
-        // Map of level to min and max index. Because we compact from the beginning,
-        // the remaining files will be contiguous.
-        let mut levels: HashMap<i32, (i32, i32)> = HashMap::new();
+        // Map of level to the indices of its tables. Indices are handed out in creation order
+        // within a level and never reused, so sorting them recovers which tables are newest.
+        let mut levels: BTreeMap<i32, Vec<u32>> = BTreeMap::new();
         for file in files {
             if file.ends_with(".index") {
                 let (level, index) = Self::parse_file_name(&file[0..(file.len() - 6)])?;
-
-                println!("Got level/index: {:?}, {:?}", level, index);
-                if !levels.contains_key(&level) {
-                    levels.insert(level, (index, index));
-                } else {
-                    let current_value = levels[&level];
-                    let lower = i32::min(current_value.0, index);
-                    let upper = i32::max(current_value.1, index);
-                    levels.insert(level, (lower, upper));
-                }
+                levels.entry(level).or_default().push(u32::try_from(index).expect("Failed to convert"));
             }
         }
 
         let mut lsm_levels: Vec<LsmLevel> = Vec::new();
-        for level_index in 0..levels.keys().len() {
-            println!("Populating level {:?}", level_index);
-
-            let (min, max) = levels[&i32::try_from(level_index).expect("Failed to convert")];
+        let level_count = levels.keys().max().map_or(0, |max_level| max_level + 1);
+        for level_index in 0..level_count {
+            // Levels can be empty, e.g. once level 0 has been compacted away
+            let mut indices = levels.remove(&level_index).unwrap_or_default();
+            indices.sort();
 
-            println!("  ({:?}, {:?})", min, max);
-            let max_u32 = u32::try_from(max).expect("Failed to convert");
-            let min_u32 = u32::try_from(min).expect("Failed to convert");
-            let tables = VecDeque::from((min_u32..(max_u32+1)).collect::<Vec<_>>());
-
-            println!("  Loaded tables: {:?}", tables);
-
-            lsm_levels.push(LsmLevel {
-                id: format!("{}-{}", table_name, level_index.to_string()),
-                max_size: u32::try_from(level_index + 1).expect("Failed to convert usize -> u32") * LEVEL_SCALING_FACTOR,
-                count: u32::try_from(max - min + 1).expect("Failed to convert"),
-                tables: tables
-            });
+            let level_index = usize::try_from(level_index).expect("Failed to convert i32 -> usize");
+            lsm_levels.push(LsmLevel::existing(
+                table_name,
+                level_index,
+                &indices,
+                level_max_size(level_index, DEFAULT_LEVEL_SCALING_FACTOR),
+                // New tables have to sort after every existing one, not just fill the gaps
+                indices.last().copied().unwrap_or(0),
+            ));
         }
 
-        println!("Creating tree with table name {}", table_name.to_string());
-        Ok(LsmTree { 
-            name: table_name.to_string(),
-            levels: lsm_levels,
-        })
+        Ok(lsm_levels)
     }
 
     /// Parses the file name to find the level and index of a given database file
-    /// File names look like `filename-level-name`
+    /// File names look like `filename-level-index`. The db name can contain dashes of its own,
+    /// so the level and index are always taken from the last two parts.
     fn parse_file_name(file_name: &str) -> Result<(i32, i32), TableErr> {
-        let dash_indices: Vec<_> = file_name.match_indices("-").collect();
-        if dash_indices.len () != 2 {
+        let parts: Vec<&str> = file_name.rsplitn(3, "-").collect();
+        if parts.len() != 3 || parts[2].is_empty() {
             return Err(TableErr::BadFile(String::from("File name should have level and index parts")));
         }
-        
-        println!("parsing file name {} with indices ({:?}, {:?})", file_name, dash_indices[0], dash_indices[1]);
-        println!("  {:?}", &file_name[dash_indices[0].0 + 1..dash_indices[1].0]);
-        println!("  {:?}", &file_name[dash_indices[1].0 + 1..file_name.len()]);
-        let level_part = file_name[dash_indices[0].0 + 1..dash_indices[1].0].parse()?;
-        let index_part = file_name[dash_indices[1].0 + 1..file_name.len()].parse()?;
+
+        let level_part = parts[1].parse()?;
+        let index_part = parts[0].parse()?;
 
         Ok((level_part, index_part))
     }
     
-    fn list_files<'a>(table_name: &'a str) -> Result<impl Iterator<Item = String> + 'a, TableErr> {
+    /// Lists the files that belong to the given db. Only files named exactly
+    /// `<name>-<level>-<index>.index` or `.data` match, so other databases whose names happen
+    /// to contain this one (and any other clutter in the directory) are left alone.
+    fn list_files(table_name: &str) -> Result<Vec<String>, TableErr> {
         let (path_part, name_part) = if table_name.contains("/") {
             let slash_indices: Vec<_> = table_name.match_indices("/").collect();
             let last_slash = slash_indices[slash_indices.len() - 1].0;
 
-            (&table_name[0..last_slash], &table_name[last_slash + 1..table_name.len() - 1])
+            (&table_name[0..last_slash], &table_name[last_slash + 1..table_name.len()])
         } else {
             ("./", table_name)
         };
 
-        let paths = fs::read_dir(path_part)?;
+        let mut files = Vec::new();
+        for path in fs::read_dir(path_part)? {
+            let entry = match path {
+                Ok(entry) => entry,
+                // Compaction can clean tables up while we're listing them
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e.into()),
+            };
+
+            if !Self::is_table_file(name_part, &entry.file_name().to_string_lossy()) {
+                continue;
+            }
+
+            // Follows symlinks, so this also catches table files that were listed but have
+            // since been cleaned up, or that point at nothing
+            match fs::metadata(entry.path()) {
+                Ok(_) => files.push(entry.path().display().to_string()),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e.into()),
+            }
+        }
+
+        Ok(files)
+    }
+
+    fn is_table_file(name: &str, file_name: &str) -> bool {
+        let Some(table_part) = file_name.strip_prefix(name).and_then(|rest| rest.strip_prefix("-")) else {
+            return false;
+        };
+        let Some((level_and_index, suffix)) = table_part.split_once(".") else {
+            return false;
+        };
+        let Some((level, index)) = level_and_index.split_once("-") else {
+            return false;
+        };
 
-        Ok(paths.map(|path| path.unwrap().path().display().to_string()).filter(move |path| path.contains(name_part)))
+        let is_number = |part: &str| !part.is_empty() && part.chars().all(|c| c.is_ascii_digit());
+        is_number(level) && is_number(index) && (suffix == "index" || suffix == "data")
     }
 }
 
@@ -176,35 +859,145 @@ pub trait Scan {
     fn read(&self, key: &str) -> Result<String, TableErr>;
 }
 
+impl LsmTable {
+    fn new(name: String, index: u32) -> LsmTable {
+        LsmTable {
+            name,
+            index,
+            obsolete: AtomicBool::new(false),
+            key_range: OnceLock::new(),
+            sparse_index: OnceLock::new(),
+        }
+    }
+
+    fn sparse_index(&self) -> Result<Option<&table::SparseIndex>, TableErr> {
+        if let Some(sparse_index) = self.sparse_index.get() {
+            return Ok(sparse_index.as_ref());
+        }
+
+        // Racing lookups may both read it, but only one copy is kept
+        let sparse_index = table::SparseIndex::load(&self.name)?;
+        Ok(self.sparse_index.get_or_init(|| sparse_index).as_ref())
+    }
+
+    /// Whether the key falls within the table's key range, and so might be in the table.
+    /// Tables without a recorded range might contain anything.
+    fn contains_range(&self, key: &str) -> bool {
+        self.key_range.get().is_none_or(|(min, max)| min.as_str() <= key && key <= max.as_str())
+    }
+
+    /// Whether the table might have keys in `[start, end)`. Tables without a recorded range
+    /// might have anything.
+    fn overlaps(&self, start: &str, end: &str) -> bool {
+        self.key_range.get().is_none_or(|(min, max)| min.as_str() < end && start <= max.as_str())
+    }
+
+    /// Sets the key range of a table merged from `sources` to cover all of theirs, if they all
+    /// have one
+    fn set_merged_key_range(&self, sources: &[Arc<LsmTable>]) {
+        let key_ranges: Option<Vec<_>> = sources.iter().map(|lsm_table| lsm_table.key_range.get()).collect();
+        let Some(key_ranges) = key_ranges else {
+            return;
+        };
+
+        if let (Some(min), Some(max)) = (key_ranges.iter().map(|(min, _)| min).min(), key_ranges.iter().map(|(_, max)| max).max()) {
+            let _ = self.key_range.set((min.to_string(), max.to_string()));
+        }
+    }
+
+    /// Marks the table's files for deletion once the last handle to it has been dropped
+    fn retire(&self) {
+        self.obsolete.store(true, Ordering::Release);
+    }
+}
+
+impl Drop for LsmTable {
+    fn drop(&mut self) {
+        if self.obsolete.load(Ordering::Acquire) {
+            if let Err(e) = table::clean(&self.name) {
+                eprintln!("Failed to clean up retired table {}: {:?}", self.name, e);
+            }
+        }
+    }
+}
+
 impl LsmLevel {
-    fn table_names<'a>(&'a self) -> impl IntoIterator<Item = String> + 'a {
-        let name = self.id.to_string();
+    fn table_names(&self) -> impl IntoIterator<Item = String> + '_ {
         // Iterate backwards because we want to check the newest tables first
-        self.tables.iter().map(move |index| { format!("{}-{}", name, index) })
+        self.tables.iter().rev().map(|lsm_table| lsm_table.name.to_string())
+    }
+
+    /// Keys that appear in more than one of the level's tables. That's expected in level 0,
+    /// but it means reads are only correct if the tables' recency was recovered correctly.
+    /// It reads every key in the level, so it's only built for debug builds, where `load`
+    /// uses it to flag loaded trees that depend on that.
+    #[cfg(debug_assertions)]
+    fn shared_keys(&self) -> Result<Vec<String>, TableErr> {
+        let mut seen = HashSet::new();
+        let mut shared = Vec::new();
+        for lsm_table in &self.tables {
+            for entry in table::iterate_keys(&lsm_table.name)? {
+                let key = entry?.key;
+                if seen.contains(&key) {
+                    if !shared.contains(&key) {
+                        shared.push(key);
+                    }
+                } else {
+                    seen.insert(key);
+                }
+            }
+        }
+
+        Ok(shared)
+    }
+
+    /// Rebuilds a level whose tables are already on disk, with `indices` oldest first
+    fn existing(table_name: &str, level_index: usize, indices: &[u32], max_size: u32, count: u32) -> LsmLevel {
+        let id = format!("{}-{}", table_name, level_index);
+        let tables: VecDeque<_> = indices.iter()
+            .map(|index| Arc::new(LsmTable::new(format!("{}-{}", id, index), *index)))
+            .collect();
+
+        LsmLevel { id, max_size, count, tables }
     }
 
     fn full(&self) -> bool {
         self.tables.len() >= usize::try_from(self.max_size).expect("Failed to convert u32 -> usize")
     }
 
-    fn oldest(&mut self) -> Result<(String, String), &'static str> {
+    /// Pops the oldest tables to compact out of the level, oldest first. There are always at
+    /// least two of them.
+    fn compaction_candidates(&mut self, strategy: CompactionStrategy) -> Result<Vec<Arc<LsmTable>>, TableErr> {
         if self.tables.len() < 2 {
-            return Err("Level is too small to compact from");
+            return Err(TableErr::InvalidArgument(format!("Level {} is too small to compact from", self.id)));
         }
 
-        let first = self.tables.pop_front().expect("Failed to pop despite vec being large enough");
-        let second = self.tables.pop_front().expect("Failed to pop despite vec being large enough");
+        let count = match strategy {
+            CompactionStrategy::Leveled => 2,
+            CompactionStrategy::SizeTiered => {
+                let oldest_size = table::size(&self.tables[0].name)?;
+                let mut count = 1;
+                for lsm_table in self.tables.iter().skip(1) {
+                    let size = table::size(&lsm_table.name)?;
+                    if size > oldest_size * SIZE_TIER_RATIO || oldest_size > size * SIZE_TIER_RATIO {
+                        break;
+                    }
+                    count += 1;
+                }
+                // The oldest two are merged regardless, so that the level always shrinks
+                count.max(2)
+            },
+        };
 
-        Ok((
-            self.table_name(first),
-            self.table_name(second),
-        ))
+        Ok(self.tables.drain(..count).collect())
     }
 
-    fn new_table(&mut self) -> String {
+    fn new_table(&mut self) -> Arc<LsmTable> {
         self.count += 1;
-        self.tables.push_back(self.count);
-        self.table_name(*self.tables.back().unwrap())
+        let new_table = Arc::new(LsmTable::new(self.table_name(self.count), self.count));
+        self.tables.push_back(Arc::clone(&new_table));
+
+        new_table
     }
 
     fn table_name(&self, index: u32) -> String {
@@ -214,12 +1007,15 @@ impl LsmLevel {
 
 impl Scan for LsmLevel {
     fn read(&self, key: &str) -> Result<String, TableErr> {
-        println!("Checking level {:?}", &self.id);
-        println!("  Level has {:?} tables", &self.table_names().into_iter().collect::<Vec<_>>().len());
-        for lsm_table in self.table_names() {
-            println!("Checking table {:?}", lsm_table);
-            if table::file_contains(&lsm_table, key)? {
-                return table::read(&lsm_table, key);
+        // Newest first, like table_names
+        for lsm_table in self.tables.iter().rev() {
+            if !lsm_table.contains_range(key) {
+                continue;
+            }
+
+            match table::read_indexed(&lsm_table.name, key, lsm_table.sparse_index()?) {
+                Err(TableErr::KeyNotFound(_)) => continue,
+                result => return result,
             }
         }
 
@@ -227,39 +1023,199 @@ impl Scan for LsmLevel {
     }
 }
 
-impl Scan for LsmTree {
-    fn read(&self, key: &str) -> Result<String, TableErr> {
-        println!("Checking levels: {:?}. This tree's name is {}", &self.levels, &self.name);
-        for level in &self.levels {
-            match level.read(key) {
-                Ok(value) => return Ok(value),
-                Err(e) => println!("{:?}", e),
-            }
+impl Drop for LsmTree {
+    fn drop(&mut self) {
+        if let Err(e) = self.stop_background_compaction() {
+            eprintln!("Background compaction of {} failed: {:?}", self.name, e);
         }
-        
-        Err(TableErr::KeyNotFound(key.to_string()))
     }
 }
 
-#[cfg(test)]
+impl Scan for LsmTree {
+    fn read(&self, key: &str) -> Result<String, TableErr> {
+        self.read_from(&self.snapshot(), key)
+    }
+}
+
+impl LsmTree {
+    /// Reads several keys from the same view of the tree, so that the results are consistent
+    /// with each other even if tables are added or compacted part way through. Missing keys
+    /// are `None`.
+    pub fn read_consistent(&self, keys: &[&str]) -> Result<Vec<Option<String>>, TableErr> {
+        let levels = self.snapshot();
+        keys.iter().map(|key| {
+            match self.read_from(&levels, key) {
+                Ok(value) => Ok(Some(value)),
+                Err(TableErr::KeyNotFound(_)) | Err(TableErr::KeyDeleted(_)) => Ok(None),
+                Err(e) => Err(e),
+            }
+        }).collect()
+    }
+
+    /// Whether the key has a live value anywhere in the tree. Only the indices are read, never
+    /// the value itself.
+    pub fn contains_key(&self, key: &str) -> Result<bool, TableErr> {
+        for level in self.snapshot().iter() {
+            for lsm_table in level.tables.iter().rev().filter(|lsm_table| lsm_table.contains_range(key)) {
+                // The newest record of the key decides, even if it's a tombstone
+                if let Some(live) = table::contains_live_indexed(&lsm_table.name, key, lsm_table.sparse_index()?)? {
+                    return Ok(live);
+                }
+            }
+        }
+
+        Ok(false)
+    }
+
+    /// Looks up several keys at once, returning their values in the same order as `keys`, with
+    /// `None` for keys that are missing or deleted. Each table is only read once, for all of the
+    /// keys that haven't been found in a newer one yet.
+    pub fn read_many(&self, keys: &[&str]) -> Result<Vec<Option<String>>, TableErr> {
+        let mut values = vec![None; keys.len()];
+        // Indices into keys that no table has had a record of so far
+        let mut pending: Vec<usize> = (0..keys.len()).collect();
+
+        for level in self.snapshot().iter() {
+            for lsm_table in level.tables.iter().rev() {
+                let wanted: Vec<&str> = pending.iter().map(|&i| keys[i]).filter(|key| lsm_table.contains_range(key)).collect();
+                if wanted.is_empty() {
+                    continue;
+                }
+
+                let found = table::read_many(&lsm_table.name, &wanted)?;
+                // The newest record of the key decides, even if it's a tombstone
+                pending.retain(|&i| match found.get(keys[i]) {
+                    Some(value) => {
+                        values[i] = value.clone();
+                        false
+                    },
+                    None => true,
+                });
+                if pending.is_empty() {
+                    return Ok(values);
+                }
+            }
+        }
+
+        Ok(values)
+    }
+
+    /// Iterates over every live entry in the tree in key order, with each key appearing once
+    /// with its newest value. Deleted keys are skipped. Tables are streamed and merged lazily, so only one entry per
+    /// table is held in memory at a time. The iterator holds on to the view it started from, so
+    /// compaction can't delete tables out from under it.
+    pub fn merged_iter(&self) -> Result<EntryIter, TableErr> {
+        self.merge_tables(|lsm_table| Ok(Box::new(table::iterate_entries(&lsm_table.name)?)))
+    }
+
+    /// Like [LsmTree::merged_iter], but only for keys in `[start, end)`. Tables whose key range
+    /// misses it aren't read at all. The rest seek to `start` through their sparse index and
+    /// stop at `end`, so the only values read are the ones that are returned (along with the
+    /// few index lines between the last sampled key and `start`).
+    pub fn scan_range(&self, start: &str, end: &str) -> Result<EntryIter, TableErr> {
+        let (start, end) = (start.to_string(), end.to_string());
+
+        self.merge_tables(move |lsm_table| {
+            if !lsm_table.overlaps(&start, &end) {
+                return Ok(Box::new(std::iter::empty()));
+            }
+
+            let end = end.clone();
+            // Errors are let through so the caller finds out about them
+            Ok(Box::new(table::iterate_entries_from(&lsm_table.name, &start, lsm_table.sparse_index()?)?
+                .take_while(move |entry| !matches!(entry, Ok(kv) if kv.key >= end))))
+        })
+    }
+
+    /// Every live key in the tree, in order and each only once. Only the index files are read,
+    /// never the values.
+    pub fn keys(&self) -> Result<impl Iterator<Item = Result<String, TableErr>>, TableErr> {
+        let merged = self.merge_tables(|lsm_table| Ok(Box::new(table::iterate_keys(&lsm_table.name)?)))?;
+
+        Ok(merged.map(|entry| entry.map(|kv| kv.key)))
+    }
+
+    /// Every live key in the tree, in order, with the size of its newest value. Like
+    /// [LsmTree::keys], only the index files are read.
+    pub fn value_sizes(&self) -> Result<impl Iterator<Item = Result<(String, usize), TableErr>>, TableErr> {
+        let levels = self.snapshot();
+
+        let mut tables: Vec<TaggedEntryIter<usize>> = Vec::new();
+        for level in levels.iter() {
+            for table_name in level.table_names() {
+                tables.push(Box::new(table::iterate_value_sizes(&table_name)?));
+            }
+        }
+
+        let merged = merge_newest_first_live_with(tables).map(|entry| entry.map(|(kv, size)| (kv.key, size)));
+        Ok(ViewIter { merged, _view: levels })
+    }
+
+    /// Reads every table's entries in the current view with `read_table`, then merges them
+    /// with newer tables shadowing older ones and drops the tombstones
+    fn merge_tables(&self, read_table: impl Fn(&LsmTable) -> Result<EntryIter, TableErr>) -> Result<EntryIter, TableErr> {
+        let levels = self.snapshot();
+
+        let mut tables: Vec<EntryIter> = Vec::new();
+        for level in levels.iter() {
+            // Newest first, like table_names
+            for lsm_table in level.tables.iter().rev() {
+                tables.push(read_table(lsm_table)?);
+            }
+        }
+
+        Ok(Box::new(ViewIter { merged: Box::new(merge_newest_first_live(tables)), _view: levels }))
+    }
+
+    fn read_from(&self, levels: &[LsmLevel], key: &str) -> Result<String, TableErr> {
+        for level in levels.iter() {
+            match level.read(key) {
+                Ok(value) => return Ok(value),
+                // The newest record is a tombstone, so older levels' values don't count
+                Err(TableErr::KeyDeleted(deleted)) => return Err(TableErr::KeyDeleted(deleted)),
+                Err(TableErr::KeyNotFound(_)) => continue,
+                // Anything else, like a corrupt value, can't be papered over with an older one
+                Err(e) => return Err(e),
+            }
+        }
+        
+        Err(TableErr::KeyNotFound(key.to_string()))
+    }
+}
+
+#[cfg(test)]
 mod test {
     use crate::lsm::tree::*;
     use std::fs;
+    use std::path::Path;
+    use std::sync::atomic::AtomicBool;
+    use std::thread;
+    use std::time::{ Duration, Instant };
+    #[test]
+    fn compaction_keeps_newest_value() -> Result<(), TableErr> {
+        let tree = LsmTree::with_scaling_factor(String::from("test_files/compaction_precedence_test"), 1)?;
+        tree.add(vec![KV::new("a", "old")])?;
+        tree.add(vec![KV::new("a", "new")])?;
+        tree.compact()?;
+
+        assert_eq!(0, tree.compaction_backlog());
+        assert_eq!("new", tree.read("a")?);
+
+        Ok(())
+    }
+
     #[test]
     fn compacts() -> Result<(), TableErr> {
-        let mut tree = LsmTree { 
-            levels: Vec::new(),
-            name: String::from("test_files/lsm_test"),
-        };
+        let tree = LsmTree::new(String::from("test_files/lsm_test"))?;
 
-        let _ = tree.add(vec![
-                 KV { key: String::from("a"), value: 50.to_string() },
-                 KV { key: String::from("c"), value: 10512.to_string() },
+        tree.add(vec![
+                 KV::new("a", 50.to_string()),
+                 KV::new("c", 10512.to_string()),
         ])?;
 
-        let _ = tree.add(vec![
-                 KV { key: String::from("b"), value: 12.to_string() },
-                 KV { key: String::from("e"), value: 125.to_string() },
+        tree.add(vec![
+                 KV::new("b", 12.to_string()),
+                 KV::new("e", 125.to_string()),
         ])?;
 
         let _ = tree.compact(); 
@@ -278,19 +1234,16 @@ mod test {
     
     #[test]
     fn loads() -> Result<(), TableErr> {
-        let mut tree = LsmTree { 
-            levels: Vec::new(),
-            name: String::from("test_files/load_test"),
-        };
+        let tree = LsmTree::new(String::from("test_files/load_test"))?;
 
-        let _ = tree.add(vec![
-                 KV { key: String::from("a"), value: 50.to_string() },
-                 KV { key: String::from("c"), value: 10512.to_string() },
+        tree.add(vec![
+                 KV::new("a", 50.to_string()),
+                 KV::new("c", 10512.to_string()),
         ])?;
 
-        let _ = tree.add(vec![
-                 KV { key: String::from("b"), value: 12.to_string() },
-                 KV { key: String::from("e"), value: 125.to_string() },
+        tree.add(vec![
+                 KV::new("b", 12.to_string()),
+                 KV::new("e", 125.to_string()),
         ])?;
 
         let loaded_tree: LsmTree = LsmTree::load("test_files/load_test")?;
@@ -302,6 +1255,849 @@ mod test {
         assert_eq!(b_value, "12".to_string());
 
 
+        Ok(())
+    }
+
+    #[test]
+    fn reads_during_compaction() -> Result<(), TableErr> {
+        let tree = Arc::new(LsmTree::new(String::from("test_files/concurrent_compaction_test"))?);
+        let first_batch = || vec![
+            KV::new("a", "apple"),
+            KV::new("b", "banana"),
+        ];
+        let second_batch = || vec![
+            KV::new("c", "cherry"),
+            KV::new("d", "date"),
+        ];
+
+        tree.add(first_batch())?;
+        tree.add(second_batch())?;
+
+        let done = Arc::new(AtomicBool::new(false));
+        let readers: Vec<_> = (0..4).map(|_| {
+            let tree = Arc::clone(&tree);
+            let done = Arc::clone(&done);
+
+            thread::spawn(move || {
+                while !done.load(Ordering::Acquire) {
+                    for (key, value) in [("a", "apple"), ("b", "banana"), ("c", "cherry"), ("d", "date")] {
+                        match tree.read(key) {
+                            Ok(read_value) => assert_eq!(value, read_value),
+                            Err(e) => panic!("Failed to read {} during compaction: {:?}", key, e),
+                        }
+                    }
+                }
+            })
+        }).collect();
+
+        // Every round re-adds the same keys to level 0 and then compacts them away, so
+        // readers are constantly racing against tables being merged and retired.
+        for _ in 0..20 {
+            tree.compact()?;
+            tree.add(first_batch())?;
+            tree.add(second_batch())?;
+        }
+
+        done.store(true, Ordering::Release);
+        for reader in readers {
+            reader.join().expect("A reader observed a missing table");
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn opens_readonly() -> Result<(), TableErr> {
+        let dir = Path::new("test_files/readonly_snapshot");
+        let _ = fs::remove_dir_all(dir);
+        fs::create_dir_all(dir)?;
+
+        let tree = LsmTree::new(String::from("test_files/readonly_snapshot/snapshot"))?;
+        tree.add(vec![
+                 KV::new("a", 50.to_string()),
+                 KV::new("c", 10512.to_string()),
+        ])?;
+        tree.add(vec![
+                 KV::new("b", 12.to_string()),
+                 KV::new("e", 125.to_string()),
+        ])?;
+
+        let list_dir = || -> Result<Vec<String>, TableErr> {
+            let mut files = fs::read_dir(dir)?
+                .map(|entry| Ok(entry?.file_name().to_string_lossy().to_string()))
+                .collect::<Result<Vec<_>, TableErr>>()?;
+            files.sort();
+            Ok(files)
+        };
+        let files_before = list_dir()?;
+
+        let snapshot = LsmTree::open_readonly(dir)?;
+
+        assert_eq!(snapshot.read("a")?, "50".to_string());
+        assert_eq!(snapshot.read("e")?, "125".to_string());
+
+        assert!(matches!(
+            snapshot.add(vec![KV::new("z", "zebra")]),
+            Err(TableErr::ReadOnly(_))
+        ));
+        assert!(matches!(snapshot.compact(), Err(TableErr::ReadOnly(_))));
+        assert_eq!(files_before, list_dir()?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn describes_levels() -> Result<(), TableErr> {
+        let tree = LsmTree::with_scaling_factor(String::from("test_files/levels_test"), 1)?;
+        assert!(tree.levels().is_empty());
+
+        for batch in [["a", "c"], ["b", "e"], ["d", "f"]] {
+            tree.add(batch.map(|key| KV::new(key.to_string(), key.to_string())))?;
+        }
+        tree.compact()?;
+
+        assert_eq!(vec![
+            LevelInfo { index: 0, table_indices: vec![3], max_size: 1, count: 3 },
+            LevelInfo { index: 1, table_indices: vec![1], max_size: 2, count: 1 },
+        ], tree.levels());
+
+        Ok(())
+    }
+
+    #[test]
+    fn loads_ignoring_unrelated_files() -> Result<(), TableErr> {
+        let dir = Path::new("test_files/robust_load");
+        let _ = fs::remove_dir_all(dir);
+        fs::create_dir_all(dir)?;
+
+        let mut tree = LsmTree::new(String::from("test_files/robust_load/robust"))?;
+        tree.set_auto_compact(false);
+        tree.add(vec![
+                 KV::new("a", 50.to_string()),
+                 KV::new("c", 10512.to_string()),
+        ])?;
+        tree.add(vec![
+                 KV::new("b", 12.to_string()),
+                 KV::new("e", 125.to_string()),
+        ])?;
+
+        // Another db whose name contains ours and stray files
+        fs::write(dir.join("other-robust-0-1.index"), "a:0,1")?;
+        fs::write(dir.join("robust-0-1.index.bak"), "not an index")?;
+        // Without a manifest, load has to work out the tables from the file names
+        fs::remove_file(dir.join("robust.manifest"))?;
+
+        let loaded_tree = LsmTree::load("test_files/robust_load/robust")?;
+
+        assert_eq!(vec![1, 2], loaded_tree.levels()[0].table_indices);
+        assert_eq!(loaded_tree.read("a")?, "50".to_string());
+        assert_eq!(loaded_tree.read("e")?, "125".to_string());
+
+        Ok(())
+    }
+
+    #[test]
+    fn reads_newest_level_zero_table_after_reload() -> Result<(), TableErr> {
+        let dir = Path::new("test_files/recency");
+        let _ = fs::remove_dir_all(dir);
+        fs::create_dir_all(dir)?;
+        let name = "test_files/recency/recency";
+        let kv = |value: &str| vec![KV::new("a", value.to_string())];
+
+        // Level 0 fills at two tables, but only compacts when asked to
+        let mut tree = LsmTree::with_scaling_factor(String::from(name), 2)?;
+        tree.set_auto_compact(false);
+        tree.add(kv("first"))?;
+        tree.add(kv("second"))?;
+        tree.compact()?;
+        tree.add(kv("third"))?;
+        assert_eq!(tree.read("a")?, "third");
+
+        // Level 0 now only holds table 3, so the next table has to be numbered after it
+        let mut tree = LsmTree::load(name)?;
+        tree.set_auto_compact(false);
+        tree.add(kv("fourth"))?;
+        assert_eq!(tree.read("a")?, "fourth");
+
+        let tree = LsmTree::load(name)?;
+        assert_eq!(tree.read("a")?, "fourth");
+        assert_eq!(vec![3, 4], tree.levels()[0].table_indices);
+        #[cfg(debug_assertions)]
+        assert_eq!(vec![String::from("a")], tree.snapshot()[0].shared_keys()?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn reads_consistently_during_writes() -> Result<(), TableErr> {
+        let tree = Arc::new(LsmTree::new(String::from("test_files/consistent_read_test"))?);
+        let generation = |i: usize| vec![
+            KV::new("a", format!("a{}", i)),
+            KV::new("b", format!("b{}", i)),
+        ];
+        tree.add(generation(0))?;
+
+        let done = Arc::new(AtomicBool::new(false));
+        let readers: Vec<_> = (0..4).map(|_| {
+            let tree = Arc::clone(&tree);
+            let done = Arc::clone(&done);
+
+            thread::spawn(move || {
+                while !done.load(Ordering::Acquire) {
+                    let values = tree.read_consistent(&["a", "b", "c"]).expect("Consistent read failed");
+                    let a = values[0].as_ref().expect("a should exist");
+                    let b = values[1].as_ref().expect("b should exist");
+                    // Both keys are always written in the same table, so they have to agree
+                    assert_eq!(a[1..], b[1..]);
+                    assert_eq!(None, values[2]);
+                }
+            })
+        }).collect();
+
+        for i in 1..20 {
+            tree.add(generation(i))?;
+        }
+
+        done.store(true, Ordering::Release);
+        for reader in readers {
+            reader.join().expect("A reader saw an inconsistent view");
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn limits_compaction_rate() -> Result<(), TableErr> {
+        let mut tree = LsmTree::with_scaling_factor(String::from("test_files/rate_limit_test"), 1)?;
+        let bytes_per_sec = 200_000;
+        tree.set_compaction_rate_limit(Some(bytes_per_sec));
+
+        let batch = |prefix: &str| (0..50).map(|i| KV::new(format!("{}{:02}", prefix, i), "x".repeat(1000))).collect::<Vec<_>>();
+        tree.add(batch("a"))?;
+
+        // The second table fills level 0, so adding it compacts
+        let start = Instant::now();
+        tree.add(batch("b"))?;
+        let elapsed = start.elapsed();
+
+        let written = fs::metadata("test_files/rate_limit_test-1-1.data")?.len();
+        assert_eq!(100_000, written);
+        assert!(elapsed >= Duration::from_secs_f64(written as f64 / bytes_per_sec as f64), "Compaction took {:?}", elapsed);
+
+        assert_eq!("x".repeat(1000), tree.read("a00")?);
+        assert_eq!("x".repeat(1000), tree.read("b49")?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn adds_while_compacting() -> Result<(), TableErr> {
+        let mut tree = LsmTree::with_scaling_factor(String::from("test_files/add_while_compacting_test"), 1)?;
+        tree.set_auto_compact(false);
+        tree.set_compaction_rate_limit(Some(200_000));
+
+        let batch = |prefix: &str| (0..50).map(|i| KV::new(format!("{}{:02}", prefix, i), "x".repeat(1000))).collect::<Vec<_>>();
+        tree.add(batch("a"))?;
+        tree.add(batch("b"))?;
+
+        thread::scope(|scope| -> Result<(), TableErr> {
+            let compaction = scope.spawn(|| tree.compact());
+            // Wait for the compaction to get going, which is once it's holding its lock
+            while tree.compaction.try_lock().is_ok() {
+                thread::yield_now();
+            }
+
+            tree.add(vec![KV::new("c", "c1")])?;
+            // The merge is rate limited to take half a second, so it's still holding the lock
+            // if the add didn't wait for it
+            assert!(tree.compaction.try_lock().is_err());
+
+            compaction.join().expect("Compaction panicked")
+        })?;
+
+        let levels = tree.snapshot();
+        assert_eq!(1, levels[0].tables.len());
+        assert_eq!(1, levels[1].tables.len());
+        assert_eq!("c1", tree.read("c")?);
+        assert_eq!("x".repeat(1000), tree.read("a00")?);
+        assert_eq!("x".repeat(1000), tree.read("b49")?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn iterates_merged_levels() -> Result<(), TableErr> {
+        let tree = LsmTree::new(String::from("test_files/merged_iter_test"))?;
+        let kv = |key: &str, value: &str| KV::new(key.to_string(), value.to_string());
+
+        tree.add(vec![kv("a", "old"), kv("c", "c1")])?;
+        tree.add(vec![kv("b", "b1"), kv("d", "d1")])?;
+        tree.compact()?;
+        tree.add(vec![kv("a", "new"), kv("e", "e1")])?;
+
+        let merged: Vec<(String, String)> = tree.merged_iter()?
+            .map(|entry| entry.map(|kv| (kv.key, kv.value)))
+            .collect::<Result<_, _>>()?;
+
+        let expected: Vec<(String, String)> = [("a", "new"), ("b", "b1"), ("c", "c1"), ("d", "d1"), ("e", "e1")].iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect();
+        assert_eq!(expected, merged);
+
+        Ok(())
+    }
+
+    #[test]
+    fn reports_corrupt_values() -> Result<(), TableErr> {
+        let tree = LsmTree::new(String::from("test_files/corrupt_tree_test"))?;
+        tree.add(vec![KV::new("a", "older")])?;
+        tree.add(vec![KV::new("a", "newer")])?;
+
+        let data_file_name = "test_files/corrupt_tree_test-0-2.data";
+        let mut data = fs::read(data_file_name)?;
+        data[0] ^= 0x01;
+        fs::write(data_file_name, data)?;
+
+        // Neither the corrupt value nor the older one it shadows is returned
+        assert!(matches!(tree.read("a"), Err(TableErr::BadFile(_))));
+
+        Ok(())
+    }
+
+    #[test]
+    fn keeps_sparse_indices_in_memory() -> Result<(), TableErr> {
+        let tree = LsmTree::new(String::from("test_files/cached_sparse_test"))?;
+        tree.add((0..10_000).map(|i| KV::new(format!("key{:05}", i), i.to_string())))?;
+        assert_eq!("5000", tree.read("key05000")?);
+
+        // Without its sparse index, a lookup would have to scan through the broken first line
+        let name = "test_files/cached_sparse_test-0-1";
+        fs::remove_file(format!("{}.sparse", name))?;
+        let index_name = format!("{}.index", name);
+        let mut index = fs::read(&index_name)?;
+        let first_line_len = index.iter().position(|b| *b == b'\n').expect("Index should have several lines");
+        index[..first_line_len].fill(0xFF);
+        fs::write(&index_name, index)?;
+
+        assert_eq!("9999", tree.read("key09999")?);
+        assert!(tree.contains_key("key00042")?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn compacts_after_adding() -> Result<(), TableErr> {
+        let tree = LsmTree::with_scaling_factor(String::from("test_files/auto_compact_test"), 1)?;
+        let batch = |key: &str| vec![KV::new(key.to_string(), key.to_string())];
+
+        tree.add(batch("a"))?;
+        assert_eq!(vec![1], tree.levels()[0].table_indices);
+
+        // Level 0 only holds one table, so the second one pushes it over
+        tree.add(batch("b"))?;
+        let levels = tree.levels();
+        assert!(levels[0].table_indices.is_empty());
+        assert_eq!(vec![1], levels[1].table_indices);
+        assert_eq!("a", tree.read("a")?);
+        assert_eq!("b", tree.read("b")?);
+
+        let mut manual = LsmTree::new(String::from("test_files/manual_compact_test"))?;
+        manual.set_auto_compact(false);
+        manual.add(batch("a"))?;
+        manual.add(batch("b"))?;
+        assert_eq!(2, manual.compaction_backlog());
+
+        Ok(())
+    }
+
+    #[test]
+    fn cascades_compaction_through_levels() -> Result<(), TableErr> {
+        let tree = LsmTree::with_scaling_factor(String::from("test_files/cascade_test"), 1)?;
+        let batch = |i: usize| vec![
+            KV::new(format!("k{}", i), i.to_string()),
+            KV::new("shared", i.to_string()),
+        ];
+
+        // Every two adds make a table in level 1, and the third of those pushes level 1 over
+        for i in 0..6 {
+            tree.add(batch(i))?;
+        }
+
+        let levels = tree.levels();
+        assert!(levels[0].table_indices.is_empty());
+        assert_eq!(vec![3], levels[1].table_indices);
+        assert_eq!(vec![1], levels[2].table_indices);
+
+        for i in 0..6 {
+            assert_eq!(i.to_string(), tree.read(&format!("k{}", i))?);
+        }
+        assert_eq!("5", tree.read("shared")?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn scales_level_sizes() -> Result<(), TableErr> {
+        let tree = LsmTree::with_scaling_factor(String::from("test_files/scaling_test"), 4)?;
+        let batch = |i: usize| vec![KV::new(format!("k{}", i), i.to_string())];
+
+        for i in 0..3 {
+            tree.add(batch(i))?;
+        }
+        assert_eq!(vec![1, 2, 3], tree.levels()[0].table_indices);
+        assert_eq!(4, tree.levels()[0].max_size);
+
+        // The fourth table fills level 0
+        tree.add(batch(3))?;
+        let levels = tree.levels();
+        assert_eq!(vec![3, 4], levels[0].table_indices);
+        assert_eq!(vec![1], levels[1].table_indices);
+        assert_eq!(8, levels[1].max_size);
+
+        assert!(matches!(LsmTree::with_scaling_factor(String::from("test_files/scaling_test"), 0), Err(TableErr::InvalidArgument(_))));
+
+        Ok(())
+    }
+
+    #[test]
+    fn loads_from_manifest() -> Result<(), TableErr> {
+        let dir = Path::new("test_files/manifest");
+        let _ = fs::remove_dir_all(dir);
+        fs::create_dir_all(dir)?;
+        let name = String::from("test_files/manifest/my-db");
+        let batch = |i: usize| vec![KV::new(format!("k{}", i), i.to_string())];
+
+        let tree = LsmTree::with_scaling_factor(name.clone(), 1)?;
+        for i in 0..3 {
+            tree.add(batch(i))?;
+        }
+
+        // The dash in the name would throw off parsing the table file names
+        let loaded_tree = LsmTree::load(&name)?;
+        assert_eq!(tree.levels(), loaded_tree.levels());
+        for i in 0..3 {
+            assert_eq!(i.to_string(), loaded_tree.read(&format!("k{}", i))?);
+        }
+
+        // The scaling factor comes back too, so the next add compacts just as it would have,
+        // filling level 1 and cascading into level 2
+        loaded_tree.add(batch(3))?;
+        let levels = loaded_tree.levels();
+        assert!(levels[0].table_indices.is_empty());
+        assert!(levels[1].table_indices.is_empty());
+        assert_eq!(vec![1], levels[2].table_indices);
+
+        let snapshot = LsmTree::open_readonly(dir)?;
+        assert_eq!("3", snapshot.read("k3")?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn parses_file_names_with_dashes() -> Result<(), TableErr> {
+        assert_eq!((1, 12), LsmTree::parse_file_name("plaindb-1-12")?);
+        assert_eq!((0, 3), LsmTree::parse_file_name("test_files/my-db-0-3")?);
+        assert_eq!((2, 1), LsmTree::parse_file_name("a-b-c-db-2-1")?);
+        assert!(LsmTree::parse_file_name("plaindb-1").is_err());
+        assert!(LsmTree::parse_file_name("-1-2").is_err());
+
+        for name in ["plaindb", "my-db", "a-b-c-db"] {
+            let dir = format!("test_files/dashes/{}", name);
+            let _ = fs::remove_dir_all(&dir);
+            fs::create_dir_all(&dir)?;
+            let db_name = format!("{}/{}", dir, name);
+
+            // Written without a manifest, so loading has to go by the file names
+            table::flush(&format!("{}-0-4", db_name), vec![KV::new("a", "new")])?;
+            table::flush(&format!("{}-1-2", db_name), vec![
+                KV::new("a", "old"),
+                KV::new("b", "old"),
+            ])?;
+
+            let tree = LsmTree::load(&db_name)?;
+            let levels = tree.levels();
+            assert_eq!(vec![4], levels[0].table_indices);
+            assert_eq!(vec![2], levels[1].table_indices);
+            assert_eq!("new", tree.read("a")?);
+            assert_eq!("old", tree.read("b")?);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn lists_files_for_path_qualified_names() -> Result<(), TableErr> {
+        let dir = Path::new("test_files/listing");
+        let _ = fs::remove_dir_all(dir);
+        fs::create_dir_all(dir)?;
+        for file_name in ["mydb-0-1.index", "mydb-0-1.data", "myd-0-1.index", "mydbx-0-1.index", "xmydb-0-1.index"] {
+            fs::write(dir.join(file_name), "")?;
+        }
+
+        let mut files = LsmTree::list_files("test_files/listing/mydb")?;
+        files.sort();
+        assert_eq!(vec!["test_files/listing/mydb-0-1.data", "test_files/listing/mydb-0-1.index"], files);
+
+        Ok(())
+    }
+
+    #[test]
+    fn scans_ranges_across_levels() -> Result<(), TableErr> {
+        let tree = LsmTree::with_scaling_factor(String::from("test_files/scan_range_test"), 1)?;
+        let kv = |key: &str, value: &str| KV::new(key.to_string(), value.to_string());
+
+        // These two get compacted into level 1
+        tree.add(vec![kv("a", "old"), kv("b", "old"), kv("c", "old"), kv("e", "old")])?;
+        tree.add(vec![kv("d", "old"), kv("f", "old")])?;
+        tree.add(vec![kv("b", "new"), KV::tombstone("c"), kv("e", "new")])?;
+        assert_eq!(vec![3], tree.levels()[0].table_indices);
+        assert_eq!(vec![1], tree.levels()[1].table_indices);
+
+        let scanned = tree.scan_range("b", "f")?
+            .map(|entry| entry.map(|kv| (kv.key, kv.value)))
+            .collect::<Result<Vec<_>, TableErr>>()?;
+        assert_eq!(vec![
+            (String::from("b"), String::from("new")),
+            (String::from("d"), String::from("old")),
+            (String::from("e"), String::from("new")),
+        ], scanned);
+
+        assert_eq!(0, tree.scan_range("x", "z")?.count());
+        assert_eq!(0, tree.scan_range("f", "a")?.count());
+
+        Ok(())
+    }
+
+    #[test]
+    fn skips_tables_outside_the_key_range() -> Result<(), TableErr> {
+        let dir = Path::new("test_files/key_range");
+        let _ = fs::remove_dir_all(dir);
+        fs::create_dir_all(dir)?;
+        let name = "test_files/key_range/db";
+        let kv = |key: &str| KV::new(key.to_string(), key.to_string());
+
+        let mut tree = LsmTree::new(String::from(name))?;
+        tree.set_auto_compact(false);
+        tree.add(vec![kv("apple"), kv("banana"), kv("cherry")])?;
+        tree.add(vec![kv("xigua"), kv("yuzu"), kv("zucchini")])?;
+
+        // The ranges are recorded in the manifest, so they survive a reload
+        let loaded_tree = LsmTree::load(name)?;
+
+        // Nothing that could answer for the newer table is left, so reads have to skip it
+        for suffix in [".index", ".bloom", ".sparse"] {
+            fs::remove_file(format!("{}-0-2{}", name, suffix))?;
+        }
+        assert_eq!("banana", tree.read("banana")?);
+        assert_eq!("cherry", loaded_tree.read("cherry")?);
+        assert!(loaded_tree.read("yuzu").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn keeps_its_compaction_strategy() -> Result<(), TableErr> {
+        let name = "test_files/persisted_strategy_test";
+        let _ = fs::remove_file(LsmTree::manifest_name(name));
+        let tree = LsmTree::with_strategy(String::from(name), 3, CompactionStrategy::SizeTiered)?;
+        tree.add(vec![KV::new("a", "1")])?;
+
+        assert_eq!(CompactionStrategy::SizeTiered, LsmTree::load(name)?.compaction_strategy);
+        assert!(LsmTree::load_with_strategy(name, CompactionStrategy::SizeTiered).is_ok());
+        assert!(matches!(LsmTree::load_with_strategy(name, CompactionStrategy::Leveled), Err(TableErr::InvalidArgument(_))));
+
+        // Manifests from before strategies were recorded are leveled
+        let manifest = fs::read_to_string(LsmTree::manifest_name(name))?;
+        fs::write(LsmTree::manifest_name(name), manifest.replace("strategy:size_tiered\n", ""))?;
+        assert_eq!(CompactionStrategy::Leveled, LsmTree::load(name)?.compaction_strategy);
+
+        Ok(())
+    }
+
+    #[test]
+    fn compacts_with_either_strategy() -> Result<(), TableErr> {
+        let batch = |i: usize| vec![KV::new(format!("k{}", i), i.to_string())];
+
+        // Level 0 holds 3 tables, so the third add fills it
+        let leveled = LsmTree::with_strategy(String::from("test_files/leveled_strategy_test"), 3, CompactionStrategy::Leveled)?;
+        let size_tiered = LsmTree::with_strategy(String::from("test_files/size_tiered_strategy_test"), 3, CompactionStrategy::SizeTiered)?;
+        for i in 0..3 {
+            leveled.add(batch(i))?;
+            size_tiered.add(batch(i))?;
+        }
+
+        // Leveled only merges the two oldest tables, but they're all the same size, so
+        // size-tiered merges the lot
+        let leveled_levels = leveled.levels();
+        assert_eq!(vec![3], leveled_levels[0].table_indices);
+        assert_eq!(vec![1], leveled_levels[1].table_indices);
+
+        let size_tiered_levels = size_tiered.levels();
+        assert!(size_tiered_levels[0].table_indices.is_empty());
+        assert_eq!(vec![1], size_tiered_levels[1].table_indices);
+
+        for i in 0..3 {
+            assert_eq!(i.to_string(), leveled.read(&format!("k{}", i))?);
+            assert_eq!(i.to_string(), size_tiered.read(&format!("k{}", i))?);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn size_tiering_stops_at_differently_sized_tables() -> Result<(), TableErr> {
+        let tree = LsmTree::with_strategy(String::from("test_files/size_tier_run_test"), 3, CompactionStrategy::SizeTiered)?;
+        tree.add(vec![KV::new("a", "1")])?;
+        tree.add(vec![KV::new("b", "2")])?;
+        tree.add((0..100).map(|i| KV::new(format!("c{:03}", i), i.to_string())))?;
+        tree.add(vec![KV::new("d", "4")])?;
+
+        // The big third table ends the run of small ones, so it stays behind with the newest
+        let levels = tree.levels();
+        assert_eq!(vec![3, 4], levels[0].table_indices);
+        assert_eq!(vec![1], levels[1].table_indices);
+        assert_eq!("2", tree.read("b")?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn drops_tombstones_at_the_bottom() -> Result<(), TableErr> {
+        let tree = LsmTree::with_scaling_factor(String::from("test_files/bottom_tombstone_test"), 2)?;
+        tree.add(vec![
+            KV::new("a", "1"),
+            KV::new("b", "2"),
+        ])?;
+        tree.add(vec![KV::tombstone("a")])?;
+
+        // Both tables were merged into level 1, which has nothing below it
+        let levels = tree.levels();
+        assert!(levels[0].table_indices.is_empty());
+        assert_eq!(vec![1], levels[1].table_indices);
+        let bottom: Vec<KV> = table::iterate_entries("test_files/bottom_tombstone_test-1-1")?.collect::<Result<_, _>>()?;
+        assert_eq!(vec!["b"], bottom.iter().map(|kv| kv.key.as_str()).collect::<Vec<_>>());
+        assert!(tree.read("a").is_err());
+
+        // Level 1 isn't empty any more, so the next tombstone merged into it has to stay
+        tree.add(vec![KV::new("c", "3")])?;
+        tree.add(vec![KV::tombstone("b")])?;
+        let levels = tree.levels();
+        assert_eq!(vec![1, 2], levels[1].table_indices);
+        let middle: Vec<KV> = table::iterate_entries("test_files/bottom_tombstone_test-1-2")?.collect::<Result<_, _>>()?;
+        assert!(middle.iter().any(|kv| kv.key == "b" && kv.tombstone));
+        assert!(tree.read("b").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn compacts_in_the_background() -> Result<(), TableErr> {
+        let tree = Arc::new(LsmTree::with_scaling_factor(String::from("test_files/background_compaction_test"), 1)?);
+        LsmTree::start_background_compaction(&tree);
+
+        for i in 0..20 {
+            tree.add(vec![KV::new(format!("k{:02}", i), i.to_string())])?;
+            // Whatever the compactor's up to, every key written so far is readable
+            for j in 0..=i {
+                assert_eq!(j.to_string(), tree.read(&format!("k{:02}", j))?);
+            }
+        }
+
+        tree.stop_background_compaction()?;
+
+        // The requested compactions have all run, so nothing's left over the limit
+        let levels = tree.levels();
+        assert!(levels.len() > 2);
+        assert!(levels.iter().all(|level| level.table_indices.len() <= level.max_size as usize));
+        for i in 0..20 {
+            assert_eq!(i.to_string(), tree.read(&format!("k{:02}", i))?);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn returns_background_compaction_errors() -> Result<(), TableErr> {
+        let name = "test_files/background_compaction_error_test";
+        let tree = Arc::new(LsmTree::with_scaling_factor(String::from(name), 1)?);
+        LsmTree::start_background_compaction(&tree);
+
+        tree.add(vec![KV::new("a", "1")])?;
+        // Compacting the first table into the second fails without its index
+        fs::remove_file(format!("{}-0-1.index", name))?;
+        tree.add(vec![KV::new("b", "2")])?;
+
+        assert!(matches!(tree.stop_background_compaction(), Err(TableErr::IO(_))));
+        // It's only returned once
+        tree.stop_background_compaction()?;
+        assert_eq!("2", tree.read("b")?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn compacts_a_key_range() -> Result<(), TableErr> {
+        let mut tree = LsmTree::with_scaling_factor(String::from("test_files/compact_range_test"), 1)?;
+        tree.set_auto_compact(false);
+        let batch = |range: std::ops::Range<u32>, value: &str| range.map(|i| KV::new(format!("k{:02}", i), value.to_string())).collect::<Vec<_>>();
+
+        tree.add(batch(0..60, "1"))?;
+        tree.add(batch(40..100, "2"))?;
+        tree.compact()?;
+        let mut overwrites = batch(10..31, "3");
+        overwrites[15] = KV::tombstone("k25");
+        tree.add(overwrites)?;
+        tree.add(batch(70..81, "4"))?;
+        let before = tree.levels();
+        assert_eq!(2, before[0].table_indices.len());
+        assert_eq!(1, before[1].table_indices.len());
+
+        tree.compact_range("k20", "k30")?;
+
+        let after = tree.levels();
+        // The table of k70-k80 doesn't overlap the range, so it wasn't touched
+        assert_eq!(before[0].table_indices[1], after[0].table_indices[1]);
+        // The rest were replaced in place, and the range was promoted to the bottom level
+        assert_ne!(before[0].table_indices[0], after[0].table_indices[0]);
+        assert_eq!(2, after[1].table_indices.len());
+        let levels = tree.snapshot();
+        let promoted: Vec<KV> = table::iterate_entries(&levels[1].tables[1].name)?.collect::<Result<_, _>>()?;
+        let expected: Vec<String> = (20..30).filter(|i| *i != 25).map(|i| format!("k{:02}", i)).collect();
+        assert_eq!(expected, promoted.iter().map(|kv| kv.key.clone()).collect::<Vec<_>>());
+        assert!(promoted.iter().all(|kv| kv.value == "3" && !kv.tombstone));
+        for lsm_table in levels[0].tables.iter().chain(levels[1].tables.iter().take(1)) {
+            for entry in table::iterate_keys(&lsm_table.name)? {
+                let key = entry?.key;
+                assert!(!("k20".."k30").contains(&key.as_str()));
+            }
+        }
+
+        for i in 0..100 {
+            let expected = match i {
+                25 => None,
+                10..=30 => Some("3"),
+                70..=80 => Some("4"),
+                40..=99 => Some("2"),
+                _ => Some("1"),
+            };
+            assert_eq!(expected, tree.read(&format!("k{:02}", i)).ok().as_deref());
+        }
+        // The new layout made it into the manifest
+        assert_eq!(after, LsmTree::load("test_files/compact_range_test")?.levels());
+
+        Ok(())
+    }
+
+    #[test]
+    fn reads_many_keys_across_levels() -> Result<(), TableErr> {
+        let tree = LsmTree::with_scaling_factor(String::from("test_files/tree_read_many_test"), 1)?;
+        tree.add(vec![
+            KV::new("a", "old"),
+            KV::new("b", "old"),
+        ])?;
+        tree.add(vec![KV::new("c", "c")])?;
+        // Still in level 0, in front of the merged table in level 1
+        tree.add(vec![KV::new("a", "new"), KV::tombstone("b")])?;
+
+        let values = tree.read_many(&["b", "a", "missing", "c", "a"])?;
+        assert_eq!(vec![None, Some(String::from("new")), None, Some(String::from("c")), Some(String::from("new"))], values);
+
+        Ok(())
+    }
+
+    #[test]
+    fn lists_each_live_key_once() -> Result<(), TableErr> {
+        let tree = LsmTree::with_scaling_factor(String::from("test_files/tree_keys_test"), 1)?;
+        tree.add(vec![
+            KV::new("a", "1"),
+            KV::new("c", "1"),
+        ])?;
+        tree.add(vec![
+            KV::new("b", "2"),
+            KV::new("c", "2"),
+        ])?;
+        // Stays in level 0, over the merged table in level 1
+        tree.add(vec![
+            KV::tombstone("a"),
+            KV::new("c", "3"),
+            KV::new("d", "3"),
+        ])?;
+        assert_eq!(vec![3], tree.levels()[0].table_indices);
+
+        let keys: Vec<String> = tree.keys()?.collect::<Result<_, _>>()?;
+        assert_eq!(vec!["b", "c", "d"], keys);
+
+        Ok(())
+    }
+
+    #[test]
+    fn rewrites_tables_in_place() -> Result<(), TableErr> {
+        let tree = LsmTree::with_scaling_factor(String::from("test_files/tree_rewrite_test"), 4)?;
+        tree.add(vec![KV::new("a", "old")])?;
+        tree.add(vec![KV::new("a", "new")])?;
+
+        let stats = tree.rewrite_table(0, 1)?;
+        assert_eq!(1, stats.entries_written);
+
+        // The copy keeps the original's place, behind the newer table
+        assert_eq!(vec![3, 2], tree.levels()[0].table_indices);
+        assert_eq!("new", tree.read("a")?);
+        assert!(!Path::new("test_files/tree_rewrite_test-0-1.index").exists());
+
+        let reloaded = LsmTree::load("test_files/tree_rewrite_test")?;
+        assert_eq!(vec![3, 2], reloaded.levels()[0].table_indices);
+        assert_eq!("new", reloaded.read("a")?);
+
+        assert!(matches!(tree.rewrite_table(0, 1), Err(TableErr::KeyNotFound(_))));
+
+        Ok(())
+    }
+
+    #[test]
+    fn purges_tombstones() -> Result<(), TableErr> {
+        let mut tree = LsmTree::with_scaling_factor(String::from("test_files/purge_tombstones_test"), 2)?;
+        tree.set_auto_compact(false);
+        tree.add((0..20).map(|i| KV::new(format!("k{:02}", i), i.to_string())))?;
+        tree.add((20..25).map(|i| KV::new(format!("k{:02}", i), i.to_string())))?;
+        tree.compact()?;
+        assert_eq!(vec![1], tree.levels()[1].table_indices);
+        tree.add((0..10).map(|i| KV::tombstone(&format!("k{:02}", i))))?;
+        tree.add(vec![KV::tombstone("k00"), KV::new("k10", "new")])?;
+        assert_eq!(11, tree.tombstone_count()?);
+
+        tree.purge_tombstones()?;
+        assert_eq!(0, tree.tombstone_count()?);
+        let levels = tree.levels();
+        assert!(levels[0].table_indices.is_empty());
+        assert_eq!(1, levels.last().map_or(0, |level| level.table_indices.len()));
+
+        assert!(matches!(tree.read("k05"), Err(TableErr::KeyNotFound(_))));
+        assert_eq!("new", tree.read("k10")?);
+        assert_eq!(15, tree.keys()?.count());
+
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn skips_table_files_that_cant_be_found() -> Result<(), TableErr> {
+        let dir = Path::new("test_files/dangling_load");
+        let _ = fs::remove_dir_all(dir);
+        fs::create_dir_all(dir)?;
+
+        let mut tree = LsmTree::new(String::from("test_files/dangling_load/dangling"))?;
+        tree.set_auto_compact(false);
+        tree.add(vec![KV::new("a", "1")])?;
+        fs::remove_file(dir.join("dangling.manifest"))?;
+
+        // Named like a table, but there's nothing behind it, as if it was cleaned up mid-listing
+        std::os::unix::fs::symlink("does-not-exist", dir.join("dangling-0-9.index"))?;
+        assert_eq!(1, LsmTree::list_files("test_files/dangling_load/dangling")?.iter().filter(|file| file.ends_with(".index")).count());
+
+        let loaded_tree = LsmTree::load("test_files/dangling_load/dangling")?;
+        assert_eq!(vec![1], loaded_tree.levels()[0].table_indices);
+        assert_eq!("1", loaded_tree.read("a")?);
+
         Ok(())
     }
 }
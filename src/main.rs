@@ -1,4 +1,122 @@
+use sloth_db::db::client::{ Client, ClientBuilder };
+use sloth_db::io::table::TableErr;
+use std::io::{ self, BufRead, Write };
+
+const USAGE: &str = "Commands: put <key> <value>, get <key>, delete <key>, scan <start> <end>";
+
+/// A command read from stdin. Values run to the end of the line, so they can contain spaces.
+#[derive(Debug, PartialEq)]
+enum Command {
+    Put(String, String),
+    Get(String),
+    Delete(String),
+    Scan(String, String),
+}
+
+fn parse(line: &str) -> Result<Command, String> {
+    let line = line.trim();
+    let (name, args) = line.split_once(' ').unwrap_or((line, ""));
+    let args = args.trim_start();
+
+    match name {
+        "put" => match args.split_once(' ') {
+            Some((key, value)) => Ok(Command::Put(key.to_string(), value.to_string())),
+            None => Err(String::from("put takes a key and a value")),
+        },
+        "get" | "delete" => {
+            if args.is_empty() || args.contains(' ') {
+                return Err(format!("{} takes a single key", name));
+            }
+            let key = args.to_string();
+            Ok(if name == "get" { Command::Get(key) } else { Command::Delete(key) })
+        },
+        "scan" => match args.split(' ').collect::<Vec<_>>()[..] {
+            [start, end] if !start.is_empty() && !end.is_empty() => Ok(Command::Scan(start.to_string(), end.to_string())),
+            _ => Err(String::from("scan takes a start and an end key")),
+        },
+        _ => Err(format!("Unknown command '{}'", name)),
+    }
+}
+
+fn execute(client: &mut Client, command: Command, out: &mut impl Write) -> Result<(), TableErr> {
+    match command {
+        Command::Put(key, value) => {
+            client.put(key, value)?;
+            writeln!(out, "OK")?;
+        },
+        Command::Get(key) => match client.get(&key)? {
+            Some(value) => writeln!(out, "{}", value)?,
+            None => writeln!(out, "(not found)")?,
+        },
+        Command::Delete(key) => {
+            client.delete(&key)?;
+            writeln!(out, "OK")?;
+        },
+        Command::Scan(start, end) => {
+            for kv in client.range(&start, &end)? {
+                writeln!(out, "{}={}", kv.key, kv.value)?;
+            }
+        },
+    }
+
+    Ok(())
+}
+
+/// Runs every command from `input`, reporting bad commands and failed ones without stopping
+fn run(client: &mut Client, input: impl BufRead, out: &mut impl Write) -> Result<(), TableErr> {
+    for line in input.lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        match parse(&line) {
+            Ok(command) => {
+                if let Err(e) = execute(client, command, out) {
+                    writeln!(out, "error: {:?}", e)?;
+                }
+            },
+            Err(message) => writeln!(out, "error: {}. {}", message, USAGE)?,
+        }
+        out.flush()?;
+    }
+
+    Ok(())
+}
+
 fn main() {
-    println!("Hello world!");
+    let Some(db_name) = std::env::args().nth(1) else {
+        eprintln!("Usage: sloth_db <db path>\n{}", USAGE);
+        std::process::exit(2);
+    };
+
+    let mut client = match ClientBuilder::new(&db_name).create_if_missing(true).open() {
+        Ok(client) => client,
+        Err(e) => {
+            eprintln!("Couldn't open {}: {:?}", db_name, e);
+            std::process::exit(1);
+        },
+    };
+
+    if let Err(e) = run(&mut client, io::stdin().lock(), &mut io::stdout()) {
+        eprintln!("{:?}", e);
+        std::process::exit(1);
+    }
 }
 
+#[cfg(test)]
+mod test {
+    use crate::*;
+
+    #[test]
+    fn parses_commands() {
+        assert_eq!(Ok(Command::Put(String::from("k"), String::from("a value"))), parse("put k a value"));
+        assert_eq!(Ok(Command::Get(String::from("k"))), parse("get k"));
+        assert_eq!(Ok(Command::Delete(String::from("k"))), parse(" delete k "));
+        assert_eq!(Ok(Command::Scan(String::from("a"), String::from("z"))), parse("scan a z"));
+
+        for malformed in ["put k", "get", "get a b", "scan a", "scan a b c", "frobnicate"] {
+            assert!(parse(malformed).is_err(), "{} should be rejected", malformed);
+        }
+    }
+}
@@ -0,0 +1,43 @@
+use std::io::Write;
+use std::process::{ Command, Stdio };
+
+/// Runs the CLI against the database with the given commands, returning what it printed
+fn run_cli(db_name: &str, commands: &str) -> String {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_sloth_db"))
+        .arg(db_name)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .expect("Failed to start the CLI");
+    child.stdin.take().expect("stdin should be piped").write_all(commands.as_bytes()).expect("Failed to write commands");
+
+    let output = child.wait_with_output().expect("The CLI didn't finish");
+    assert!(output.status.success());
+
+    String::from_utf8(output.stdout).expect("The CLI printed invalid UTF-8")
+}
+
+#[test]
+fn runs_scripted_commands() {
+    let dir = "test_files/cli_test";
+    let _ = std::fs::remove_dir_all(dir);
+    let db_name = format!("{}/db", dir);
+
+    let output = run_cli(&db_name, "put a 1\nput b two words\nput c 3\nget b\nget missing\ndelete a\nget a\nscan a c\nbogus\nput lonely\n");
+    assert_eq!(concat!(
+        "OK\n",
+        "OK\n",
+        "OK\n",
+        "two words\n",
+        "(not found)\n",
+        "OK\n",
+        "(not found)\n",
+        "b=two words\n",
+        "error: Unknown command 'bogus'. Commands: put <key> <value>, get <key>, delete <key>, scan <start> <end>\n",
+        "error: put takes a key and a value. Commands: put <key> <value>, get <key>, delete <key>, scan <start> <end>\n",
+    ), output);
+
+    // The mem_table's flushed on exit, so the next run picks up where this one left off
+    let output = run_cli(&db_name, "get c\nscan a z\n");
+    assert_eq!("3\nb=two words\nc=3\n", output);
+}